@@ -0,0 +1,123 @@
+//! Compares the time taken to generate a patch at every level, and prints the size of the
+//! compressed patch at each of them.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use ddelta::{CompressedWriter, Compression, GenerateOptions};
+
+/// A function of a sample executable: blocks of code, each followed by a call to another function.
+struct Function {
+    code: Vec<Vec<u8>>,
+    calls: Vec<usize>,
+}
+
+/// Lays out the functions in the given order, with the calls pointing to their absolute address.
+fn link(functions: &[Function], order: &[usize]) -> Vec<u8> {
+    let mut addresses = vec![0u32; functions.len()];
+    let mut len = 0;
+    for &f in order {
+        addresses[f] = len;
+        len += functions[f]
+            .code
+            .iter()
+            .map(|code| code.len() as u32 + 5)
+            .sum::<u32>();
+    }
+    let mut out = Vec::new();
+    for &f in order {
+        for (code, &call) in functions[f].code.iter().zip(&functions[f].calls) {
+            out.extend_from_slice(code);
+            out.push(0xE8);
+            out.extend_from_slice(&addresses[call].to_le_bytes());
+        }
+    }
+    out
+}
+
+/// A linear congruential generator, so the sample is the same every time.
+struct Random(u32);
+
+impl Random {
+    fn next(&mut self) -> usize {
+        self.0 = self.0.wrapping_mul(1_103_515_245).wrapping_add(12345);
+        (self.0 >> 16) as usize
+    }
+}
+
+const FUNCTIONS: usize = 20_000;
+
+/// Creates a function whose code is made of `pieces`.
+fn function(random: &mut Random, pieces: &[Vec<u8>]) -> Function {
+    let blocks = 1 + random.next() % 8;
+    Function {
+        code: (0..blocks)
+            .map(|_| {
+                (0..1 + random.next() % 6)
+                    .flat_map(|_| pieces[random.next() % pieces.len()].clone())
+                    .collect()
+            })
+            .collect(),
+        calls: (0..blocks).map(|_| random.next() % FUNCTIONS).collect(),
+    }
+}
+
+/// Creates two builds of a sample executable. Functions were added, removed and changed, so most
+/// addresses differ between them, which is what makes diffing executables hard.
+fn sample() -> (Vec<u8>, Vec<u8>) {
+    let mut random = Random(1);
+    // Instructions repeat a lot, so code is made of a limited set of byte sequences
+    let pieces: Vec<Vec<u8>> = (0..4096)
+        .map(|_| {
+            (0..1 + random.next() % 12)
+                .map(|_| random.next() as u8)
+                .collect()
+        })
+        .collect();
+    let mut functions: Vec<Function> = (0..FUNCTIONS)
+        .map(|_| function(&mut random, &pieces))
+        .collect();
+    let old_order: Vec<usize> = (0..FUNCTIONS).collect();
+    let old = link(&functions, &old_order);
+
+    let mut new_order: Vec<usize> = (0..FUNCTIONS)
+        .filter(|_| !random.next().is_multiple_of(50))
+        .collect();
+    for _ in 0..FUNCTIONS / 50 {
+        functions.push(function(&mut random, &pieces));
+        new_order.insert(random.next() % new_order.len(), functions.len() - 1);
+    }
+    for _ in 0..FUNCTIONS / 20 {
+        let code = &mut functions[random.next() % FUNCTIONS].code;
+        let block = random.next() % code.len();
+        code[block] = pieces[random.next() % pieces.len()].clone();
+    }
+    let new = link(&functions, &new_order);
+    (old, new)
+}
+
+fn levels(c: &mut Criterion) {
+    let (old, new) = sample();
+    let mut group = c.benchmark_group("levels");
+    group.sample_size(10);
+    for level in 1..=9 {
+        let options = GenerateOptions::new().level(level);
+        let mut patch = CompressedWriter::new(Vec::new(), Compression::Zstd).unwrap();
+        options.generate(&old, &new, &mut patch, |_| {}).unwrap();
+        let patch = patch.finish().unwrap();
+        println!("level {}: {} bytes compressed", level, patch.len());
+        group.bench_with_input(
+            BenchmarkId::from_parameter(level),
+            &options,
+            |b, options| {
+                b.iter(|| {
+                    let mut patch = Vec::with_capacity(new.len() * 2);
+                    options.generate(&old, &new, &mut patch, |_| {}).unwrap();
+                    patch
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, levels);
+criterion_main!(benches);
@@ -0,0 +1,681 @@
+//! Asynchronous variants of applying and generating patches, built on the traits of
+//! [futures-io](https://docs.rs/futures-io). Tokio's I/O types can be used with them via
+//! [`tokio_util::compat`](https://docs.rs/tokio-util/*/tokio_util/compat/index.html).
+//!
+//! The outer compression of a patch is decompressed as the patch is read. The sections of split
+//! and bsdiff patches are held in memory in decompressed form, instead of being streamed.
+
+#[cfg(any(feature = "xz", feature = "zstd", feature = "bzip2"))]
+use std::io::Write;
+use std::io::{self, ErrorKind, Read, SeekFrom};
+
+use futures_util::io::{
+    AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, AsyncWrite, AsyncWriteExt,
+};
+
+use crate::cancel::CancelToken;
+#[cfg(feature = "diff")]
+use crate::diff::{generate_chunk, output_memory, write_end};
+use crate::error::Context;
+use crate::parser::{Parser, Sections, Step, HEADER_LEN};
+use crate::patch::{decode_section, BLOCK_SIZE};
+use crate::{ApplyOptions, Checksums, Compression, Error, Result, Stream, DDELTA_COMPRESSED_MAGIC};
+#[cfg(feature = "diff")]
+use crate::{GenerateOptions, Layout, Matcher, State};
+
+/// Decompresses data that is pushed into it, instead of reading it from a [`Read`].
+enum PushDecoder {
+    None,
+    #[cfg(feature = "xz")]
+    Xz(xz2::write::XzDecoder<Vec<u8>>),
+    #[cfg(feature = "zstd")]
+    Zstd(zstd::stream::write::Decoder<'static, Vec<u8>>),
+    #[cfg(feature = "bzip2")]
+    Bzip2(bzip2::write::BzDecoder<Vec<u8>>),
+}
+
+impl PushDecoder {
+    fn new(compression: Compression) -> Result<Self> {
+        Ok(match compression {
+            Compression::None => PushDecoder::None,
+            #[cfg(feature = "xz")]
+            Compression::Xz => PushDecoder::Xz(xz2::write::XzDecoder::new(Vec::new())),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => PushDecoder::Zstd(
+                zstd::stream::write::Decoder::new(Vec::new()).context(Stream::Patch)?,
+            ),
+            #[cfg(feature = "bzip2")]
+            Compression::Bzip2 => PushDecoder::Bzip2(bzip2::write::BzDecoder::new(Vec::new())),
+            #[allow(unreachable_patterns)]
+            other => return Err(Error::UnsupportedCompression(other)),
+        })
+    }
+
+    /// Decompresses `input` and appends the output to `out`. An empty `input` marks the end of
+    /// the compressed stream.
+    fn push(&mut self, input: &[u8], out: &mut Vec<u8>) -> io::Result<()> {
+        match self {
+            PushDecoder::None => out.extend_from_slice(input),
+            #[cfg(feature = "xz")]
+            PushDecoder::Xz(decoder) => {
+                decoder.write_all(input)?;
+                decoder.flush()?;
+                out.append(decoder.get_mut());
+            }
+            #[cfg(feature = "zstd")]
+            PushDecoder::Zstd(decoder) => {
+                decoder.write_all(input)?;
+                decoder.flush()?;
+                out.append(decoder.get_mut());
+            }
+            #[cfg(feature = "bzip2")]
+            PushDecoder::Bzip2(decoder) => {
+                if input.is_empty() {
+                    decoder.try_finish()?;
+                }
+                decoder.write_all(input)?;
+                decoder.flush()?;
+                out.append(decoder.get_mut());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A patch that is read asynchronously and decompressed if it has a compression header.
+struct AsyncPatch<'a, R> {
+    reader: &'a mut R,
+    decoder: PushDecoder,
+    /// The compressed data read last, which is reused for every read.
+    input: Vec<u8>,
+    /// Decompressed data, of which everything before `pos` has already been consumed.
+    buf: Vec<u8>,
+    pos: usize,
+    finished: bool,
+}
+
+impl<'a, R: AsyncRead + Unpin> AsyncPatch<'a, R> {
+    async fn new(reader: &'a mut R) -> Result<AsyncPatch<'a, R>> {
+        let mut magic = [0; 8];
+        let mut bytes_read = 0;
+        while bytes_read < magic.len() {
+            match reader.read(&mut magic[bytes_read..]).await {
+                Ok(0) => break,
+                Ok(n) => bytes_read += n,
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(Error::Io(Stream::Patch, e)),
+            }
+        }
+        let mut patch = AsyncPatch {
+            reader,
+            decoder: PushDecoder::None,
+            input: vec![0; BLOCK_SIZE as usize],
+            buf: Vec::new(),
+            pos: 0,
+            finished: false,
+        };
+        if &magic == DDELTA_COMPRESSED_MAGIC {
+            let mut id = [0];
+            patch
+                .reader
+                .read_exact(&mut id)
+                .await
+                .context(Stream::Patch)?;
+            let compression = Compression::from_id(id[0]).ok_or(Error::BadMagic)?;
+            patch.decoder = PushDecoder::new(compression)?;
+        } else {
+            patch.buf.extend_from_slice(&magic[..bytes_read]);
+        }
+        Ok(patch)
+    }
+
+    /// Reads and decompresses more of the patch. Returns `false` if the patch has ended.
+    async fn fill(&mut self) -> Result<bool> {
+        if self.finished {
+            return Ok(false);
+        }
+        self.buf.drain(..self.pos);
+        self.pos = 0;
+        let read = loop {
+            match self.reader.read(&mut self.input).await {
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+                other => break other.context(Stream::Patch)?,
+            }
+        };
+        self.finished = read == 0;
+        self.decoder
+            .push(&self.input[..read], &mut self.buf)
+            .context(Stream::Patch)?;
+        Ok(true)
+    }
+
+    /// Fills `out`, or as much of it as the rest of the patch. Returns the amount of bytes read.
+    async fn read_up_to(&mut self, out: &mut [u8]) -> Result<usize> {
+        let mut done = 0;
+        while done < out.len() {
+            if self.pos == self.buf.len() {
+                if !self.fill().await? {
+                    break;
+                }
+                continue;
+            }
+            let len = (self.buf.len() - self.pos).min(out.len() - done);
+            out[done..done + len].copy_from_slice(&self.buf[self.pos..self.pos + len]);
+            self.pos += len;
+            done += len;
+        }
+        Ok(done)
+    }
+
+    async fn read_exact(&mut self, out: &mut [u8]) -> Result<()> {
+        if self.read_up_to(out).await? < out.len() {
+            return Err(Error::TruncatedPatch);
+        }
+        Ok(())
+    }
+
+    /// Reads `len` bytes into memory.
+    async fn read_vec(&mut self, len: u64) -> Result<Vec<u8>> {
+        let mut data = Vec::new();
+        while (data.len() as u64) < len {
+            let block = (len - data.len() as u64).min(BLOCK_SIZE) as usize;
+            let start = data.len();
+            data.resize(start + block, 0);
+            self.read_exact(&mut data[start..]).await?;
+        }
+        Ok(data)
+    }
+
+    /// Reads the rest of the patch into memory.
+    #[cfg(feature = "bzip2")]
+    async fn read_to_end(&mut self) -> Result<Vec<u8>> {
+        while self.fill().await? {}
+        let data = self.buf.split_off(self.pos);
+        self.pos = self.buf.len();
+        Ok(data)
+    }
+}
+
+/// Where the diff or extra bytes of a patch are read from.
+enum Source<'p, 'a, R> {
+    /// Directly from the patch, for interleaved patches.
+    Patch(&'p mut AsyncPatch<'a, R>),
+    /// From a decompressed section.
+    Memory(&'p [u8]),
+}
+
+impl<R: AsyncRead + Unpin> Source<'_, '_, R> {
+    async fn read_exact(&mut self, buf: &mut [u8]) -> Result<()> {
+        match self {
+            Source::Patch(patch) => patch.read_exact(buf).await,
+            Source::Memory(data) => Read::read_exact(data, buf).context(Stream::Patch),
+        }
+    }
+}
+
+/// The new file, along with the checksum of everything written to it since the current chunk
+/// started.
+struct NewFile<'a, W> {
+    inner: &'a mut W,
+    hasher: crc32fast::Hasher,
+    cancel: Option<&'a CancelToken>,
+}
+
+impl<W: AsyncWrite + Unpin> NewFile<'_, W> {
+    async fn write_all(&mut self, buf: &[u8]) -> Result<()> {
+        if self.cancel.is_some_and(CancelToken::is_cancelled) {
+            return Err(Error::Cancelled);
+        }
+        self.inner.write_all(buf).await.context(Stream::New)?;
+        self.hasher.update(buf);
+        Ok(())
+    }
+}
+
+/// Applies `size` bytes of diff data, using each half of `buf` as a block of the patch and of the
+/// old file.
+async fn apply_diff<R: AsyncRead + Unpin>(
+    diff: &mut Source<'_, '_, R>,
+    old_f: &mut (impl AsyncRead + Unpin),
+    new: &mut NewFile<'_, impl AsyncWrite + Unpin>,
+    mut size: u64,
+    buf: &mut [u8],
+) -> Result<()> {
+    let (old, patch) = buf.split_at_mut(buf.len() / 2);
+    let block_size = old.len() as u64;
+    while size > 0 {
+        let to_read = block_size.min(size) as usize;
+        let old = &mut old[..to_read];
+        let patch = &mut patch[..to_read];
+
+        diff.read_exact(patch).await?;
+        old_f.read_exact(old).await.context(Stream::Old)?;
+
+        old.iter_mut()
+            .zip(patch.iter())
+            .for_each(|(old, patch)| *old = old.wrapping_add(*patch));
+
+        new.write_all(old).await?;
+
+        size -= to_read as u64;
+    }
+    Ok(())
+}
+
+async fn copy_bytes<R: AsyncRead + Unpin>(
+    extra: &mut Source<'_, '_, R>,
+    new: &mut NewFile<'_, impl AsyncWrite + Unpin>,
+    mut bytes: u64,
+    buf: &mut [u8],
+) -> Result<()> {
+    while bytes > 0 {
+        let to_read = (buf.len() as u64).min(bytes) as usize;
+        let buf = &mut buf[..to_read];
+        extra.read_exact(buf).await?;
+        new.write_all(buf).await?;
+        bytes -= to_read as u64;
+    }
+    Ok(())
+}
+
+/// Verifies the checksum of the old data, leaving `old` at the same position as it was before.
+async fn verify_old(
+    old: &mut (impl AsyncRead + AsyncSeek + Unpin),
+    checksums: &Checksums,
+    buf: &mut [u8],
+) -> Result<()> {
+    let start = old.seek(SeekFrom::Current(0)).await.context(Stream::Old)?;
+    let mut hasher = crc32fast::Hasher::new();
+    let mut bytes = checksums.old_size;
+    while bytes > 0 {
+        let to_read = (buf.len() as u64).min(bytes) as usize;
+        let buf = &mut buf[..to_read];
+        match old.read_exact(buf).await {
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+                return Err(Error::ChecksumMismatch(Stream::Old))
+            }
+            other => other.context(Stream::Old)?,
+        }
+        hasher.update(buf);
+        bytes -= to_read as u64;
+    }
+    if hasher.finalize() != checksums.old {
+        return Err(Error::ChecksumMismatch(Stream::Old));
+    }
+    old.seek(SeekFrom::Start(start))
+        .await
+        .context(Stream::Old)?;
+    Ok(())
+}
+
+/// Applies the chunks `parser` finds in `patch`, like the synchronous
+/// [`apply_parsed`][crate::patch::apply_parsed].
+async fn apply_parsed<R: AsyncRead + Unpin>(
+    old: &mut (impl AsyncRead + AsyncSeek + Unpin),
+    new: &mut NewFile<'_, impl AsyncWrite + Unpin>,
+    patch: &mut AsyncPatch<'_, R>,
+    parser: &mut Parser,
+    buf: &mut [u8],
+) -> Result<()> {
+    let mut header = [0; HEADER_LEN];
+    loop {
+        match parser.next()? {
+            Step::Read(len) => {
+                let read = patch.read_up_to(&mut header[..len]).await?;
+                parser.feed(&header[..read])?;
+            }
+            Step::Chunk(chunk) => {
+                if parser.is_chunked() {
+                    let offset = chunk.old_offset.unwrap_or(chunk.new_offset);
+                    old.seek(SeekFrom::Start(offset))
+                        .await
+                        .context(Stream::Old)?;
+                }
+                if let Some(checksums) = &chunk.checksums {
+                    verify_old(old, checksums, buf).await?;
+                }
+            }
+            Step::Sections(sections) => {
+                let (diff, extra) = read_sections(patch, parser, sections).await?;
+                let mut diff = Source::<R>::Memory(&diff);
+                let mut extra = Source::<R>::Memory(&extra);
+                while let Some(entry) = parser.next_entry()? {
+                    apply_diff(&mut diff, old, new, entry.diff, buf).await?;
+                    copy_bytes(&mut extra, new, entry.extra, buf).await?;
+                    old.seek(SeekFrom::Current(entry.seek))
+                        .await
+                        .context(Stream::Old)?;
+                }
+            }
+            Step::Entry(entry) => {
+                let mut source = Source::Patch(patch);
+                apply_diff(&mut source, old, new, entry.diff, buf).await?;
+                copy_bytes(&mut source, new, entry.extra, buf).await?;
+                old.seek(SeekFrom::Current(entry.seek))
+                    .await
+                    .context(Stream::Old)?;
+            }
+            Step::ChunkEnd(checksums) => {
+                let hasher = std::mem::take(&mut new.hasher);
+                if checksums.is_some_and(|checksums| hasher.finalize() != checksums.new) {
+                    return Err(Error::ChecksumMismatch(Stream::New));
+                }
+            }
+            Step::End => return Ok(()),
+        }
+    }
+}
+
+/// Reads the sections of a chunk into memory, passing the control section to `parser`. Returns the
+/// decompressed diff and extra bytes.
+async fn read_sections<R: AsyncRead + Unpin>(
+    patch: &mut AsyncPatch<'_, R>,
+    parser: &mut Parser,
+    sections: Sections,
+) -> Result<(Vec<u8>, Vec<u8>)> {
+    match sections {
+        Sections::Split {
+            compression,
+            control,
+            extra,
+            diff,
+        } => {
+            parser.control(decode_section(
+                &patch.read_vec(control).await?,
+                compression,
+            )?);
+            let extra = decode_section(&patch.read_vec(extra).await?, compression)?;
+            let diff = decode_section(&patch.read_vec(diff).await?, compression)?;
+            Ok((diff, extra))
+        }
+        #[cfg(feature = "bzip2")]
+        Sections::Bsdiff { control, diff } => {
+            let control = patch.read_vec(control).await?;
+            parser.control(decode_section(&control, Compression::Bzip2)?);
+            let diff = decode_section(&patch.read_vec(diff).await?, Compression::Bzip2)?;
+            let extra = decode_section(&patch.read_to_end().await?, Compression::Bzip2)?;
+            Ok((diff, extra))
+        }
+    }
+}
+
+/// Apply a patch like [`apply`][crate::apply], but asynchronously.
+///
+/// This requires the `async` feature.
+pub async fn apply_async(
+    old: &mut (impl AsyncRead + AsyncSeek + Unpin),
+    new: &mut (impl AsyncWrite + Unpin),
+    patch: &mut (impl AsyncRead + Unpin),
+) -> Result<()> {
+    ApplyOptions::new().apply_async(old, new, patch).await
+}
+
+/// Apply a patch like [`apply_chunked`][crate::apply_chunked], but asynchronously.
+///
+/// This requires the `async` feature.
+pub async fn apply_chunked_async(
+    old: &mut (impl AsyncRead + AsyncSeek + Unpin),
+    new: &mut (impl AsyncWrite + Unpin),
+    patch: &mut (impl AsyncRead + Unpin),
+) -> Result<()> {
+    ApplyOptions::new()
+        .apply_chunked_async(old, new, patch)
+        .await
+}
+
+impl ApplyOptions<'_> {
+    /// Apply a patch file like [`apply_async`].
+    pub async fn apply_async(
+        &mut self,
+        old: &mut (impl AsyncRead + AsyncSeek + Unpin),
+        new: &mut (impl AsyncWrite + Unpin),
+        patch: &mut (impl AsyncRead + Unpin),
+    ) -> Result<()> {
+        self.apply_parsed_async(old, new, patch, Parser::single())
+            .await
+    }
+
+    /// Apply a patch file like [`apply_chunked_async`].
+    pub async fn apply_chunked_async(
+        &mut self,
+        old: &mut (impl AsyncRead + AsyncSeek + Unpin),
+        new: &mut (impl AsyncWrite + Unpin),
+        patch: &mut (impl AsyncRead + Unpin),
+    ) -> Result<()> {
+        self.apply_parsed_async(old, new, patch, Parser::chunked())
+            .await
+    }
+
+    /// Applies a patch with the buffer of the options, checking the cancel token before every
+    /// block written.
+    async fn apply_parsed_async(
+        &mut self,
+        old: &mut (impl AsyncRead + AsyncSeek + Unpin),
+        new: &mut (impl AsyncWrite + Unpin),
+        patch: &mut (impl AsyncRead + Unpin),
+        mut parser: Parser,
+    ) -> Result<()> {
+        let (buf, cancel) = self.buf_and_token();
+        let mut patch = AsyncPatch::new(patch).await?;
+        let mut new = NewFile {
+            inner: new,
+            hasher: crc32fast::Hasher::new(),
+            cancel,
+        };
+        apply_parsed(old, &mut new, &mut patch, &mut parser, buf).await?;
+        new.inner.flush().await.context(Stream::New)
+    }
+}
+
+#[cfg(feature = "diff")]
+async fn read_up_to(reader: &mut (impl AsyncRead + Unpin), buf: &mut [u8]) -> io::Result<usize> {
+    let mut bytes_read = 0;
+    while bytes_read < buf.len() {
+        match reader.read(&mut buf[bytes_read..]).await {
+            Ok(0) => break,
+            Ok(n) => bytes_read += n,
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(bytes_read)
+}
+
+/// Generate a patch like [`generate_chunked`][crate::generate_chunked] or
+/// [`generate_chunked_split`][crate::generate_chunked_split], but read the old and new file and
+/// write the patch asynchronously.
+///
+/// Each chunk of the patch is generated in memory before being written. Note that generating a
+/// chunk takes a while without yielding to the executor, so you may want to run this on a thread
+/// where that's acceptable.
+///
+/// This requires the `async` and `diff` features.
+#[cfg(feature = "diff")]
+pub async fn generate_chunked_async(
+    old_f: &mut (impl AsyncRead + Unpin),
+    new_f: &mut (impl AsyncRead + Unpin),
+    patch_f: &mut (impl AsyncWrite + Unpin),
+    chunk_sizes: impl Into<Option<usize>>,
+    layout: Layout,
+    progress: impl FnMut(State),
+) -> Result<()> {
+    GenerateOptions::new()
+        .generate_chunked_async(old_f, new_f, patch_f, chunk_sizes, layout, progress)
+        .await
+}
+
+#[cfg(feature = "diff")]
+impl<M: Matcher> GenerateOptions<M> {
+    /// Generate a patch like [`generate_chunked_async`]. As each chunk of the patch is buffered,
+    /// that buffer counts towards the [`max_memory`][GenerateOptions::max_memory] as well.
+    pub async fn generate_chunked_async(
+        &self,
+        old_f: &mut (impl AsyncRead + Unpin),
+        new_f: &mut (impl AsyncRead + Unpin),
+        patch_f: &mut (impl AsyncWrite + Unpin),
+        chunk_sizes: impl Into<Option<usize>>,
+        layout: Layout,
+        mut progress: impl FnMut(State),
+    ) -> Result<()> {
+        let chunk_sizes = self.chunk_size_with(chunk_sizes.into(), |len| {
+            self.chunk_memory(len, layout) + output_memory(len)
+        })?;
+        let mut old_buf = vec![0; chunk_sizes];
+        let mut new_buf = vec![0; chunk_sizes];
+        let buffers = (old_buf.len() + new_buf.len()) as u64;
+        let mut patch = Vec::new();
+        let mut peak_memory = 0;
+        let mut bytes_completed = 0;
+        loop {
+            progress(State::Reading);
+            let new_bytes_read = read_up_to(new_f, &mut new_buf).await.context(Stream::New)?;
+            let new_buf = &new_buf[..new_bytes_read];
+            patch.clear();
+            // Nothing left in new file, so no need to read any more
+            if new_buf.is_empty() {
+                if bytes_completed == 0 {
+                    peak_memory = generate_chunk(&[], &[], &mut patch, layout, self, |_| {})?;
+                    patch_f.write_all(&patch).await.context(Stream::Patch)?;
+                }
+                break;
+            }
+            let old_bytes_read = read_up_to(old_f, &mut old_buf).await.context(Stream::Old)?;
+            let old_buf = &old_buf[..old_bytes_read];
+
+            self.check_cancelled()?;
+            progress(State::Sorting);
+            let memory = generate_chunk(old_buf, new_buf, &mut patch, layout, self, |d| match d {
+                State::Working(bytes) => progress(State::Working(bytes + bytes_completed)),
+                other => progress(other),
+            })?;
+            peak_memory = peak_memory.max(memory + patch.capacity() as u64);
+            patch_f.write_all(&patch).await.context(Stream::Patch)?;
+            bytes_completed += new_bytes_read as u64;
+        }
+        patch.clear();
+        write_end(&mut patch, bytes_completed)?;
+        patch_f.write_all(&patch).await.context(Stream::Patch)?;
+        patch_f.flush().await.context(Stream::Patch)?;
+        progress(State::Finished(buffers + peak_memory));
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "diff"))]
+mod test {
+    use futures_executor::block_on;
+    use futures_util::io::Cursor;
+
+    use super::{apply_async, apply_chunked_async, generate_chunked_async};
+    use crate::{
+        generate, generate_chunked, generate_chunked_split, ApplyOptions, CancelToken,
+        CompressedWriter, Compression, Error, GenerateOptions, Layout, State,
+    };
+
+    const OLD: &[u8] = b"The quick brown fox jumps over the lazy dog. The end.";
+    const NEW: &[u8] = b"The quick red fox jumped over the lazy dogs! The end!";
+
+    fn assert_send<T: Send>(_: &T) {}
+
+    #[test]
+    fn apply_formats() {
+        let mut patch = Vec::new();
+        generate(OLD, NEW, &mut patch, |_| {}).unwrap();
+        let mut new = Cursor::new(Vec::new());
+        block_on(apply_async(
+            &mut Cursor::new(OLD),
+            &mut new,
+            &mut &patch[..],
+        ))
+        .unwrap();
+        assert_eq!(new.get_ref(), NEW);
+
+        for &compression in &[
+            Compression::None,
+            Compression::Xz,
+            Compression::Zstd,
+            Compression::Bzip2,
+        ] {
+            if !compression.is_supported() {
+                continue;
+            }
+            let mut patch = CompressedWriter::new(Vec::new(), compression).unwrap();
+            generate_chunked(&mut &OLD[..], &mut &NEW[..], &mut patch, 20, |_| {}).unwrap();
+            let patch = patch.finish().unwrap();
+            let (mut old, mut new) = (Cursor::new(OLD), Cursor::new(Vec::new()));
+            let mut patch = &patch[..];
+            let future = apply_chunked_async(&mut old, &mut new, &mut patch);
+            assert_send(&future);
+            block_on(future).unwrap();
+            assert_eq!(new.get_ref(), NEW, "{}", compression);
+
+            let mut patch = Vec::new();
+            generate_chunked_split(
+                &mut &OLD[..],
+                &mut &NEW[..],
+                &mut patch,
+                20,
+                compression,
+                |_| {},
+            )
+            .unwrap();
+            let mut new = Cursor::new(Vec::new());
+            block_on(apply_chunked_async(
+                &mut Cursor::new(OLD),
+                &mut new,
+                &mut &patch[..],
+            ))
+            .unwrap();
+            assert_eq!(new.get_ref(), NEW, "{}", compression);
+        }
+    }
+
+    #[test]
+    fn same_as_chunked() {
+        let mut expected = Vec::new();
+        generate_chunked(&mut &OLD[..], &mut &NEW[..], &mut expected, 20, |_| {}).unwrap();
+        let mut patch = Cursor::new(Vec::new());
+        let mut finished = false;
+        block_on(generate_chunked_async(
+            &mut Cursor::new(OLD),
+            &mut Cursor::new(NEW),
+            &mut patch,
+            20,
+            Layout::Interleaved,
+            |state| finished |= matches!(state, State::Finished(_)),
+        ))
+        .unwrap();
+        assert_eq!(patch.get_ref(), &expected);
+        assert!(finished);
+
+        // The options pick the chunk size, and their token is checked while applying
+        let options = GenerateOptions::new().max_memory(600_000);
+        let mut patch = Cursor::new(Vec::new());
+        block_on(options.generate_chunked_async(
+            &mut Cursor::new(OLD),
+            &mut Cursor::new(NEW),
+            &mut patch,
+            None,
+            Layout::Interleaved,
+            |_| {},
+        ))
+        .unwrap();
+        let token = CancelToken::new();
+        let mut options = ApplyOptions::new().cancel_token(token.clone());
+        let mut new = Cursor::new(Vec::new());
+        block_on(options.apply_chunked_async(
+            &mut Cursor::new(OLD),
+            &mut new,
+            &mut &patch.get_ref()[..],
+        ))
+        .unwrap();
+        assert_eq!(new.get_ref(), NEW);
+        token.cancel();
+        let result = block_on(options.apply_chunked_async(
+            &mut Cursor::new(OLD),
+            &mut Cursor::new(Vec::new()),
+            &mut &patch.get_ref()[..],
+        ));
+        assert!(matches!(result, Err(Error::Cancelled)));
+    }
+}
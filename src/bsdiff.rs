@@ -0,0 +1,178 @@
+//! Support for the patch format of the original [bsdiff](http://www.daemonology.net/bsdiff/) tool.
+//!
+//! A BSDIFF40 patch starts with the magic number, followed by the lengths of the compressed control
+//! block, the compressed diff block and the size of the new file. Then, the three bzip2-compressed
+//! blocks follow. All numbers are stored in bsdiff's little-endian sign-magnitude representation.
+
+use std::io::Read;
+#[cfg(feature = "diff")]
+use std::io::Write;
+
+use zerocopy::AsBytes;
+
+#[cfg(feature = "diff")]
+use crate::diff::{generate_entries, write_diff, EntrySink};
+use crate::error::Context;
+#[cfg(feature = "diff")]
+use crate::{CompressedWriter, Compression, EntryHeader, GenerateOptions, State, BSDIFF_MAGIC};
+use crate::{Error, PatchHeader, Result, Stream};
+
+/// Decodes a number in bsdiff's sign-magnitude representation.
+fn offtin(buf: [u8; 8]) -> i64 {
+    let magnitude = (u64::from_le_bytes(buf) & !(1 << 63)) as i64;
+    if buf[7] & 0x80 != 0 {
+        -magnitude
+    } else {
+        magnitude
+    }
+}
+
+/// Encodes a number in bsdiff's sign-magnitude representation.
+#[cfg(feature = "diff")]
+fn offtout(x: i64) -> [u8; 8] {
+    let mut buf = x.unsigned_abs().to_le_bytes();
+    if x < 0 {
+        buf[7] |= 0x80;
+    }
+    buf
+}
+
+/// Reads a non-negative number in bsdiff's representation.
+fn read_len(reader: &mut impl Read) -> Result<u64> {
+    let mut buf = [0; 8];
+    reader.read_exact(&mut buf).context(Stream::Patch)?;
+    match offtin(buf) {
+        len if len < 0 => Err(Error::CorruptPatch),
+        len => Ok(len as u64),
+    }
+}
+
+/// The lengths stored in the header of a BSDIFF40 patch.
+pub(crate) struct BsdiffHeader {
+    pub(crate) control_len: u64,
+    pub(crate) diff_len: u64,
+    pub(crate) new_file_size: u64,
+}
+
+impl BsdiffHeader {
+    /// Reads the rest of the header, whose magic number and control block length have already been
+    /// read as `header`.
+    pub(crate) fn read(header: PatchHeader, patch: &mut impl Read) -> Result<Self> {
+        let mut control_len = [0; 8];
+        control_len.copy_from_slice(header.new_file_size.as_bytes());
+        let control_len = match offtin(control_len) {
+            len if len < 0 => return Err(Error::CorruptPatch),
+            len => len as u64,
+        };
+        Ok(BsdiffHeader {
+            control_len,
+            diff_len: read_len(patch)?,
+            new_file_size: read_len(patch)?,
+        })
+    }
+}
+
+/// Reads a control tuple of diff length, extra length and seek from the decompressed control block.
+pub(crate) fn read_entry(control: &mut impl Read) -> Result<(u64, u64, i64)> {
+    let diff_len = read_len(control)?;
+    let extra_len = read_len(control)?;
+    let mut seek = [0; 8];
+    control.read_exact(&mut seek).context(Stream::Patch)?;
+    Ok((diff_len, extra_len, offtin(seek)))
+}
+
+/// Collects the control tuples, diff and extra bytes in separate bzip2-compressed blocks.
+#[cfg(feature = "diff")]
+struct Blocks {
+    control: CompressedWriter<Vec<u8>>,
+    diff: CompressedWriter<Vec<u8>>,
+    extra: CompressedWriter<Vec<u8>>,
+}
+
+#[cfg(feature = "diff")]
+impl EntrySink for Blocks {
+    fn write_entry(
+        &mut self,
+        entry: &EntryHeader,
+        old: &[u8],
+        new: &[u8],
+        extra: &[u8],
+    ) -> Result<()> {
+        self.control
+            .write_all(&offtout(entry.diff.get() as i64))
+            .and_then(|_| self.control.write_all(&offtout(entry.extra.get() as i64)))
+            .and_then(|_| self.control.write_all(&offtout(entry.seek.get())))
+            .context(Stream::Patch)?;
+        write_diff(&mut self.diff, old, new).context(Stream::Patch)?;
+        self.extra.write_all(extra).context(Stream::Patch)
+    }
+}
+
+/// Generate a patch in the format of the original bsdiff tool, BSDIFF40. This has a limit of
+/// 2^31-1 bytes.
+///
+/// The patch can be applied with bspatch, or with [`apply`][crate::apply] and
+/// [`apply_chunked`][crate::apply_chunked]. It does not contain any checksums, and is already
+/// compressed, so it should not be wrapped in a [`CompressedWriter`]. `progress` is a function
+/// that will be called periodically with progress updates.
+///
+/// This requires the `bzip2` feature.
+#[cfg(feature = "diff")]
+pub fn generate_bsdiff(
+    old: &[u8],
+    new: &[u8],
+    patch: &mut impl Write,
+    mut progress: impl FnMut(State),
+) -> Result<()> {
+    if old.len().max(new.len()) >= i32::MAX as usize {
+        return Err(Error::InputTooLarge);
+    }
+    progress(State::Sorting);
+    let mut blocks = Blocks {
+        control: CompressedWriter::without_header(Vec::new(), Compression::Bzip2)?,
+        diff: CompressedWriter::without_header(Vec::new(), Compression::Bzip2)?,
+        extra: CompressedWriter::without_header(Vec::new(), Compression::Bzip2)?,
+    };
+    generate_entries(old, new, &mut blocks, &GenerateOptions::new(), progress)?;
+    let control = blocks.control.finish()?;
+    let diff = blocks.diff.finish()?;
+    let extra = blocks.extra.finish()?;
+    patch
+        .write_all(BSDIFF_MAGIC)
+        .and_then(|_| patch.write_all(&offtout(control.len() as i64)))
+        .and_then(|_| patch.write_all(&offtout(diff.len() as i64)))
+        .and_then(|_| patch.write_all(&offtout(new.len() as i64)))
+        .and_then(|_| patch.write_all(&control))
+        .and_then(|_| patch.write_all(&diff))
+        .and_then(|_| patch.write_all(&extra))
+        .and_then(|_| patch.flush())
+        .context(Stream::Patch)
+}
+
+#[cfg(all(test, feature = "diff"))]
+mod test {
+    use std::io::Cursor;
+
+    use super::{offtin, offtout};
+    use crate::{apply, generate_bsdiff};
+
+    #[test]
+    fn sign_magnitude() {
+        for &x in &[0, 1, -1, 255, -256, i64::MAX, -i64::MAX] {
+            assert_eq!(offtin(offtout(x)), x);
+        }
+        assert_eq!(offtout(-2), [2, 0, 0, 0, 0, 0, 0, 0x80]);
+    }
+
+    #[test]
+    fn roundtrip() {
+        let old = b"The quick brown fox jumps over the lazy dog. The end.";
+        let new = b"The quick red fox jumped over the lazy dogs! The end!";
+        let mut patch = Vec::new();
+        generate_bsdiff(old, new, &mut patch, |_| {}).unwrap();
+        assert_eq!(&patch[..8], b"BSDIFF40");
+        let mut patched = Vec::new();
+        apply(&mut Cursor::new(&old[..]), &mut patched, &mut &patch[..]).unwrap();
+        assert_eq!(&patched[..], &new[..]);
+    }
+}
@@ -0,0 +1,188 @@
+//! Stopping the generation or application of a patch from another thread.
+
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use crate::{Error, Result};
+
+/// A flag to stop generating or applying a patch, e.g. when the user aborts it, or a newer build
+/// supersedes the one being diffed. Clones share the same flag, so keep one to
+/// [`cancel`][CancelToken::cancel] and pass another one to
+/// [`GenerateOptions::cancel_token`][crate::GenerateOptions::cancel_token] or
+/// [`ApplyOptions::cancel_token`][crate::ApplyOptions::cancel_token]. Once cancelled, they fail
+/// with [`Error::Cancelled`], leaving the patch or new file incomplete.
+///
+/// Only the methods of these options check the token, including the asynchronous ones,
+/// [`ApplyOptions::apply_resumable`][crate::ApplyOptions::apply_resumable] and
+/// [`ApplyOptions::apply_in_place`][crate::ApplyOptions::apply_in_place]. The free functions, such
+/// as [`apply_chunked`][crate::apply_chunked], can't be cancelled. Neither can
+/// [`read_new_range`][crate::read_new_range], while a [`PatchedReader`][crate::PatchedReader] only
+/// does work when it is read from, so it can be stopped by no longer reading from it.
+///
+/// ```no_run
+/// # fn main() -> ddelta::Result<()> {
+/// use ddelta::{CancelToken, GenerateOptions};
+///
+/// let old = std::fs::read("old").unwrap();
+/// let new = std::fs::read("new").unwrap();
+/// let mut patch = std::fs::File::create("patch").unwrap();
+/// let token = CancelToken::new();
+/// let cancel = token.clone();
+/// std::thread::spawn(move || {
+///     std::thread::sleep(std::time::Duration::from_secs(60));
+///     cancel.cancel();
+/// });
+/// GenerateOptions::new()
+///     .cancel_token(token)
+///     .generate(&old, &new, &mut patch, |_| {})?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    /// Creates a token that hasn't been cancelled.
+    pub fn new() -> Self {
+        CancelToken::default()
+    }
+
+    /// Stops everything this token or one of its clones was passed to as soon as possible.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    /// Whether [`cancel`][CancelToken::cancel] was called on this token or one of its clones.
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Tokens are equal if they are clones of each other.
+impl PartialEq for CancelToken {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for CancelToken {}
+
+impl Hash for CancelToken {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        Arc::as_ptr(&self.0).hash(state);
+    }
+}
+
+/// Checks a token before every block written to the new file.
+pub(crate) struct Cancellable<'a, W> {
+    inner: &'a mut W,
+    token: Option<&'a CancelToken>,
+}
+
+impl<'a, W: crate::io::Write> Cancellable<'a, W> {
+    pub(crate) fn new(inner: &'a mut W, token: Option<&'a CancelToken>) -> Self {
+        Cancellable { inner, token }
+    }
+
+    /// Unwraps [`Error::Cancelled`] from the I/O error it was returned as by
+    /// [`write`][io::Write::write]. Other errors are passed through, even if the token was
+    /// cancelled.
+    pub(crate) fn result<T>(&self, result: Result<T>) -> Result<T> {
+        match result {
+            Err(Error::Io(_, err)) if is_cancelled(&err) => Err(Error::Cancelled),
+            result => result,
+        }
+    }
+}
+
+/// Whether `err` was returned by [`Cancellable`] because its token was cancelled.
+fn is_cancelled(err: &io::Error) -> bool {
+    err.get_ref()
+        .and_then(|err| err.downcast_ref::<Error>())
+        .is_some_and(|err| matches!(err, Error::Cancelled))
+}
+
+impl<W: crate::io::Write> io::Write for Cancellable<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.token.is_some_and(CancelToken::is_cancelled) {
+            return Err(io::Error::other(Error::Cancelled));
+        }
+        self.inner.write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(all(test, feature = "diff"))]
+mod test {
+    use std::io::Cursor;
+
+    use super::CancelToken;
+    use crate::test_data::random_bytes;
+    use crate::{generate_chunked, ApplyOptions, Error, GenerateOptions, Layout, State, Stream};
+
+    #[test]
+    fn cancel() {
+        let old = random_bytes(100_000);
+        let mut new = old.clone();
+        new[50_000..50_100].copy_from_slice(&[1; 100]);
+
+        let token = CancelToken::new();
+        let options = GenerateOptions::new().cancel_token(token.clone());
+        let mut patch = Vec::new();
+        let result = options.generate_chunked(
+            &mut &old[..],
+            &mut &new[..],
+            &mut patch,
+            30_000,
+            Layout::Interleaved,
+            |state| {
+                if let State::Working(bytes) = state {
+                    if bytes > 0 {
+                        token.cancel();
+                    }
+                }
+            },
+        );
+        assert!(matches!(result, Err(Error::Cancelled)));
+        assert!(patch.len() < 60_000);
+
+        let mut patch = Vec::new();
+        generate_chunked(&mut &old[..], &mut &new[..], &mut patch, 30_000, |_| {}).unwrap();
+        let token = CancelToken::new();
+        let mut options = ApplyOptions::new()
+            .block_size(1000)
+            .cancel_token(token.clone());
+        let mut patched = Vec::new();
+        options
+            .apply_chunked(&mut Cursor::new(&old), &mut patched, &mut &patch[..])
+            .unwrap();
+        assert_eq!(patched, new);
+        token.cancel();
+        let result =
+            options.apply_chunked(&mut Cursor::new(&old), &mut Vec::new(), &mut &patch[..]);
+        assert!(matches!(result, Err(Error::Cancelled)));
+        // Errors that aren't caused by the token are still reported
+        let other = vec![0; old.len()];
+        let result =
+            options.apply_chunked(&mut Cursor::new(&other), &mut Vec::new(), &mut &patch[..]);
+        assert!(matches!(result, Err(Error::ChecksumMismatch(Stream::Old))));
+
+        let result = options.apply_resumable(
+            &mut Cursor::new(&old),
+            &mut Cursor::new(Vec::new()),
+            &mut &patch[..],
+            None,
+            |_| Ok(()),
+        );
+        assert!(matches!(result, Err(Error::Cancelled)));
+        let result =
+            options.apply_in_place(&mut Cursor::new(old.clone()), &mut Cursor::new(&patch));
+        assert!(matches!(result, Err(Error::Cancelled)));
+    }
+}
@@ -0,0 +1,381 @@
+use core::fmt;
+#[cfg(all(feature = "std", feature = "zstd"))]
+use std::io::BufReader;
+#[cfg(feature = "std")]
+use std::io::{self, Chain, Cursor, ErrorKind, Read, Take, Write};
+#[cfg(feature = "std")]
+use std::str::FromStr;
+
+#[cfg(feature = "std")]
+use zerocopy::AsBytes;
+
+#[cfg(feature = "std")]
+use crate::error::Context;
+#[cfg(feature = "std")]
+use crate::{CompressionHeader, Error, Result, Stream, DDELTA_COMPRESSED_MAGIC};
+
+/// A compression algorithm a patch can be wrapped in.
+///
+/// All algorithms are always listed, but only those enabled via the cargo feature of the same name
+/// can actually be used. Otherwise, [`Error::UnsupportedCompression`] is returned.
+#[derive(Eq, PartialEq, Copy, Clone, Hash, Debug)]
+pub enum Compression {
+    /// Don't compress the patch at all. This creates the same output as not using a
+    /// [`CompressedWriter`].
+    None,
+    /// Compress the patch with xz (LZMA2). Requires the `xz` feature.
+    Xz,
+    /// Compress the patch with zstd. Requires the `zstd` feature.
+    Zstd,
+    /// Compress the patch with bzip2. Requires the `bzip2` feature.
+    Bzip2,
+}
+
+impl Compression {
+    pub(crate) fn from_id(id: u8) -> Option<Compression> {
+        match id {
+            0 => Some(Compression::None),
+            1 => Some(Compression::Xz),
+            2 => Some(Compression::Zstd),
+            3 => Some(Compression::Bzip2),
+            _ => None,
+        }
+    }
+
+    #[cfg(feature = "std")]
+    pub(crate) fn id(self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Xz => 1,
+            Compression::Zstd => 2,
+            Compression::Bzip2 => 3,
+        }
+    }
+
+    /// Whether support for this algorithm has been compiled in.
+    pub fn is_supported(self) -> bool {
+        match self {
+            Compression::None => true,
+            Compression::Xz => cfg!(feature = "xz"),
+            Compression::Zstd => cfg!(feature = "zstd"),
+            Compression::Bzip2 => cfg!(feature = "bzip2"),
+        }
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Compression::None => "none",
+            Compression::Xz => "xz",
+            Compression::Zstd => "zstd",
+            Compression::Bzip2 => "bzip2",
+        })
+    }
+}
+
+#[cfg(feature = "std")]
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Compression::None),
+            "xz" => Ok(Compression::Xz),
+            "zstd" => Ok(Compression::Zstd),
+            "bzip2" => Ok(Compression::Bzip2),
+            other => Err(format!("Unknown compression algorithm {}", other)),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+enum Encoder<W: Write> {
+    None(W),
+    #[cfg(feature = "xz")]
+    Xz(xz2::write::XzEncoder<W>),
+    #[cfg(feature = "zstd")]
+    Zstd(zstd::stream::write::Encoder<'static, W>),
+    #[cfg(feature = "bzip2")]
+    Bzip2(bzip2::write::BzEncoder<W>),
+}
+
+/// A writer that compresses a patch, to be passed to [`generate`][crate::generate] or
+/// [`generate_chunked`][crate::generate_chunked].
+///
+/// The compression algorithm is recorded in a header, so [`apply`][crate::apply] and
+/// [`apply_chunked`][crate::apply_chunked] will automatically decompress the patch.
+/// [`finish`][CompressedWriter::finish] must be called once the patch has been generated.
+///
+/// ```no_run
+/// # fn main() -> ddelta::Result<()> {
+/// use ddelta::{generate_chunked, CompressedWriter, Compression};
+/// use std::fs::File;
+///
+/// let mut old = File::open("old").unwrap();
+/// let mut new = File::open("new").unwrap();
+/// let mut patch = CompressedWriter::new(File::create("patch").unwrap(), Compression::Zstd)?;
+/// generate_chunked(&mut old, &mut new, &mut patch, None, |_| {})?;
+/// patch.finish()?;
+/// # Ok(())
+/// # }
+/// ```
+#[cfg(feature = "std")]
+pub struct CompressedWriter<W: Write> {
+    encoder: Encoder<W>,
+}
+
+#[cfg(feature = "std")]
+impl<W: Write> CompressedWriter<W> {
+    /// Writes the compression header to `writer`, and prepares to compress everything written
+    /// afterwards with `compression`.
+    pub fn new(mut writer: W, compression: Compression) -> Result<Self> {
+        if !compression.is_supported() {
+            return Err(Error::UnsupportedCompression(compression));
+        }
+        if compression != Compression::None {
+            writer
+                .write_all(
+                    CompressionHeader {
+                        magic: *DDELTA_COMPRESSED_MAGIC,
+                        compression: compression.id(),
+                    }
+                    .as_bytes(),
+                )
+                .context(Stream::Patch)?;
+        }
+        Self::without_header(writer, compression)
+    }
+
+    /// Prepares to compress everything written to `writer` with `compression`, without writing the
+    /// compression header, e.g. for sections of a patch whose compression is recorded elsewhere.
+    pub(crate) fn without_header(writer: W, compression: Compression) -> Result<Self> {
+        let encoder = match compression {
+            #[cfg(feature = "xz")]
+            Compression::Xz => Encoder::Xz(xz2::write::XzEncoder::new(writer, 6)),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => {
+                Encoder::Zstd(zstd::stream::write::Encoder::new(writer, 0).context(Stream::Patch)?)
+            }
+            #[cfg(feature = "bzip2")]
+            Compression::Bzip2 => Encoder::Bzip2(bzip2::write::BzEncoder::new(
+                writer,
+                bzip2::Compression::best(),
+            )),
+            Compression::None => Encoder::None(writer),
+            #[allow(unreachable_patterns)]
+            other => return Err(Error::UnsupportedCompression(other)),
+        };
+        Ok(CompressedWriter { encoder })
+    }
+
+    /// Finishes the compressed stream and returns the underlying writer.
+    pub fn finish(self) -> Result<W> {
+        let writer = match self.encoder {
+            Encoder::None(writer) => Ok(writer),
+            #[cfg(feature = "xz")]
+            Encoder::Xz(encoder) => encoder.finish(),
+            #[cfg(feature = "zstd")]
+            Encoder::Zstd(encoder) => encoder.finish(),
+            #[cfg(feature = "bzip2")]
+            Encoder::Bzip2(encoder) => encoder.finish(),
+        };
+        let mut writer = writer.context(Stream::Patch)?;
+        writer.flush().context(Stream::Patch)?;
+        Ok(writer)
+    }
+}
+
+#[cfg(feature = "std")]
+impl<W: Write> Write for CompressedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match &mut self.encoder {
+            Encoder::None(writer) => writer.write(buf),
+            #[cfg(feature = "xz")]
+            Encoder::Xz(encoder) => encoder.write(buf),
+            #[cfg(feature = "zstd")]
+            Encoder::Zstd(encoder) => encoder.write(buf),
+            #[cfg(feature = "bzip2")]
+            Encoder::Bzip2(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.encoder {
+            Encoder::None(writer) => writer.flush(),
+            #[cfg(feature = "xz")]
+            Encoder::Xz(encoder) => encoder.flush(),
+            #[cfg(feature = "zstd")]
+            Encoder::Zstd(encoder) => encoder.flush(),
+            #[cfg(feature = "bzip2")]
+            Encoder::Bzip2(encoder) => encoder.flush(),
+        }
+    }
+}
+
+/// Checks whether `patch` starts with a compression header, and if so, returns a reader
+/// decompressing the rest of it. Otherwise, the returned reader yields the patch unmodified.
+#[cfg(feature = "std")]
+pub(crate) fn decompress<'a, R: Read>(patch: &'a mut R) -> Result<Box<dyn Read + 'a>> {
+    Ok(Box::new(Decoder::new(patch)?))
+}
+
+/// Reads a patch it owns like the reader returned by [`decompress`], so it can be stored without
+/// borrowing the patch.
+#[cfg(feature = "std")]
+pub(crate) enum Decoder<R: Read> {
+    /// The patch isn't compressed. Yields the bytes that were read to detect this first.
+    None(Chain<Take<Cursor<[u8; 8]>>, R>),
+    #[cfg(feature = "xz")]
+    Xz(xz2::read::XzDecoder<R>),
+    #[cfg(feature = "zstd")]
+    Zstd(zstd::stream::read::Decoder<'static, BufReader<R>>),
+    #[cfg(feature = "bzip2")]
+    Bzip2(bzip2::read::BzDecoder<R>),
+}
+
+#[cfg(feature = "std")]
+impl<R: Read> Decoder<R> {
+    /// Checks whether `patch` starts with a compression header, and if so, decompresses the rest of
+    /// it.
+    pub(crate) fn new(mut patch: R) -> Result<Self> {
+        let mut magic = [0; 8];
+        let mut bytes_read = 0;
+        while bytes_read < magic.len() {
+            match patch.read(&mut magic[bytes_read..]) {
+                Ok(0) => break,
+                Ok(n) => bytes_read += n,
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(Error::Io(Stream::Patch, e)),
+            }
+        }
+        if &magic != DDELTA_COMPRESSED_MAGIC {
+            return Ok(Decoder::None(
+                Cursor::new(magic).take(bytes_read as u64).chain(patch),
+            ));
+        }
+        let mut id = [0];
+        patch.read_exact(&mut id).context(Stream::Patch)?;
+        Ok(match Compression::from_id(id[0]).ok_or(Error::BadMagic)? {
+            Compression::None => Decoder::None(Cursor::new(magic).take(0).chain(patch)),
+            #[cfg(feature = "xz")]
+            Compression::Xz => Decoder::Xz(xz2::read::XzDecoder::new(patch)),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => {
+                Decoder::Zstd(zstd::stream::read::Decoder::new(patch).context(Stream::Patch)?)
+            }
+            #[cfg(feature = "bzip2")]
+            Compression::Bzip2 => Decoder::Bzip2(bzip2::read::BzDecoder::new(patch)),
+            #[allow(unreachable_patterns)]
+            other => return Err(Error::UnsupportedCompression(other)),
+        })
+    }
+
+    /// Returns the reader of the patch if it isn't compressed.
+    pub(crate) fn uncompressed(&mut self) -> Option<&mut Chain<Take<Cursor<[u8; 8]>>, R>> {
+        match self {
+            Decoder::None(reader) => Some(reader),
+            #[allow(unreachable_patterns)]
+            _ => None,
+        }
+    }
+
+    /// Returns the patch, at an unspecified position.
+    pub(crate) fn into_inner(self) -> R {
+        match self {
+            Decoder::None(reader) => reader.into_inner().1,
+            #[cfg(feature = "xz")]
+            Decoder::Xz(decoder) => decoder.into_inner(),
+            #[cfg(feature = "zstd")]
+            Decoder::Zstd(decoder) => decoder.finish().into_inner(),
+            #[cfg(feature = "bzip2")]
+            Decoder::Bzip2(decoder) => decoder.into_inner(),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<R: Read> Read for Decoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Decoder::None(reader) => reader.read(buf),
+            #[cfg(feature = "xz")]
+            Decoder::Xz(decoder) => decoder.read(buf),
+            #[cfg(feature = "zstd")]
+            Decoder::Zstd(decoder) => decoder.read(buf),
+            #[cfg(feature = "bzip2")]
+            Decoder::Bzip2(decoder) => decoder.read(buf),
+        }
+    }
+}
+
+/// Returns a reader decompressing `reader` with `compression`.
+#[cfg(feature = "std")]
+pub(crate) fn decoder<'a>(
+    reader: impl Read + 'a,
+    compression: Compression,
+) -> Result<Box<dyn Read + 'a>> {
+    Ok(match compression {
+        Compression::None => Box::new(reader),
+        #[cfg(feature = "xz")]
+        Compression::Xz => Box::new(xz2::read::XzDecoder::new(reader)),
+        #[cfg(feature = "zstd")]
+        Compression::Zstd => {
+            Box::new(zstd::stream::read::Decoder::new(reader).context(Stream::Patch)?)
+        }
+        #[cfg(feature = "bzip2")]
+        Compression::Bzip2 => Box::new(bzip2::read::BzDecoder::new(reader)),
+        #[allow(unreachable_patterns)]
+        other => return Err(Error::UnsupportedCompression(other)),
+    })
+}
+
+#[cfg(all(test, feature = "diff"))]
+mod test {
+    use std::io::Cursor;
+
+    use crate::test_data::random_bytes;
+    use crate::{
+        apply_chunked, generate_chunked, generate_chunked_split, CompressedWriter, Compression,
+    };
+
+    #[test]
+    fn roundtrip() {
+        let old = random_bytes(10_000);
+        let mut new = old.clone();
+        new[1234..1300]
+            .iter_mut()
+            .for_each(|b| *b = b.wrapping_add(3));
+        new.extend_from_slice(b"appended");
+        for &compression in &[
+            Compression::None,
+            Compression::Xz,
+            Compression::Zstd,
+            Compression::Bzip2,
+        ] {
+            if !compression.is_supported() {
+                continue;
+            }
+            let mut patch = CompressedWriter::new(Vec::new(), compression).unwrap();
+            generate_chunked(&mut &old[..], &mut &new[..], &mut patch, None, |_| {}).unwrap();
+            let patch = patch.finish().unwrap();
+            let mut patched = Vec::new();
+            apply_chunked(&mut Cursor::new(&old), &mut patched, &mut &patch[..]).unwrap();
+            assert_eq!(patched, new, "{}", compression);
+
+            let mut patch = Vec::new();
+            generate_chunked_split(
+                &mut &old[..],
+                &mut &new[..],
+                &mut patch,
+                None,
+                compression,
+                |_| {},
+            )
+            .unwrap();
+            let mut patched = Vec::new();
+            apply_chunked(&mut Cursor::new(&old), &mut patched, &mut &patch[..]).unwrap();
+            assert_eq!(patched, new, "{}", compression);
+        }
+    }
+}
@@ -1,18 +1,65 @@
 use std::cmp::Ordering;
-use std::i32;
-use std::io::{ErrorKind, Read, Write};
+use std::io::{self, ErrorKind, Read, Seek, Write};
 
-use anyhow::{ensure, Context, Result};
-use byteorder::WriteBytesExt;
-#[cfg(not(feature = "c"))]
-use divsufsort as cdivsufsort;
-use zerocopy::{AsBytes, I64, U64};
+use zerocopy::{AsBytes, I64, U32, U64};
 
-use crate::{EntryHeader, PatchHeader, State, DDELTA_MAGIC};
+use crate::cancel::CancelToken;
+use crate::error::Context;
+use crate::matcher::Matcher;
+use crate::suffix::{DivSufSort, SuffixIndex, SuffixSort};
+use crate::window::Windowed;
+use crate::writer::DiffWriter;
+use crate::{
+    ChecksumHeader, CompressedWriter, Compression, EntryHeader, Error, PatchHeader, Result,
+    SplitHeader, State, Stream, WindowHeader, DDELTA_CHECKSUM_MAGIC, DDELTA_END_MAGIC,
+    DDELTA_MAGIC, DDELTA_SPLIT_MAGIC, DDELTA_WINDOW_MAGIC,
+};
 
 const FUZZ: isize = 8;
+/// The size of the largest chunk of a chunked patch, which is limited by the 32-bit positions of
+/// the checksummed formats.
+const MAX_CHUNK_SIZE: usize = i32::MAX as usize - 1;
 
-fn read_up_to(reader: &mut impl Read, buf: &mut [u8]) -> Result<usize> {
+/// The level used by default, which keeps the output of earlier versions.
+const DEFAULT_LEVEL: u8 = 5;
+const MAX_LEVEL: u8 = 9;
+
+/// The heuristics of the scan loop, as tuned by the level.
+struct Effort {
+    /// Every position the longest match is shorter than [`FUZZ`] at skips this many more
+    /// positions.
+    skip: isize,
+    /// How much longer than the data matching at the current offset a new match must be to be
+    /// used.
+    min_gain: isize,
+    /// How many positions in a row may find almost the same match, as happens in data that only
+    /// differs by a few bytes, before giving up.
+    max_stuck: u32,
+}
+
+impl Effort {
+    fn new(level: u8) -> Self {
+        // Each step up skips less, switches to better matches more readily or gives up later
+        let (skip, min_gain, max_stuck) = match level {
+            1 => (63, 16, 100),
+            2 => (31, 16, 100),
+            3 => (15, 12, 100),
+            4 => (7, 8, 100),
+            5 => (0, 8, 100),
+            6 => (0, 8, 400),
+            7 => (0, 8, 1000),
+            8 => (0, 8, 4000),
+            _ => (0, 8, 10_000),
+        };
+        Effort {
+            skip,
+            min_gain,
+            max_stuck,
+        }
+    }
+}
+
+pub(crate) fn read_up_to(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
     let mut bytes_read = 0;
     while bytes_read < buf.len() {
         match reader.read(&mut buf[bytes_read..]) {
@@ -21,7 +68,7 @@ fn read_up_to(reader: &mut impl Read, buf: &mut [u8]) -> Result<usize> {
                 bytes_read += n;
             }
             Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
-            Err(e) => return Err(e.into()),
+            Err(e) => return Err(e),
         }
     }
     Ok(bytes_read)
@@ -37,47 +84,533 @@ fn read_up_to(reader: &mut impl Read, buf: &mut [u8]) -> Result<usize> {
 /// will never consume more than chunk_sizes * 6, so this parameter can be used to implement a RAM
 /// limit. Pass [`None`] as a parameter to set no limit. Note that this uses anything implementing
 /// `Into<Option<usize>>`, including a [`usize`] itself, so you can just pass a number to that
-/// parameter. A smaller `chunk_sizes` value uses less RAM, but creates less optimal patches.
+/// parameter. A smaller `chunk_sizes` value uses less RAM, but creates less optimal patches. To
+/// pick the chunk size from a memory limit instead, use [`GenerateOptions::max_memory`].
+///
+/// Every chunk contains a checksum of the old and new data it covers, which is verified when the
+/// patch is applied. The patch ends with a marker holding the size of the new file, so a patch
+/// that was cut off between two chunks fails to apply with [`Error::TruncatedPatch`].
 pub fn generate_chunked(
     old_f: &mut impl Read,
     new_f: &mut impl Read,
     patch_f: &mut impl Write,
     chunk_sizes: impl Into<Option<usize>>,
-    mut progress: impl FnMut(State) -> (),
+    progress: impl FnMut(State),
+) -> Result<()> {
+    GenerateOptions::new().generate_chunked(
+        old_f,
+        new_f,
+        patch_f,
+        chunk_sizes,
+        Layout::Interleaved,
+        progress,
+    )
+}
+
+/// Generate a patch like [`generate_chunked`], but with every chunk using the layout of
+/// [`generate_split`].
+pub fn generate_chunked_split(
+    old_f: &mut impl Read,
+    new_f: &mut impl Read,
+    patch_f: &mut impl Write,
+    chunk_sizes: impl Into<Option<usize>>,
+    compression: Compression,
+    progress: impl FnMut(State),
+) -> Result<()> {
+    GenerateOptions::new().generate_chunked(
+        old_f,
+        new_f,
+        patch_f,
+        chunk_sizes,
+        Layout::Split(compression),
+        progress,
+    )
+}
+
+/// Generate a patch like [`generate_chunked`], but diff each chunk of the new file against the
+/// window of the old file that matches it best, instead of the chunk at the same position.
+///
+/// This keeps the patch small when data has been inserted into or removed from the old file, which
+/// would otherwise shift the chunks of the new file against the chunks of the old file. Before
+/// generating the patch, the old file is read once to build an index of samples of it, which takes
+/// up to a few dozen megabytes of memory in addition to the memory described in
+/// [`generate_chunked`]. The offset of each window is stored in the patch, so
+/// [`apply_chunked`][crate::apply_chunked] reads the same windows.
+pub fn generate_chunked_windowed(
+    old_f: &mut (impl Read + Seek),
+    new_f: &mut impl Read,
+    patch_f: &mut impl Write,
+    chunk_sizes: impl Into<Option<usize>>,
+    layout: Layout,
+    progress: impl FnMut(State),
+) -> Result<()> {
+    GenerateOptions::new().generate_chunked_windowed(
+        old_f,
+        new_f,
+        patch_f,
+        chunk_sizes,
+        layout,
+        progress,
+    )
+}
+
+/// How the entries of each chunk of a patch are laid out.
+#[derive(Eq, PartialEq, Copy, Clone, Hash, Debug)]
+pub enum Layout {
+    /// Each entry is directly followed by its diff and extra bytes, like [`generate_chunked`].
+    Interleaved,
+    /// The entries, diff and extra bytes are stored in separate sections, which are compressed
+    /// with the given algorithm, like [`generate_chunked_split`].
+    Split(Compression),
+}
+
+/// Options for generating a patch, which restrict the patches that are created.
+///
+/// ```no_run
+/// # fn main() -> ddelta::Result<()> {
+/// use ddelta::{GenerateOptions, Layout};
+/// use std::fs::File;
+///
+/// let mut old = File::open("old").unwrap();
+/// let mut new = File::open("new").unwrap();
+/// let mut patch = File::create("patch").unwrap();
+/// GenerateOptions::new().forward_only(true).generate_chunked(
+///     &mut old,
+///     &mut new,
+///     &mut patch,
+///     None,
+///     Layout::Interleaved,
+///     |_| {},
+/// )?;
+/// # Ok(())
+/// # }
+/// ```
+///
+/// To diff files larger than 2^31-1 bytes in a single patch, sort the suffixes of the old file
+/// with [`SaIs`][crate::SaIs], which works with 64-bit positions:
+///
+/// ```no_run
+/// # fn main() -> ddelta::Result<()> {
+/// use ddelta::{GenerateOptions, SaIs};
+///
+/// let old = std::fs::read("old.img").unwrap();
+/// let new = std::fs::read("new.img").unwrap();
+/// let mut patch = std::fs::File::create("patch").unwrap();
+/// GenerateOptions::new()
+///     .suffix_sort(SaIs)
+///     .generate(&old, &new, &mut patch, |_| {})?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Eq, PartialEq, Clone, Hash, Debug)]
+pub struct GenerateOptions<M = DivSufSort> {
+    forward_only: bool,
+    level: u8,
+    max_memory: Option<u64>,
+    cancel: Option<CancelToken>,
+    matcher: M,
+}
+
+impl GenerateOptions {
+    /// Creates the default options, which are the ones used by [`generate`] and
+    /// [`generate_chunked`].
+    pub fn new() -> Self {
+        GenerateOptions::default()
+    }
+}
+
+impl<M: Default> Default for GenerateOptions<M> {
+    fn default() -> Self {
+        GenerateOptions {
+            forward_only: false,
+            level: DEFAULT_LEVEL,
+            max_memory: None,
+            cancel: None,
+            matcher: M::default(),
+        }
+    }
+}
+
+impl<M: Matcher> GenerateOptions<M> {
+    /// The algorithm used to sort the suffixes of the old file, which also limits the size of the
+    /// files [`generate`][GenerateOptions::generate] accepts. Defaults to
+    /// [`DivSufSort`][crate::DivSufSort]. Chunked patches always use chunks of at most 2^31-1
+    /// bytes.
+    pub fn suffix_sort<T: SuffixSort>(self, suffix_sort: T) -> GenerateOptions<T> {
+        self.matcher(suffix_sort)
+    }
+
+    /// The strategy used to find the data of the new file in the old file, such as a
+    /// [`HashChain`][crate::HashChain] that needs less memory than a suffix array, at the cost of
+    /// larger patches. Defaults to sorting suffixes with [`DivSufSort`][crate::DivSufSort].
+    pub fn matcher<T: Matcher>(self, matcher: T) -> GenerateOptions<T> {
+        GenerateOptions {
+            forward_only: self.forward_only,
+            level: self.level,
+            max_memory: self.max_memory,
+            cancel: self.cancel,
+            matcher,
+        }
+    }
+
+    /// Whether to only create patches that never seek backwards in the old file, so they can be
+    /// applied with [`apply_streaming`][crate::apply_streaming]. Parts of the new file that would
+    /// be diffed against old data before the current position are stored in the patch as is, so
+    /// the patch may be larger if data was moved towards the start of the file.
+    pub fn forward_only(mut self, forward_only: bool) -> Self {
+        self.forward_only = forward_only;
+        self
+    }
+
+    /// How hard to look for matches, like the level of a compressor: from 1, the fastest, to 9,
+    /// which explores the most candidate matches. Levels outside of that range are clamped to it.
+    /// Indexing the old file takes the same time at every level.
+    ///
+    /// The default level 5 creates the same patches as previous versions. Lower levels skip ahead
+    /// in parts of the new file that aren't found in the old file, and prefer extending the
+    /// current match over switching to a slightly longer one, which makes diffing faster. Higher
+    /// levels keep looking for matches for longer in data that only differs by a few bytes. Which
+    /// level creates the smallest patch depends on the data: `cargo bench` prints the time and size
+    /// at each level for a sample executable.
+    pub fn level(mut self, level: u8) -> Self {
+        self.level = level.clamp(1, MAX_LEVEL);
+        self
+    }
+
+    /// The amount of memory in bytes generating a patch may use, which defaults to no limit. The
+    /// chunked functions pick the largest chunks whose buffers, index and buffered output fit into
+    /// it, up to the `chunk_sizes` passed to them. [`generate`][GenerateOptions::generate] fails
+    /// with [`Error::MemoryLimit`] if the index of the old file doesn't fit, as do the chunked
+    /// functions if not even a chunk of 1 byte does.
+    ///
+    /// The memory counted is what the generator allocates itself, as estimated by the
+    /// [`Matcher`][crate::Matcher]: the old and new file passed to
+    /// [`generate`][GenerateOptions::generate] and the internal state of compressors are not
+    /// included. Once the patch has been written, the peak amount is reported as
+    /// [`State::Finished`].
+    pub fn max_memory(mut self, max_memory: impl Into<Option<u64>>) -> Self {
+        self.max_memory = max_memory.into();
+        self
+    }
+
+    /// Stops generating the patch with [`Error::Cancelled`] once `token` is cancelled, leaving the
+    /// patch incomplete. The token is checked while searching for matches and between chunks, but
+    /// not while the old file is being indexed, which may take a few seconds for large chunks.
+    pub fn cancel_token(mut self, token: CancelToken) -> Self {
+        self.cancel = Some(token);
+        self
+    }
+
+    /// Fails with [`Error::Cancelled`] if the token was cancelled.
+    pub(crate) fn check_cancelled(&self) -> Result<()> {
+        match &self.cancel {
+            Some(token) if token.is_cancelled() => Err(Error::Cancelled),
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks that an old file of `old_len` bytes can be indexed by the matcher within the memory
+    /// limit. Returns the memory the index uses.
+    pub(crate) fn check_old(&self, old_len: usize) -> Result<u64> {
+        if old_len as u64 > self.matcher.max_len() {
+            return Err(Error::InputTooLarge);
+        }
+        let memory = self.matcher.memory(old_len);
+        if memory > self.max_memory.unwrap_or(u64::MAX) {
+            return Err(Error::MemoryLimit);
+        }
+        Ok(memory)
+    }
+
+    /// Builds the index of `old` with the matcher.
+    pub(crate) fn index(&self, old: &[u8]) -> M::Index {
+        self.matcher.index(old)
+    }
+
+    /// Generate a patch like [`generate`]. The old file has a limit of 2^31-1 bytes, unless the
+    /// matcher supports larger files.
+    pub fn generate(
+        &self,
+        old: &[u8],
+        new: &[u8],
+        patch: &mut impl Write,
+        mut progress: impl FnMut(State),
+    ) -> Result<()> {
+        let memory = self.check_old(old.len())?;
+        progress(State::Sorting);
+        write_header(patch, new.len() as u64)?;
+        generate_entries(old, new, &mut Interleaved(&mut *patch), self, &mut progress)?;
+        write_ending(patch)?;
+        patch.flush().context(Stream::Patch)?;
+        progress(State::Finished(memory));
+        Ok(())
+    }
+
+    /// Generate a patch like [`generate_chunked`] or [`generate_chunked_split`], depending on
+    /// `layout`.
+    pub fn generate_chunked(
+        &self,
+        old_f: &mut impl Read,
+        new_f: &mut impl Read,
+        patch_f: &mut impl Write,
+        chunk_sizes: impl Into<Option<usize>>,
+        layout: Layout,
+        progress: impl FnMut(State),
+    ) -> Result<()> {
+        generate_chunked_with_layout(
+            &mut Aligned(old_f),
+            new_f,
+            patch_f,
+            chunk_sizes.into(),
+            layout,
+            self,
+            progress,
+        )
+    }
+
+    /// Generate a patch like [`generate_chunked_windowed`]. The index of the old file it builds
+    /// counts towards the [`max_memory`][GenerateOptions::max_memory].
+    pub fn generate_chunked_windowed(
+        &self,
+        old_f: &mut (impl Read + Seek),
+        new_f: &mut impl Read,
+        patch_f: &mut impl Write,
+        chunk_sizes: impl Into<Option<usize>>,
+        layout: Layout,
+        mut progress: impl FnMut(State),
+    ) -> Result<()> {
+        progress(State::Reading);
+        let mut old = Windowed::new(old_f).context(Stream::Old)?;
+        generate_chunked_with_layout(
+            &mut old,
+            new_f,
+            patch_f,
+            chunk_sizes.into(),
+            layout,
+            self,
+            progress,
+        )
+    }
+
+    /// Create a [`DiffWriter`] the new file is written to, which writes a patch like
+    /// [`generate_chunked`] or [`generate_chunked_split`], depending on `layout`. If the
+    /// [`max_memory`][GenerateOptions::max_memory] doesn't fit a chunk, writing to it fails with
+    /// [`Error::MemoryLimit`].
+    pub fn diff_writer<Old: Read, Patch: Write>(
+        &self,
+        old: Old,
+        patch: Patch,
+        chunk_sizes: impl Into<Option<usize>>,
+        layout: Layout,
+    ) -> DiffWriter<Old, Patch, M>
+    where
+        M: Clone,
+    {
+        DiffWriter::with_options(old, patch, chunk_sizes.into(), layout, self.clone())
+    }
+
+    /// Returns the size of the chunks to split the files into: `chunk_sizes`, or smaller if
+    /// diffing a chunk wouldn't fit into the memory limit otherwise, given that `fixed` bytes are
+    /// used regardless of the chunk size.
+    pub(crate) fn chunk_size(
+        &self,
+        chunk_sizes: Option<usize>,
+        layout: Layout,
+        fixed: u64,
+    ) -> Result<usize> {
+        self.chunk_size_with(chunk_sizes, |len| fixed + self.chunk_memory(len, layout))
+    }
+
+    /// Returns the largest chunk size up to `chunk_sizes` for which `memory` fits into the memory
+    /// limit, given that it grows with the chunk size.
+    pub(crate) fn chunk_size_with(
+        &self,
+        chunk_sizes: Option<usize>,
+        memory: impl Fn(usize) -> u64,
+    ) -> Result<usize> {
+        let max = chunk_sizes.unwrap_or(MAX_CHUNK_SIZE).min(MAX_CHUNK_SIZE);
+        let max_memory = match self.max_memory {
+            Some(max_memory) => max_memory,
+            None => return Ok(max),
+        };
+        let fits = |len| memory(len) <= max_memory;
+        if !fits(1) {
+            return Err(Error::MemoryLimit);
+        }
+        // The memory used grows with the chunk size, so find the largest chunk that fits
+        let (mut low, mut high) = (1, max.max(1));
+        while low < high {
+            let mid = low + (high - low).div_ceil(2);
+            if fits(mid) {
+                low = mid;
+            } else {
+                high = mid - 1;
+            }
+        }
+        Ok(low)
+    }
+
+    /// The memory used to diff chunks of `len` bytes, apart from what the old source uses.
+    pub(crate) fn chunk_memory(&self, len: usize, layout: Layout) -> u64 {
+        // The old and new chunk are buffered
+        let buffers = 2 * len as u64;
+        let output = match layout {
+            Layout::Interleaved => 0,
+            Layout::Split(_) => output_memory(len),
+        };
+        buffers + output + self.matcher.memory(len)
+    }
+}
+
+/// The memory used to buffer the diff and extra bytes of a chunk of `len` bytes. They make up the
+/// size of the new chunk if they don't compress, plus the headers of the entries, which rarely add
+/// more than a quarter of that. The buffers grow by doubling, so they may take up twice as much.
+pub(crate) fn output_memory(len: usize) -> u64 {
+    2 * (len as u64 + len as u64 / 4)
+}
+
+/// Supplies the old data each chunk of the new file is diffed against.
+pub(crate) trait OldSource {
+    /// Fills `buf` with the old data to diff `new` against. Returns the amount of bytes read, and
+    /// the offset of the data in the old file, if it has to be recorded in the patch.
+    fn read_chunk(&mut self, new: &[u8], buf: &mut [u8]) -> io::Result<(usize, Option<u64>)>;
+
+    /// The amount of memory in bytes used to select the old data.
+    fn memory(&self) -> u64 {
+        0
+    }
+}
+
+/// Uses the chunk of the old file at the same position as the chunk of the new file.
+pub(crate) struct Aligned<'a, R>(pub(crate) &'a mut R);
+
+impl<R: Read> OldSource for Aligned<'_, R> {
+    fn read_chunk(&mut self, _new: &[u8], buf: &mut [u8]) -> io::Result<(usize, Option<u64>)> {
+        Ok((read_up_to(self.0, buf)?, None))
+    }
+}
+
+fn generate_chunked_with_layout(
+    old_f: &mut impl OldSource,
+    new_f: &mut impl Read,
+    patch_f: &mut impl Write,
+    chunk_sizes: Option<usize>,
+    layout: Layout,
+    options: &GenerateOptions<impl Matcher>,
+    mut progress: impl FnMut(State),
 ) -> Result<()> {
-    let chunk_sizes = chunk_sizes
-        .into()
-        .unwrap_or(i32::MAX as usize - 1)
-        .min(i32::MAX as usize - 1);
+    let chunk_sizes = options.chunk_size(chunk_sizes, layout, old_f.memory())?;
     let mut old_buf = vec![0; chunk_sizes];
     let mut new_buf = vec![0; chunk_sizes];
+    let buffers = old_f.memory() + (old_buf.len() + new_buf.len()) as u64;
+    let mut peak_memory = 0;
     let mut bytes_completed = 0;
     loop {
         progress(State::Reading);
-        let new_bytes_read = read_up_to(new_f, &mut new_buf)?;
+        let new_bytes_read = read_up_to(new_f, &mut new_buf).context(Stream::New)?;
         let new_buf = &new_buf[..new_bytes_read];
         // Nothing left in new file, so no need to read any more
         if new_buf.is_empty() {
             if bytes_completed == 0 {
-                write_header(patch_f, 0)?;
-                write_ending(patch_f)?;
+                peak_memory = generate_chunk(&[], &[], patch_f, layout, options, |_| {})?;
             }
             break;
         }
 
-        let old_bytes_read = read_up_to(old_f, &mut old_buf)?;
-        let old_buf = &old_buf[..old_bytes_read];
-
-        generate(old_buf, new_buf, patch_f, |d| match d {
-            State::Working(bytes) => progress(State::Working(bytes + bytes_completed)),
-            other => progress(other),
-        })?;
+        let memory = write_chunk(
+            old_f,
+            &mut old_buf,
+            new_buf,
+            patch_f,
+            layout,
+            options,
+            |d| match d {
+                State::Working(bytes) => progress(State::Working(bytes + bytes_completed)),
+                other => progress(other),
+            },
+        )?;
+        peak_memory = peak_memory.max(memory);
         bytes_completed += new_bytes_read as u64;
     }
+    write_end(patch_f, bytes_completed)?;
+    patch_f.flush().context(Stream::Patch)?;
+    progress(State::Finished(buffers + peak_memory));
     Ok(())
 }
 
-fn write_header(patch: &mut impl Write, len: u64) -> Result<()> {
+/// Writes the patch of one chunk of the new file, diffed against the old data `old_f` supplies for
+/// it. Returns the memory used like [`generate_chunk`].
+pub(crate) fn write_chunk(
+    old_f: &mut impl OldSource,
+    old_buf: &mut [u8],
+    new: &[u8],
+    patch_f: &mut impl Write,
+    layout: Layout,
+    options: &GenerateOptions<impl Matcher>,
+    mut progress: impl FnMut(State),
+) -> Result<u64> {
+    let (old_bytes_read, old_offset) = old_f.read_chunk(new, old_buf).context(Stream::Old)?;
+    let old_buf = &old_buf[..old_bytes_read];
+    if let Some(old_offset) = old_offset {
+        patch_f
+            .write_all(
+                WindowHeader {
+                    magic: *DDELTA_WINDOW_MAGIC,
+                    old_offset: U64::new(old_offset),
+                }
+                .as_bytes(),
+            )
+            .context(Stream::Patch)?;
+    }
+
+    options.check_cancelled()?;
+    progress(State::Sorting);
+    generate_chunk(old_buf, new, patch_f, layout, options, progress)
+}
+
+/// Writes a checksummed patch of `new` against `old`, which must not be larger than 2^31-1 bytes.
+/// Returns the peak amount of memory used for the index and the output.
+pub(crate) fn generate_chunk(
+    old: &[u8],
+    new: &[u8],
+    patch: &mut impl Write,
+    layout: Layout,
+    options: &GenerateOptions<impl Matcher>,
+    progress: impl FnMut(State),
+) -> Result<u64> {
+    let index = options.index(old);
+    let output = generate_chunk_sorted(old, &index, new, patch, layout, options, progress)?;
+    Ok(options.matcher.memory(old.len()) + output)
+}
+
+/// Like [`generate_chunk`], but with the index of `old` already built. Returns the memory used to
+/// buffer the output.
+pub(crate) fn generate_chunk_sorted<M: Matcher>(
+    old: &[u8],
+    index: &M::Index,
+    new: &[u8],
+    patch: &mut impl Write,
+    layout: Layout,
+    options: &GenerateOptions<M>,
+    progress: impl FnMut(State),
+) -> Result<u64> {
+    match layout {
+        Layout::Interleaved => {
+            write_checksum_header(patch, DDELTA_CHECKSUM_MAGIC, old, new)?;
+            let mut sink = Interleaved(&mut *patch);
+            generate_entries_sorted(old, index, new, &mut sink, options, progress)?;
+            write_ending(patch)?;
+            Ok(0)
+        }
+        Layout::Split(compression) => {
+            let mut sections = Sections::new(compression)?;
+            generate_entries_sorted(old, index, new, &mut sections, options, progress)?;
+            sections.finish(patch, old, new)
+        }
+    }
+}
+
+pub(crate) fn write_header(patch: &mut impl Write, len: u64) -> Result<()> {
     patch
         .write_all(
             PatchHeader {
@@ -86,10 +619,50 @@ fn write_header(patch: &mut impl Write, len: u64) -> Result<()> {
             }
             .as_bytes(),
         )
-        .context("Failed to write to patch file")
+        .context(Stream::Patch)
 }
 
-fn write_ending(patch: &mut impl Write) -> Result<()> {
+pub(crate) fn write_checksum_header(
+    patch: &mut impl Write,
+    magic: &[u8; 8],
+    old: &[u8],
+    new: &[u8],
+) -> Result<()> {
+    patch
+        .write_all(
+            PatchHeader {
+                magic: *magic,
+                new_file_size: U64::new(new.len() as u64),
+            }
+            .as_bytes(),
+        )
+        .and_then(|_| {
+            patch.write_all(
+                ChecksumHeader {
+                    old_file_size: U64::new(old.len() as u64),
+                    old_checksum: U32::new(crc32fast::hash(old)),
+                    new_checksum: U32::new(crc32fast::hash(new)),
+                }
+                .as_bytes(),
+            )
+        })
+        .context(Stream::Patch)
+}
+
+/// Ends a chunked patch, whose chunks produce `new_len` bytes in total.
+pub(crate) fn write_end(patch: &mut impl Write, new_len: u64) -> Result<()> {
+    patch
+        .write_all(
+            PatchHeader {
+                magic: *DDELTA_END_MAGIC,
+                new_file_size: U64::new(new_len),
+            }
+            .as_bytes(),
+        )
+        .context(Stream::Patch)
+}
+
+pub(crate) fn write_ending(patch: &mut impl Write) -> Result<()> {
     patch
         .write_all(
             EntryHeader {
@@ -99,7 +672,178 @@ fn write_ending(patch: &mut impl Write) -> Result<()> {
             }
             .as_bytes(),
         )
-        .context("Failed to write to patch file")
+        .context(Stream::Patch)
+}
+
+/// Writes the byte-wise difference `new - old` of two slices of the same length.
+pub(crate) fn write_diff(patch: &mut impl Write, old: &[u8], new: &[u8]) -> io::Result<()> {
+    let mut buf = [0; 4096];
+    for (old, new) in old.chunks(buf.len()).zip(new.chunks(buf.len())) {
+        let buf = &mut buf[..old.len()];
+        buf.iter_mut()
+            .zip(old.iter().zip(new.iter()))
+            .for_each(|(diff, (old, new))| *diff = new.wrapping_sub(*old));
+        patch.write_all(buf)?;
+    }
+    Ok(())
+}
+
+/// Receives the entries of a patch as they are generated.
+pub(crate) trait EntrySink {
+    /// Writes `entry`, whose diff bytes are calculated from `old` and `new`, and whose extra bytes
+    /// are `extra`.
+    fn write_entry(
+        &mut self,
+        entry: &EntryHeader,
+        old: &[u8],
+        new: &[u8],
+        extra: &[u8],
+    ) -> Result<()>;
+}
+
+/// Writes every entry directly followed by its diff and extra bytes, as done by ddelta.
+pub(crate) struct Interleaved<'a, W>(pub(crate) &'a mut W);
+
+impl<W: Write> EntrySink for Interleaved<'_, W> {
+    fn write_entry(
+        &mut self,
+        entry: &EntryHeader,
+        old: &[u8],
+        new: &[u8],
+        extra: &[u8],
+    ) -> Result<()> {
+        self.0.write_all(entry.as_bytes()).context(Stream::Patch)?;
+        write_diff(self.0, old, new).context(Stream::Patch)?;
+        self.0.write_all(extra).context(Stream::Patch)
+    }
+}
+
+/// Collects the control entries, diff and extra bytes in separately compressed buffers.
+struct Sections {
+    control: CompressedWriter<Vec<u8>>,
+    diff: CompressedWriter<Vec<u8>>,
+    extra: CompressedWriter<Vec<u8>>,
+    compression: Compression,
+}
+
+impl Sections {
+    fn new(compression: Compression) -> Result<Self> {
+        Ok(Sections {
+            control: CompressedWriter::without_header(Vec::new(), compression)?,
+            diff: CompressedWriter::without_header(Vec::new(), compression)?,
+            extra: CompressedWriter::without_header(Vec::new(), compression)?,
+            compression,
+        })
+    }
+
+    /// Writes the headers followed by all sections to `patch`. Returns the memory the sections took
+    /// up.
+    fn finish(self, patch: &mut impl Write, old: &[u8], new: &[u8]) -> Result<u64> {
+        let control = self.control.finish()?;
+        let extra = self.extra.finish()?;
+        let diff = self.diff.finish()?;
+        let memory = (control.capacity() + extra.capacity() + diff.capacity()) as u64;
+        write_checksum_header(patch, DDELTA_SPLIT_MAGIC, old, new)?;
+        patch
+            .write_all(
+                SplitHeader {
+                    compression: self.compression.id(),
+                    control_len: U64::new(control.len() as u64),
+                    extra_len: U64::new(extra.len() as u64),
+                    diff_len: U64::new(diff.len() as u64),
+                }
+                .as_bytes(),
+            )
+            .and_then(|_| patch.write_all(&control))
+            .and_then(|_| patch.write_all(&extra))
+            .and_then(|_| patch.write_all(&diff))
+            .context(Stream::Patch)?;
+        Ok(memory)
+    }
+}
+
+impl EntrySink for Sections {
+    fn write_entry(
+        &mut self,
+        entry: &EntryHeader,
+        old: &[u8],
+        new: &[u8],
+        extra: &[u8],
+    ) -> Result<()> {
+        self.control
+            .write_all(entry.as_bytes())
+            .context(Stream::Patch)?;
+        write_diff(&mut self.diff, old, new).context(Stream::Patch)?;
+        self.extra.write_all(extra).context(Stream::Patch)
+    }
+}
+
+/// Turns entries that would seek backwards in the old file into extra bytes, if `enabled` by
+/// [`forward_only`][GenerateOptions::forward_only].
+pub(crate) struct ForwardOnly<'a, S> {
+    inner: &'a mut S,
+    enabled: bool,
+    /// The position in the old file after the entries that have been written.
+    old_pos: i64,
+    /// The position the generated entries expect, which may be before `old_pos`.
+    expected_pos: i64,
+}
+
+impl<'a, S> ForwardOnly<'a, S> {
+    pub(crate) fn new<M>(inner: &'a mut S, options: &GenerateOptions<M>) -> Self {
+        ForwardOnly {
+            inner,
+            enabled: options.forward_only,
+            old_pos: 0,
+            expected_pos: 0,
+        }
+    }
+}
+
+impl<S: EntrySink> EntrySink for ForwardOnly<'_, S> {
+    fn write_entry(
+        &mut self,
+        entry: &EntryHeader,
+        old: &[u8],
+        new: &[u8],
+        extra: &[u8],
+    ) -> Result<()> {
+        if !self.enabled {
+            return self.inner.write_entry(entry, old, new, extra);
+        }
+        // Entries only seek up to where the next one expects, so the old data is either at the
+        // current position or has already been passed. Without diff bytes, nothing is read.
+        let reachable = new.is_empty() || self.old_pos == self.expected_pos;
+        self.expected_pos += new.len() as i64 + entry.seek.get();
+        if reachable {
+            self.old_pos += new.len() as i64;
+        }
+        let seek = (self.expected_pos - self.old_pos).max(0);
+        self.old_pos += seek;
+        let entry = EntryHeader {
+            seek: I64::new(seek),
+            ..*entry
+        };
+        if reachable {
+            if new.is_empty() && extra.is_empty() && seek == 0 {
+                // This would be mistaken for the end of the patch, and doesn't do anything anyway
+                return Ok(());
+            }
+            return self.inner.write_entry(&entry, old, new, extra);
+        }
+        // Store the new data instead of diffing it against old data that has been passed
+        let extra = [new, extra].concat();
+        self.inner.write_entry(
+            &EntryHeader {
+                diff: U64::new(0),
+                extra: U64::new(extra.len() as u64),
+                ..entry
+            },
+            &[],
+            &[],
+            &extra,
+        )
+    }
 }
 
 /// Generate a ddelta patch. This has a limit of 2^31-1 bytes.
@@ -109,21 +853,94 @@ fn write_ending(patch: &mut impl Write) -> Result<()> {
 /// tool, but not with bsdiff. Call [`apply`][crate::apply] or
 /// [`apply_chunked`][crate::apply_chunked] to use the created patch file. `progress` is a function
 /// that will be called periodically with progress updates.
+///
+/// As this format contains no checksums, applying the patch to the wrong old file or applying a
+/// corrupted patch is only detected if the patch doesn't fit the old file. Use [`generate_chunked`]
+/// to create a patch with checksums.
 pub fn generate(
     old: &[u8],
     new: &[u8],
     patch: &mut impl Write,
-    mut progress: impl FnMut(State) -> (),
+    progress: impl FnMut(State),
+) -> Result<()> {
+    GenerateOptions::new().generate(old, new, patch, progress)
+}
+
+/// Generate a patch that stores the control entries, diff bytes and extra bytes in three separate
+/// sections, like bsdiff does. This has a limit of 2^31-1 bytes.
+///
+/// Each section is compressed on its own with `compression`, which often results in a smaller
+/// patch than compressing the output of [`generate`]. Because of this, the output should not be
+/// wrapped in a [`CompressedWriter`]. The patch contains checksums like the ones of
+/// [`generate_chunked`]. The output is not compatible with the original ddelta tool or bsdiff, so
+/// call [`apply`][crate::apply] or [`apply_chunked`][crate::apply_chunked] to use the created patch
+/// file. While applying, the control entries and extra bytes are held in memory. `progress` is a
+/// function that will be called periodically with progress updates.
+pub fn generate_split(
+    old: &[u8],
+    new: &[u8],
+    patch: &mut impl Write,
+    compression: Compression,
+    mut progress: impl FnMut(State),
 ) -> Result<()> {
-    ensure!(
-        old.len().max(new.len()) < i32::MAX as usize,
-        "The filesize must not be larger than {} bytes",
-        i32::MAX
-    );
+    if old.len().max(new.len()) >= i32::MAX as usize {
+        return Err(Error::InputTooLarge);
+    }
     progress(State::Sorting);
-    write_header(patch, new.len() as u64)?;
-    let mut sorted = cdivsufsort::sort(old).into_parts().1;
-    sorted.push(0);
+    let layout = Layout::Split(compression);
+    let options = GenerateOptions::new();
+    let memory = generate_chunk(old, new, patch, layout, &options, &mut progress)?;
+    write_end(patch, new.len() as u64)?;
+    patch.flush().context(Stream::Patch)?;
+    progress(State::Finished(memory));
+    Ok(())
+}
+
+/// Generates the entries of a patch, without a header or ending. `old` and `new` must not be larger
+/// than 2^31-1 bytes.
+pub(crate) fn generate_entries(
+    old: &[u8],
+    new: &[u8],
+    sink: &mut impl EntrySink,
+    options: &GenerateOptions<impl Matcher>,
+    progress: impl FnMut(State),
+) -> Result<()> {
+    let index = options.index(old);
+    generate_entries_sorted(old, &index, new, sink, options, progress)
+}
+
+/// Calculates the suffix array of `old`, as used by [`search`]. This is the index of every
+/// [`SuffixSort`] matcher.
+pub(crate) fn sort<S: SuffixSort>(suffix_sort: &S, old: &[u8]) -> Vec<S::Index> {
+    let mut sorted = suffix_sort.sort(old);
+    sorted.push(S::Index::default());
+    sorted
+}
+
+/// Like [`generate_entries`], but with the index of `old` already built.
+pub(crate) fn generate_entries_sorted<M: Matcher>(
+    old: &[u8],
+    index: &M::Index,
+    new: &[u8],
+    sink: &mut impl EntrySink,
+    options: &GenerateOptions<M>,
+    progress: impl FnMut(State),
+) -> Result<()> {
+    let mut sink = ForwardOnly::new(sink, options);
+    scan_entries(old, index, new, &mut sink, options, progress)
+}
+
+/// Like [`generate_entries_sorted`], but the entries may seek backwards even if
+/// [`forward_only`][GenerateOptions::forward_only] is set.
+pub(crate) fn scan_entries<M: Matcher>(
+    old: &[u8],
+    index: &M::Index,
+    new: &[u8],
+    sink: &mut impl EntrySink,
+    options: &GenerateOptions<M>,
+    mut progress: impl FnMut(State),
+) -> Result<()> {
+    let effort = Effort::new(options.level);
     let mut scan = 0;
     let mut len = 0;
     let mut pos = 0;
@@ -140,21 +957,17 @@ pub fn generate(
         // go past that block of data. We need to track the number of
         // times we're stuck in the block and break out of it.
         while scan < new.len() as isize {
-            if scan % 100_00 == 0 {
+            options.check_cancelled()?;
+            if scan % 10_000 == 0 {
                 progress(State::Working(scan as u64));
             }
             let prev_len = len;
             let prev_oldscore = oldscore;
             let prev_pos = pos;
 
-            len = search(
-                &sorted,
-                &old[..old.len().wrapping_sub(1).min(old.len())],
-                &new[scan as usize..],
-                0,
-                old.len(),
-                &mut pos,
-            );
+            let (found_pos, found_len) = options.matcher.find(index, old, &new[scan as usize..]);
+            pos = found_pos as isize;
+            len = found_len as isize;
 
             while scsc < scan + len {
                 if (scsc + lastoffset < old.len() as isize)
@@ -165,7 +978,7 @@ pub fn generate(
                 scsc += 1;
             }
 
-            if ((len as isize == oldscore) && (len != 0)) || (len as isize > oldscore + 8) {
+            if ((len == oldscore) && (len != 0)) || (len > oldscore + effort.min_gain) {
                 break;
             }
 
@@ -175,25 +988,39 @@ pub fn generate(
                 oldscore -= 1;
             }
 
-            if prev_len as isize - FUZZ <= len as isize
+            if prev_len - FUZZ <= len
                 && len <= prev_len
                 && prev_oldscore - FUZZ <= oldscore
                 && oldscore <= prev_oldscore
                 && prev_pos <= pos
-                && pos as isize <= prev_pos as isize + FUZZ
-                && oldscore <= len as isize
-                && len as isize <= oldscore + FUZZ
+                && pos <= prev_pos + FUZZ
+                && oldscore <= len
+                && len <= oldscore + FUZZ
             {
                 num_less_than_eight += 1;
             } else {
                 num_less_than_eight = 0;
             }
 
-            if num_less_than_eight > 100 {
+            if num_less_than_eight > effort.max_stuck {
                 break;
             }
 
             scan += 1;
+            if effort.skip > 0 && len < FUZZ {
+                // Nothing worth using was found, so don't look for a match at the next few
+                // positions. Only the ones already compared at the current offset are uncounted.
+                let skip = effort.skip.min(new.len() as isize - scan);
+                for i in scan..(scan + skip).min(scsc) {
+                    if (i + lastoffset < old.len() as isize)
+                        && (old[(i + lastoffset) as usize] == new[i as usize])
+                    {
+                        oldscore -= 1;
+                    }
+                }
+                scan += skip;
+                scsc = scsc.max(scan);
+            }
         }
 
         if (len != oldscore) || (scan == new.len() as isize) {
@@ -252,40 +1079,26 @@ pub fn generate(
             if lenf < 0 || (scan - lenb) - (lastscan + lenf) < 0 {
                 panic!();
             }
-            patch
-                .write_all(
-                    EntryHeader {
-                        diff: U64::new(lenf as u64),
-                        extra: U64::new(((scan - lenb) - (lastscan + lenf)) as u64),
-                        seek: I64::new(((pos - lenb) - (lastpos + lenf)) as i64),
-                    }
-                    .as_bytes(),
-                )
-                .context("Failed to write to patch file")?;
-            for i in 0..lenf {
-                patch
-                    .write_u8(
-                        new[(lastscan + i) as usize].wrapping_sub(old[(lastpos + i) as usize]),
-                    )
-                    .context("Failed to write to patch file")?;
-            }
-            if (scan - lenb) - (lastscan + lenf) != 0 {
-                patch
-                    .write_all(&new[(lastscan + lenf) as usize..(scan - lenb) as usize])
-                    .context("Failed to write to patch file")?;
-            }
+            sink.write_entry(
+                &EntryHeader {
+                    diff: U64::new(lenf as u64),
+                    extra: U64::new(((scan - lenb) - (lastscan + lenf)) as u64),
+                    seek: I64::new(((pos - lenb) - (lastpos + lenf)) as i64),
+                },
+                &old[lastpos as usize..(lastpos + lenf) as usize],
+                &new[lastscan as usize..(lastscan + lenf) as usize],
+                &new[(lastscan + lenf) as usize..(scan - lenb) as usize],
+            )?;
 
             lastscan = scan - lenb;
             lastpos = pos - lenb;
             lastoffset = pos - scan;
         }
     }
-    write_ending(patch)?;
-    patch.flush()?;
     Ok(())
 }
 
-fn match_len(a: &[u8], b: &[u8]) -> usize {
+pub(crate) fn match_len(a: &[u8], b: &[u8]) -> usize {
     a.iter()
         .zip(b.iter())
         .enumerate()
@@ -305,21 +1118,28 @@ fn min_memcmp(a: &[u8], b: &[u8]) -> Ordering {
 /// `sorted`. `st` and `en` is the start and end of the search range (inclusive).
 /// Returns the length of the longest prefix found and stores the position of the
 /// string found in `*pos`.
-fn search(sorted: &[i32], old: &[u8], new: &[u8], st: usize, en: usize, pos: &mut isize) -> isize {
+pub(crate) fn search(
+    sorted: &[impl SuffixIndex],
+    old: &[u8],
+    new: &[u8],
+    st: usize,
+    en: usize,
+    pos: &mut isize,
+) -> isize {
     if en - st < 2 {
-        let x = match_len(&old[(sorted[st] as usize)..], new) as isize;
-        let y = match_len(&old[(sorted[en] as usize)..], new) as isize;
+        let x = match_len(&old[sorted[st].get()..], new) as isize;
+        let y = match_len(&old[sorted[en].get()..], new) as isize;
 
         if x > y {
-            *pos = sorted[st] as isize;
+            *pos = sorted[st].get() as isize;
             x
         } else {
-            *pos = sorted[en] as isize;
+            *pos = sorted[en].get() as isize;
             y
         }
     } else {
         let x = st + (en - st) / 2;
-        if min_memcmp(&old[(sorted[x] as usize)..], new) != Ordering::Greater {
+        if min_memcmp(&old[sorted[x].get()..], new) != Ordering::Greater {
             search(sorted, old, new, x, en, pos)
         } else {
             search(sorted, old, new, st, x, pos)
@@ -329,7 +1149,11 @@ fn search(sorted: &[i32], old: &[u8], new: &[u8], st: usize, en: usize, pos: &mu
 
 #[cfg(test)]
 mod test {
+    use std::io::Cursor;
+
     use crate::diff::match_len;
+    use crate::test_data::random_bytes;
+    use crate::{apply_chunked, Error, GenerateOptions, Layout, State};
 
     #[test]
     fn testy() {
@@ -338,4 +1162,85 @@ mod test {
         assert_eq!(match_len(b"abcdef", b"abc"), 3);
         assert_eq!(match_len(b"dabcde", b"abcfed"), 0);
     }
+
+    #[test]
+    fn levels() {
+        // Insert runs of bytes that aren't in the old file, which has no bytes above 0x7F, and
+        // change single bytes
+        let data = random_bytes(60_320);
+        let old: Vec<u8> = data[..2020].iter().map(|b| b & 0x7F).collect();
+        let mut new = old.clone();
+        for (k, r) in data[52_448..52_472].chunks(3).enumerate() {
+            let at = r[0] as usize * new.len() / 256;
+            let inserted = &data[60_000 + k * 40..][..r[1] as usize % 40];
+            new.splice(at..at, inserted.iter().map(|b| b | 0x80));
+            let at = r[2] as usize * new.len() / 256;
+            new[at] ^= 0x55;
+        }
+
+        for level in 0..=10 {
+            let mut patch = Vec::new();
+            GenerateOptions::new()
+                .level(level)
+                .generate(&old, &new, &mut patch, |_| {})
+                .unwrap();
+            if level == 5 {
+                // The patch created by versions before levels were added
+                assert_eq!((patch.len(), crc32fast::hash(&patch)), (2422, 0x93B6_DDB0));
+            }
+            let mut patched = Vec::new();
+            crate::apply(&mut Cursor::new(&old), &mut patched, &mut &patch[..]).unwrap();
+            assert_eq!(patched, new);
+        }
+    }
+
+    #[test]
+    fn max_memory() {
+        let old = random_bytes(100_000);
+        let mut new = old.clone();
+        new[50_000..50_100].copy_from_slice(&[1; 100]);
+
+        for &layout in &[Layout::Interleaved, Layout::Split(crate::Compression::None)] {
+            let options = GenerateOptions::new().max_memory(1_000_000);
+            let mut patch = Vec::new();
+            let mut peak = None;
+            options
+                .generate_chunked(
+                    &mut &old[..],
+                    &mut &new[..],
+                    &mut patch,
+                    None,
+                    layout,
+                    |s| {
+                        if let State::Finished(memory) = s {
+                            peak = Some(memory);
+                        }
+                    },
+                )
+                .unwrap();
+            let peak = peak.unwrap();
+            assert!(peak <= 1_000_000 && peak > 750_000, "{}", peak);
+            let mut patched = Vec::new();
+            apply_chunked(&mut Cursor::new(&old), &mut patched, &mut &patch[..]).unwrap();
+            assert_eq!(patched, new);
+        }
+
+        let options = GenerateOptions::new().max_memory(100_000);
+        assert!(matches!(
+            options.generate(&old, &new, &mut Vec::new(), |_| {}),
+            Err(Error::MemoryLimit)
+        ));
+        let options = options.max_memory(1000);
+        assert!(matches!(
+            options.generate_chunked(
+                &mut &old[..],
+                &mut &new[..],
+                &mut Vec::new(),
+                None,
+                Layout::Interleaved,
+                |_| {}
+            ),
+            Err(Error::MemoryLimit)
+        ));
+    }
 }
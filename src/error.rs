@@ -0,0 +1,155 @@
+use core::fmt;
+
+use crate::io::{self, ErrorKind};
+use crate::Compression;
+
+/// A stream involved in generating or applying a patch.
+#[derive(Eq, PartialEq, Copy, Clone, Hash, Debug)]
+pub enum Stream {
+    /// The old/original file.
+    Old,
+    /// The new file.
+    New,
+    /// The patch file.
+    Patch,
+    /// A serialized [`OldIndex`][crate::OldIndex].
+    Index,
+    /// The journal [`Checkpoint`][crate::Checkpoint]s are saved to.
+    Journal,
+}
+
+impl fmt::Display for Stream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Stream::Old => "old",
+            Stream::New => "new",
+            Stream::Patch => "patch",
+            Stream::Index => "index",
+            Stream::Journal => "journal",
+        })
+    }
+}
+
+/// An error that occurred while generating or applying a patch.
+#[derive(Debug)]
+pub enum Error {
+    /// The patch does not start with a known magic number.
+    BadMagic,
+    /// The patch is compressed with an algorithm whose feature is not enabled.
+    UnsupportedCompression(Compression),
+    /// The patch file ended unexpectedly.
+    TruncatedPatch,
+    /// The patch contains invalid data, such as negative lengths.
+    CorruptPatch,
+    /// The patch ended before producing the amount of bytes declared in its header.
+    SizeMismatch {
+        /// The size of the new file, as declared in the header.
+        expected: u64,
+        /// The amount of bytes that were actually written.
+        actual: u64,
+    },
+    /// The patch reads parts of the old file that have already been overwritten by the new file,
+    /// so it can't be applied with [`apply_in_place`][crate::apply_in_place].
+    NotInPlace,
+    /// The patch seeks backwards in the old file, so it can't be applied with
+    /// [`apply_streaming`][crate::apply_streaming].
+    BackwardSeek,
+    /// The patch stores its diff and extra bytes in separate sections, so a
+    /// [`PatchIndex`][crate::PatchIndex] can't be created for it.
+    NoRandomAccess,
+    /// A serialized [`OldIndex`][crate::OldIndex] contains invalid data.
+    CorruptIndex,
+    /// The old or new file is too large to be handled in a single patch. See
+    /// [`generate_chunked`][crate::generate_chunked] or
+    /// [`GenerateOptions::suffix_sort`][crate::GenerateOptions::suffix_sort] for larger files.
+    InputTooLarge,
+    /// The memory limit set with [`GenerateOptions::max_memory`][crate::GenerateOptions::max_memory]
+    /// is too low to index the old file, or to hold the smallest chunk.
+    MemoryLimit,
+    /// The [`CancelToken`][crate::CancelToken] passed to the options was cancelled before the
+    /// patch was fully generated or applied.
+    Cancelled,
+    /// The checksum of the old or new file did not match the one stored in the patch.
+    ///
+    /// A mismatch of [`Stream::Old`] means that the old file is not the one the patch was created
+    /// from. This is detected before any data is written to the new file. A mismatch of
+    /// [`Stream::New`] is detected after all data has been written, so the new file should be
+    /// discarded.
+    ChecksumMismatch(Stream),
+    /// Reading from or writing to the given stream failed.
+    Io(Stream, io::Error),
+}
+
+/// A specialized [`Result`][std::result::Result] type for ddelta operations.
+pub type Result<T> = core::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::BadMagic => write!(f, "Invalid magic number"),
+            Error::UnsupportedCompression(compression) => {
+                write!(f, "Support for {} compression is not enabled", compression)
+            }
+            Error::TruncatedPatch => write!(f, "Patch too short"),
+            Error::CorruptPatch => write!(f, "Patch is corrupt"),
+            Error::NotInPlace => write!(f, "The patch can't be applied in place"),
+            Error::BackwardSeek => write!(f, "The patch seeks backwards in the old file"),
+            Error::NoRandomAccess => write!(f, "Ranges can't be read from split or bsdiff patches"),
+            Error::CorruptIndex => write!(f, "Index is corrupt"),
+            Error::SizeMismatch { expected, actual } => write!(
+                f,
+                "Patch produced {} bytes, but {} bytes were expected",
+                actual, expected
+            ),
+            Error::InputTooLarge => {
+                write!(f, "The file is too large to be diffed in a single chunk")
+            }
+            Error::MemoryLimit => write!(f, "The memory limit is too low to generate a patch"),
+            Error::Cancelled => write!(f, "The operation was cancelled"),
+            Error::ChecksumMismatch(Stream::New) => {
+                write!(f, "The patched file does not match the patch")
+            }
+            Error::ChecksumMismatch(stream) => {
+                write!(f, "The {} file does not match the patch", stream)
+            }
+            Error::Io(stream, _) => write!(f, "Failed to access the {} file", stream),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(_, err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+/// Converts an error back into an [`io::Error`], to be returned from I/O traits such as the
+/// [`Read`][std::io::Read] implementation of [`PatchedReader`][crate::PatchedReader]. I/O errors
+/// are unwrapped, and everything else becomes an error of kind [`ErrorKind::InvalidData`].
+#[cfg(feature = "std")]
+impl From<Error> for io::Error {
+    fn from(err: Error) -> Self {
+        match err {
+            Error::Io(_, err) => err,
+            err => io::Error::new(ErrorKind::InvalidData, err),
+        }
+    }
+}
+
+/// Tags an [`io::Error`] with the [`Stream`] it occurred on.
+pub(crate) trait Context<T> {
+    fn context(self, stream: Stream) -> Result<T>;
+}
+
+impl<T> Context<T> for core::result::Result<T, io::Error> {
+    fn context(self, stream: Stream) -> Result<T> {
+        self.map_err(|err| match stream {
+            Stream::Patch if err.kind() == ErrorKind::UnexpectedEof => Error::TruncatedPatch,
+            _ => Error::Io(stream, err),
+        })
+    }
+}
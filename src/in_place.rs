@@ -0,0 +1,259 @@
+//! Applying a patch to a single file, which contains the old file before and the new file after.
+//!
+//! The new file is written sequentially from its start, so once `n` bytes have been written, the
+//! first `n` bytes of the old file are lost. A patch can therefore only be applied in place if every
+//! diff reads the old file at or after the position it writes the new file to.
+
+use std::cell::RefCell;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+#[cfg(feature = "diff")]
+use zerocopy::{I64, U64};
+
+#[cfg(feature = "diff")]
+use crate::diff::{
+    generate_entries, write_checksum_header, write_end, write_ending, EntrySink, Interleaved,
+};
+use crate::error::Context;
+use crate::{ApplyOptions, Error, PatchReader, Record, Result, Stream};
+#[cfg(feature = "diff")]
+use crate::{EntryHeader, GenerateOptions, State, DDELTA_CHECKSUM_MAGIC};
+
+/// Reads the old file from a file that is written to at the same time.
+struct OldHandle<'a, F> {
+    file: &'a RefCell<&'a mut F>,
+    pos: u64,
+}
+
+impl<F: Read + Seek> Read for OldHandle<'_, F> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut file = self.file.borrow_mut();
+        file.seek(SeekFrom::Start(self.pos))?;
+        let read = file.read(buf)?;
+        self.pos += read as u64;
+        Ok(read)
+    }
+}
+
+impl<F: Seek> Seek for OldHandle<'_, F> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+            SeekFrom::End(_) => Some(self.file.borrow_mut().seek(pos)?),
+        };
+        self.pos = new_pos.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "seek to a negative position")
+        })?;
+        Ok(self.pos)
+    }
+}
+
+/// Writes the new file to a file that is read from at the same time.
+struct NewHandle<'a, F> {
+    file: &'a RefCell<&'a mut F>,
+    pos: u64,
+}
+
+impl<F: Write + Seek> Write for NewHandle<'_, F> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut file = self.file.borrow_mut();
+        file.seek(SeekFrom::Start(self.pos))?;
+        let written = file.write(buf)?;
+        self.pos += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.borrow_mut().flush()
+    }
+}
+
+/// Checks that every diff of `patch` reads old data that hasn't been overwritten yet. Returns the
+/// size of the new file.
+fn check(patch: &mut impl Read) -> Result<u64> {
+    let mut old_pos = 0i64;
+    let mut new_pos = 0;
+    for record in PatchReader::new(patch)? {
+        match record? {
+            Record::Chunk(chunk) => {
+                old_pos = chunk.old_offset.unwrap_or(chunk.new_offset) as i64;
+                // The old data is read to verify its checksum before the chunk is applied
+                let reads_old = chunk.checksums.is_some_and(|c| c.old_size > 0);
+                if reads_old && old_pos < chunk.new_offset as i64 {
+                    return Err(Error::NotInPlace);
+                }
+                new_pos = chunk.new_offset + chunk.new_size;
+            }
+            Record::Entry(entry) => {
+                if entry.diff > 0 && old_pos < entry.new_offset as i64 {
+                    return Err(Error::NotInPlace);
+                }
+                old_pos += entry.diff as i64 + entry.seek;
+            }
+        }
+    }
+    Ok(new_pos)
+}
+
+/// Apply a patch to `file`, which contains the old file, replacing it with the new file. This
+/// supports the same formats as [`apply_chunked`][crate::apply_chunked], but only patches that
+/// never read parts of the old file that have already been overwritten, such as the ones created by
+/// [`generate_in_place`].
+///
+/// The patch is read twice: first to check that it can be applied in place, which fails with
+/// [`Error::NotInPlace`] before `file` is modified if it can't, and then to apply it. Returns the
+/// size of the new file. If the new file is smaller than the old file, `file` still contains the
+/// rest of the old file afterwards, so it should be truncated to the returned size, e.g. with
+/// [`File::set_len`][std::fs::File::set_len].
+///
+/// If the patch contains checksums and the new data doesn't match, the old file has already been
+/// overwritten, so the contents of `file` are undefined.
+pub fn apply_in_place(
+    file: &mut (impl Read + Write + Seek),
+    patch: &mut (impl Read + Seek),
+) -> Result<u64> {
+    ApplyOptions::new().apply_in_place(file, patch)
+}
+
+impl ApplyOptions<'_> {
+    /// Apply a patch file like [`apply_in_place`]. If the cancel token is cancelled, `file` is
+    /// left partially overwritten.
+    pub fn apply_in_place(
+        &mut self,
+        file: &mut (impl Read + Write + Seek),
+        patch: &mut (impl Read + Seek),
+    ) -> Result<u64> {
+        let start = patch.stream_position().context(Stream::Patch)?;
+        let new_len = check(patch)?;
+        patch.seek(SeekFrom::Start(start)).context(Stream::Patch)?;
+
+        let file = RefCell::new(file);
+        let mut old = OldHandle {
+            file: &file,
+            pos: 0,
+        };
+        let mut new = NewHandle {
+            file: &file,
+            pos: 0,
+        };
+        self.apply_chunked(&mut old, &mut new, patch)?;
+        Ok(new_len)
+    }
+}
+
+/// Turns entries that would read overwritten data into extra bytes.
+#[cfg(feature = "diff")]
+struct InPlaceSafe<S> {
+    inner: S,
+    old_pos: i64,
+    new_pos: u64,
+}
+
+#[cfg(feature = "diff")]
+impl<S: EntrySink> EntrySink for InPlaceSafe<S> {
+    fn write_entry(
+        &mut self,
+        entry: &EntryHeader,
+        old: &[u8],
+        new: &[u8],
+        extra: &[u8],
+    ) -> Result<()> {
+        let safe = new.is_empty() || self.old_pos >= self.new_pos as i64;
+        self.old_pos += new.len() as i64 + entry.seek.get();
+        self.new_pos += (new.len() + extra.len()) as u64;
+        if safe {
+            return self.inner.write_entry(entry, old, new, extra);
+        }
+        // Store the new data instead, and skip the old data that would have been read
+        let extra = [new, extra].concat();
+        self.inner.write_entry(
+            &EntryHeader {
+                diff: U64::new(0),
+                extra: U64::new(extra.len() as u64),
+                seek: I64::new(entry.seek.get() + new.len() as i64),
+            },
+            &[],
+            &[],
+            &extra,
+        )
+    }
+}
+
+/// Generate a patch that can be applied with [`apply_in_place`]. This has a limit of 2^31-1 bytes.
+///
+/// This works like [`generate_chunked`][crate::generate_chunked] with a single chunk, but every
+/// part of the new file that would be diffed against a part of the old file that has already been
+/// overwritten at that point is stored in the patch as is. Depending on how much data was moved
+/// towards the end of the file, the patch may be larger than the one created by `generate_chunked`.
+/// It can also be applied with [`apply`][crate::apply] and [`apply_chunked`][crate::apply_chunked].
+#[cfg(feature = "diff")]
+pub fn generate_in_place(
+    old: &[u8],
+    new: &[u8],
+    patch: &mut impl Write,
+    mut progress: impl FnMut(State),
+) -> Result<()> {
+    if old.len().max(new.len()) >= i32::MAX as usize {
+        return Err(Error::InputTooLarge);
+    }
+    progress(State::Sorting);
+    write_checksum_header(patch, DDELTA_CHECKSUM_MAGIC, old, new)?;
+    let mut sink = InPlaceSafe {
+        inner: Interleaved(&mut *patch),
+        old_pos: 0,
+        new_pos: 0,
+    };
+    generate_entries(old, new, &mut sink, &GenerateOptions::new(), progress)?;
+    write_ending(patch)?;
+    write_end(patch, new.len() as u64)?;
+    patch.flush().context(Stream::Patch)
+}
+
+#[cfg(all(test, feature = "diff"))]
+mod test {
+    use std::io::Cursor;
+
+    use super::{apply_in_place, generate_in_place};
+    use crate::test_data::random_bytes;
+    use crate::{generate, Error};
+
+    /// Moves the first block of `old` to the end, so a regular patch would read it after it has
+    /// been overwritten.
+    fn moved() -> (Vec<u8>, Vec<u8>) {
+        let old = random_bytes(20_000);
+        let mut new = old[5000..].to_vec();
+        new.extend_from_slice(&old[..5000]);
+        new.extend_from_slice(b"appended");
+        (old, new)
+    }
+
+    #[test]
+    fn in_place_roundtrip() {
+        let (old, new) = moved();
+        let mut patch = Vec::new();
+        generate_in_place(&old, &new, &mut patch, |_| {}).unwrap();
+        let mut file = Cursor::new(old.clone());
+        let len = apply_in_place(&mut file, &mut Cursor::new(&patch)).unwrap();
+        assert_eq!(&file.get_ref()[..len as usize], &new[..]);
+
+        // Shrinking the file
+        let mut patch = Vec::new();
+        generate_in_place(&new, &old, &mut patch, |_| {}).unwrap();
+        let len = apply_in_place(&mut file, &mut Cursor::new(&patch)).unwrap();
+        assert_eq!(&file.get_ref()[..len as usize], &old[..]);
+    }
+
+    #[test]
+    fn not_in_place() {
+        let (old, new) = moved();
+        let mut patch = Vec::new();
+        generate(&old, &new, &mut patch, |_| {}).unwrap();
+        let mut file = Cursor::new(old.clone());
+        assert!(matches!(
+            apply_in_place(&mut file, &mut Cursor::new(&patch)),
+            Err(Error::NotInPlace)
+        ));
+        assert_eq!(file.get_ref(), &old);
+    }
+}
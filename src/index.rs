@@ -0,0 +1,314 @@
+use std::io::{Read, Write};
+use std::mem::size_of;
+
+use zerocopy::{AsBytes, U32, U64};
+
+use crate::diff::{
+    generate_chunk_sorted, generate_entries_sorted, write_end, write_ending, write_header,
+    Interleaved,
+};
+use crate::error::Context;
+use crate::suffix::{DivSufSort, SuffixIndex, SuffixSort};
+use crate::{
+    Compression, Error, GenerateOptions, IndexHeader, Layout, Result, State, Stream,
+    DDELTA_INDEX64_MAGIC, DDELTA_INDEX_MAGIC,
+};
+
+/// The amount of suffix array entries converted at once when reading or writing an index.
+const BLOCK_LEN: usize = 8 * 1024;
+
+/// An old file together with its suffix array, to diff it against many new files.
+///
+/// Calculating the suffix array takes most of the time of [`generate`][crate::generate], and only
+/// depends on the old file. An `OldIndex` calculates it once, so that every patch created from it
+/// only needs to scan the new file. With the default [`DivSufSort`], the suffix array takes up 4
+/// times the size of the old file. It can be saved with [`write_to`][OldIndex::write_to] and loaded
+/// again with [`read_from`][OldIndex::read_from], so the sort is only done once per old file.
+///
+/// ```no_run
+/// # fn main() -> ddelta::Result<()> {
+/// use ddelta::OldIndex;
+/// use std::fs::{self, File};
+///
+/// let index = OldIndex::new(fs::read("old").unwrap())?;
+/// for name in &["new-x86", "new-arm"] {
+///     let new = fs::read(name).unwrap();
+///     let mut patch = File::create(format!("{}.patch", name)).unwrap();
+///     index.generate(&new, &mut patch, |_| {})?;
+/// }
+/// # Ok(())
+/// # }
+/// ```
+///
+/// To use another suffix sort or other [`GenerateOptions`], create the index with
+/// [`GenerateOptions::old_index`].
+pub struct OldIndex<S: SuffixSort = DivSufSort> {
+    old: Vec<u8>,
+    sorted: Vec<S::Index>,
+    options: GenerateOptions<S>,
+    /// The memory the suffix sort uses, which is reported as part of [`State::Finished`].
+    memory: u64,
+}
+
+impl OldIndex {
+    /// Calculates the suffix array of `old`, which has a limit of 2^31-1 bytes.
+    pub fn new(old: Vec<u8>) -> Result<Self> {
+        GenerateOptions::new().old_index(old)
+    }
+
+    /// Reads a suffix array written by [`write_to`][OldIndex::write_to] from `index`, which must
+    /// have been created from `old`.
+    ///
+    /// If the index was created from a different file, [`Error::ChecksumMismatch`] with
+    /// [`Stream::Old`] is returned.
+    pub fn read_from(old: Vec<u8>, index: &mut impl Read) -> Result<Self> {
+        GenerateOptions::new().read_old_index(old, index)
+    }
+}
+
+impl<S: SuffixSort + Clone> GenerateOptions<S> {
+    /// Calculates the suffix array of `old` with the [`suffix_sort`][GenerateOptions::suffix_sort]
+    /// of these options, which are used for every patch generated from the returned index. Fails
+    /// like [`generate`][GenerateOptions::generate] if the old file is too large for the suffix
+    /// sort, or its index doesn't fit into the [`max_memory`][GenerateOptions::max_memory].
+    pub fn old_index(&self, old: Vec<u8>) -> Result<OldIndex<S>> {
+        let memory = self.check_old(old.len())?;
+        let sorted = self.index(&old);
+        Ok(OldIndex {
+            old,
+            sorted,
+            options: self.clone(),
+            memory,
+        })
+    }
+
+    /// Reads a suffix array like [`OldIndex::read_from`], which must have been written by an index
+    /// with the same suffix sort. The options are used for every patch generated from the returned
+    /// index.
+    pub fn read_old_index(&self, old: Vec<u8>, index: &mut impl Read) -> Result<OldIndex<S>> {
+        let memory = self.check_old(old.len())?;
+        let (magic, width) = index_format::<S::Index>();
+        let mut buf = [0; size_of::<IndexHeader>()];
+        index.read_exact(&mut buf).context(Stream::Index)?;
+        let header = zerocopy::LayoutVerified::<_, IndexHeader>::new_unaligned(&buf[..])
+            .expect("buffer has the size of the header");
+        if &header.magic != magic {
+            return Err(Error::BadMagic);
+        }
+        if header.old_file_size.get() != old.len() as u64
+            || header.old_checksum.get() != crc32fast::hash(&old)
+        {
+            return Err(Error::ChecksumMismatch(Stream::Old));
+        }
+        let mut sorted = Vec::with_capacity(old.len() + 1);
+        let mut buf = vec![0; BLOCK_LEN * width];
+        while sorted.len() < old.len() {
+            let len = (old.len() - sorted.len()).min(BLOCK_LEN) * width;
+            index.read_exact(&mut buf[..len]).context(Stream::Index)?;
+            for bytes in buf[..len].chunks_exact(width) {
+                let pos = bytes.iter().fold(0, |pos, &byte| pos << 8 | byte as u64);
+                if pos >= old.len() as u64 {
+                    return Err(Error::CorruptIndex);
+                }
+                sorted.push(S::Index::new(pos as usize));
+            }
+        }
+        sorted.push(S::Index::default());
+        Ok(OldIndex {
+            old,
+            sorted,
+            options: self.clone(),
+            memory,
+        })
+    }
+}
+
+/// The magic number and the width in bytes of the positions of a serialized suffix array.
+fn index_format<I: SuffixIndex>() -> (&'static [u8; 8], usize) {
+    if size_of::<I>() <= size_of::<u32>() {
+        (DDELTA_INDEX_MAGIC, size_of::<u32>())
+    } else {
+        (DDELTA_INDEX64_MAGIC, size_of::<u64>())
+    }
+}
+
+impl<S: SuffixSort> OldIndex<S> {
+    /// The old file this index was created from.
+    pub fn old(&self) -> &[u8] {
+        &self.old
+    }
+
+    /// Generate a patch like [`generate`][GenerateOptions::generate]. The output is the same as if
+    /// `generate` was called with the old file and options of this index.
+    pub fn generate(
+        &self,
+        new: &[u8],
+        patch: &mut impl Write,
+        mut progress: impl FnMut(State),
+    ) -> Result<()> {
+        write_header(patch, new.len() as u64)?;
+        generate_entries_sorted(
+            &self.old,
+            &self.sorted,
+            new,
+            &mut Interleaved(&mut *patch),
+            &self.options,
+            &mut progress,
+        )?;
+        write_ending(patch)?;
+        patch.flush().context(Stream::Patch)?;
+        progress(State::Finished(self.memory));
+        Ok(())
+    }
+
+    /// Generate a patch like [`generate_split`][crate::generate_split], with the options of this
+    /// index. The output is the same as if `generate_split` was called with the old file of this
+    /// index, if the index was created with the default options. Unlike `generate_split`, the new
+    /// file has no size limit.
+    pub fn generate_split(
+        &self,
+        new: &[u8],
+        patch: &mut impl Write,
+        compression: Compression,
+        mut progress: impl FnMut(State),
+    ) -> Result<()> {
+        let output = generate_chunk_sorted(
+            &self.old,
+            &self.sorted,
+            new,
+            patch,
+            Layout::Split(compression),
+            &self.options,
+            &mut progress,
+        )?;
+        write_end(patch, new.len() as u64)?;
+        patch.flush().context(Stream::Patch)?;
+        progress(State::Finished(self.memory + output));
+        Ok(())
+    }
+
+    /// Writes the suffix array to `index`. The old file itself is not included, only a checksum of
+    /// it, so it has to be passed to [`read_from`][OldIndex::read_from] as well.
+    pub fn write_to(&self, index: &mut impl Write) -> Result<()> {
+        let (magic, width) = index_format::<S::Index>();
+        index
+            .write_all(
+                IndexHeader {
+                    magic: *magic,
+                    old_file_size: U64::new(self.old.len() as u64),
+                    old_checksum: U32::new(crc32fast::hash(&self.old)),
+                }
+                .as_bytes(),
+            )
+            .context(Stream::Index)?;
+        let mut buf = Vec::with_capacity(BLOCK_LEN * width);
+        // The last entry is a sentinel added by sort, which isn't part of the suffix array
+        for block in self.sorted[..self.old.len()].chunks(BLOCK_LEN) {
+            buf.clear();
+            for &pos in block {
+                buf.extend_from_slice(
+                    &(pos.get() as u64).to_be_bytes()[size_of::<u64>() - width..],
+                );
+            }
+            index.write_all(&buf).context(Stream::Index)?;
+        }
+        index.flush().context(Stream::Index)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::OldIndex;
+    use crate::{
+        generate, generate_split, Compression, Error, GenerateOptions, SaIs, State, Stream,
+    };
+
+    const OLD: &[u8] = b"The quick brown fox jumps over the lazy dog. The end.";
+    const NEW: &[u8] = b"The quick red fox jumped over the lazy dogs! The end!";
+
+    #[test]
+    fn same_as_generate() {
+        let index = OldIndex::new(OLD.to_vec()).unwrap();
+        let mut expected = Vec::new();
+        let mut expected_memory = None;
+        generate(OLD, NEW, &mut expected, |state| {
+            if let State::Finished(memory) = state {
+                expected_memory = Some(memory);
+            }
+        })
+        .unwrap();
+        let mut patch = Vec::new();
+        let mut memory = None;
+        index
+            .generate(NEW, &mut patch, |state| {
+                if let State::Finished(finished) = state {
+                    memory = Some(finished);
+                }
+            })
+            .unwrap();
+        assert_eq!(patch, expected);
+        assert_eq!(memory, expected_memory);
+
+        let mut expected = Vec::new();
+        generate_split(OLD, NEW, &mut expected, Compression::None, |state| {
+            if let State::Finished(memory) = state {
+                expected_memory = Some(memory);
+            }
+        })
+        .unwrap();
+        let mut patch = Vec::new();
+        index
+            .generate_split(NEW, &mut patch, Compression::None, |state| {
+                if let State::Finished(finished) = state {
+                    memory = Some(finished);
+                }
+            })
+            .unwrap();
+        assert_eq!(patch, expected);
+        assert_eq!(memory, expected_memory);
+    }
+
+    #[test]
+    fn serialize() {
+        let index = OldIndex::new(OLD.to_vec()).unwrap();
+        let mut serialized = Vec::new();
+        index.write_to(&mut serialized).unwrap();
+        let read = OldIndex::read_from(OLD.to_vec(), &mut &serialized[..]).unwrap();
+        assert_eq!(read.sorted, index.sorted);
+
+        assert!(matches!(
+            OldIndex::read_from(NEW.to_vec(), &mut &serialized[..]),
+            Err(Error::ChecksumMismatch(Stream::Old))
+        ));
+    }
+
+    #[test]
+    fn options() {
+        let options = GenerateOptions::new().suffix_sort(SaIs).level(9);
+        let index = options.old_index(OLD.to_vec()).unwrap();
+        let mut expected = Vec::new();
+        options.generate(OLD, NEW, &mut expected, |_| {}).unwrap();
+        let mut patch = Vec::new();
+        index.generate(NEW, &mut patch, |_| {}).unwrap();
+        assert_eq!(patch, expected);
+
+        // The positions of SA-IS are stored as 64-bit numbers
+        let mut serialized = Vec::new();
+        index.write_to(&mut serialized).unwrap();
+        assert_eq!(serialized.len(), 20 + OLD.len() * 8);
+        let read = options
+            .read_old_index(OLD.to_vec(), &mut &serialized[..])
+            .unwrap();
+        assert_eq!(read.sorted, index.sorted);
+        assert!(matches!(
+            OldIndex::read_from(OLD.to_vec(), &mut &serialized[..]),
+            Err(Error::BadMagic)
+        ));
+
+        let options = GenerateOptions::new().max_memory(100);
+        assert!(matches!(
+            options.old_index(OLD.to_vec()),
+            Err(Error::MemoryLimit)
+        ));
+    }
+}
@@ -0,0 +1,180 @@
+//! Minimal I/O traits used to apply patches, which are available without the standard library.
+//!
+//! With the `std` feature, these are implemented for every type implementing the traits of the same
+//! name in [`std::io`], and [`Error`], [`ErrorKind`] and [`SeekFrom`] are the ones of `std::io`.
+//! Without it, implement them for the storage the old and new file live in, such as flash memory.
+
+#[cfg(feature = "std")]
+pub use std::io::{Error, ErrorKind, SeekFrom};
+
+/// The kind of an I/O [`Error`].
+#[cfg(not(feature = "std"))]
+#[derive(Eq, PartialEq, Copy, Clone, Hash, Debug)]
+pub enum ErrorKind {
+    /// A read ended before filling the whole buffer.
+    UnexpectedEof,
+    /// A write didn't accept any more bytes.
+    WriteZero,
+    /// A seek went before the start of the stream.
+    InvalidInput,
+    /// Any other error.
+    Other,
+}
+
+/// An error that occurred while reading, writing or seeking.
+#[cfg(not(feature = "std"))]
+#[derive(Debug)]
+pub struct Error {
+    kind: ErrorKind,
+}
+
+#[cfg(not(feature = "std"))]
+impl Error {
+    /// The kind of this error.
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl From<ErrorKind> for Error {
+    fn from(kind: ErrorKind) -> Self {
+        Error { kind }
+    }
+}
+
+/// A position to seek to.
+#[cfg(not(feature = "std"))]
+#[derive(Eq, PartialEq, Copy, Clone, Debug)]
+pub enum SeekFrom {
+    /// An offset from the start of the stream.
+    Start(u64),
+    /// An offset from the end of the stream.
+    End(i64),
+    /// An offset from the current position.
+    Current(i64),
+}
+
+/// A source of bytes, such as the old file or the patch.
+pub trait Read {
+    /// Reads up to `buf.len()` bytes, returning how many were read. `0` means the end was reached.
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error>;
+
+    /// Fills the whole `buf`, failing with [`ErrorKind::UnexpectedEof`] if the end is reached
+    /// first.
+    fn read_exact(&mut self, mut buf: &mut [u8]) -> Result<(), Error> {
+        while !buf.is_empty() {
+            match self.read(buf)? {
+                0 => return Err(ErrorKind::UnexpectedEof.into()),
+                n => buf = &mut buf[n..],
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A destination for bytes, such as the new file.
+pub trait Write {
+    /// Writes all of `buf`.
+    fn write_all(&mut self, buf: &[u8]) -> Result<(), Error>;
+
+    /// Makes sure everything written so far has reached its destination.
+    fn flush(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// A stream with a position that can be changed, such as the old file.
+pub trait Seek {
+    /// Moves to `pos`, returning the new position from the start of the stream.
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64, Error>;
+
+    /// Returns the current position from the start of the stream.
+    fn stream_position(&mut self) -> Result<u64, Error> {
+        self.seek(SeekFrom::Current(0))
+    }
+}
+
+#[cfg(feature = "std")]
+impl<R: std::io::Read + ?Sized> Read for R {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        std::io::Read::read(self, buf)
+    }
+
+    fn read_exact(&mut self, buf: &mut [u8]) -> Result<(), Error> {
+        std::io::Read::read_exact(self, buf)
+    }
+}
+
+#[cfg(feature = "std")]
+impl<W: std::io::Write + ?Sized> Write for W {
+    fn write_all(&mut self, buf: &[u8]) -> Result<(), Error> {
+        std::io::Write::write_all(self, buf)
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        std::io::Write::flush(self)
+    }
+}
+
+#[cfg(feature = "std")]
+impl<S: std::io::Seek + ?Sized> Seek for S {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64, Error> {
+        std::io::Seek::seek(self, pos)
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl Read for &[u8] {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        let len = buf.len().min(self.len());
+        let (read, rest) = self.split_at(len);
+        buf[..len].copy_from_slice(read);
+        *self = rest;
+        Ok(len)
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl Write for &mut [u8] {
+    fn write_all(&mut self, buf: &[u8]) -> Result<(), Error> {
+        if buf.len() > self.len() {
+            return Err(ErrorKind::WriteZero.into());
+        }
+        let (written, rest) = core::mem::take(self).split_at_mut(buf.len());
+        written.copy_from_slice(buf);
+        *self = rest;
+        Ok(())
+    }
+}
+
+/// Implements the traits of [`std::io`] for a type only implementing the ones of this module, to
+/// pass it to code built on the standard library, such as decompressors.
+#[cfg(feature = "std")]
+pub(crate) struct Std<'a, T: ?Sized>(pub(crate) &'a mut T);
+
+#[cfg(feature = "std")]
+impl<R: Read + ?Sized> std::io::Read for Std<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+#[cfg(feature = "std")]
+impl<W: Write + ?Sized> std::io::Write for Std<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.flush()
+    }
+}
+
+#[cfg(feature = "std")]
+impl<S: Seek + ?Sized> std::io::Seek for Std<'_, S> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.0.seek(pos)
+    }
+}
@@ -1,15 +1,61 @@
 //! A rust port of [ddelta], which is a streaming and more efficient version of [bsdiff]. The
 //! output created by this program is sometimes (when using [`generate`]) compatible with the
-//! original C tool, [ddelta], but not with [bsdiff]. This library may use up to 5 times the old
+//! original C tool, [ddelta]. With the `bzip2` feature, patches created by [bsdiff] can be applied,
+//! and `generate_bsdiff` creates patches compatible with it. This library may use up to 5 times the old
 //! file size + the new file size, (5 × min(o, 2^31-1) + min(n, 2^31-1)), up to 12GiB. To control
-//! this, see the `chunk_sizes` parameter of [`generate_chunked`].
+//! this, see the `chunk_sizes` parameter of [`generate_chunked`], or set a memory limit with
+//! [`GenerateOptions::max_memory`], which picks the chunk size for you. If data was inserted into or
+//! removed from a large file, prefer [`generate_chunked_windowed`], which doesn't require the
+//! chunks of the old and new file to be aligned. Alternatively, sort the old file with [`SaIs`] via
+//! [`GenerateOptions::suffix_sort`], which lifts the limit of 2^31-1 bytes of a single patch. To
+//! use far less memory at the cost of larger patches, find matches with a [`HashChain`] via
+//! [`GenerateOptions::matcher`] instead of a suffix array.
 //!
 //! **Note**: the patches created by program should be compressed. If not compressed, the output may
-//! actually be larger than just including the new file. You might want to feed the patch file
-//! directly to an [encoder][XzEncoder], and read via a
-//! [decoder implementing a compression algorithm][XzDecoder] to not require much disk space.
-//! Additionally, no checksum is performed, so you should strongly consider doing a checksum of at
-//! least either the old or new file once written.
+//! actually be larger than just including the new file. Wrap the patch file in a
+//! [`CompressedWriter`] when generating it: [`apply`] and [`apply_chunked`] detect the compression
+//! and decompress it automatically. Alternatively, you might want to feed the patch file directly
+//! to an [encoder][XzEncoder], and read via a
+//! [decoder implementing a compression algorithm][XzDecoder].
+//!
+//! Patches created by [`generate_chunked`] embed a CRC32 checksum of the old and new data of every
+//! chunk. [`apply`] and [`apply_chunked`] verify these, and fail with [`Error::ChecksumMismatch`]
+//! instead of silently producing garbage when given the wrong old file. Patches created by
+//! [`generate`] don't contain any checksum to stay compatible with the original ddelta tool, so you
+//! should strongly consider doing a checksum of at least either the old or new file once written.
+//!
+//! To choose the block size used while applying a patch, or to provide the buffer yourself, use
+//! [`ApplyOptions`].
+//!
+//! To continue applying a patch after being interrupted, e.g. by a power loss, use
+//! [`apply_resumable`].
+//!
+//! To update a file without room for a second copy, create the patch with [`generate_in_place`]
+//! and apply it with [`apply_in_place`].
+//!
+//! To apply a patch to an old file that can't seek, such as one coming from a pipe, create the
+//! patch with [`GenerateOptions::forward_only`] and apply it with [`apply_streaming`].
+//!
+//! To reconstruct a range of the new file without applying the patch up to it, e.g. to serve
+//! range requests, use [`read_new_range`], or a [`PatchIndex`] to read multiple ranges.
+//!
+//! To pass the new file to code that reads it, instead of writing it somewhere, wrap the old file
+//! and the patch in a [`PatchedReader`], which applies the patch as the new file is read.
+//!
+//! When the new file is produced by code that writes it, such as a linker, write it to a
+//! [`DiffWriter`] instead of buffering it, which generates the patch chunk by chunk.
+//!
+//! When diffing one old file against many new files, create an [`OldIndex`] to only sort the old
+//! file once.
+//!
+//! To diff faster, or to look harder for matches, set a [`GenerateOptions::level`], like the level
+//! of a compressor.
+//!
+//! To stop generating or applying a patch from another thread, e.g. when the user aborts it, pass
+//! a [`CancelToken`] to [`GenerateOptions::cancel_token`] or [`ApplyOptions::cancel_token`].
+//!
+//! To inspect a patch without applying it, iterate over its chunks and control entries with a
+//! [`PatchReader`].
 //!
 //! ## Features
 //!
@@ -20,27 +66,160 @@
 //!
 //! ```toml
 //! [dependencies]
-//! ddelta = { version = "0.1.0", default-features = false }
+//! ddelta = { version = "0.1.0", default-features = false, features = ["diff"] }
 //! ```
 //!
+//! Without the `std` feature, which every other feature enables, the crate is `#![no_std]` and
+//! doesn't allocate. Only [`ApplyOptions::with_buffer`] is available then, which reads and writes
+//! through the minimal traits of the [`io`] module, to apply uncompressed patches on targets
+//! such as microcontrollers. The command line tool requires the `cli` feature.
+//!
+//! The compression algorithms available to [`CompressedWriter`] are each enabled by a feature:
+//! `zstd` (enabled by default), `xz` and `bzip2`. The latter is also required for bsdiff support.
+//!
+//! The `mmap` feature adds `generate_mmap`, which maps the old and new file into memory instead of
+//! copying them into buffers, so files larger than the available memory can be diffed. The
+//! `parallel` feature adds `generate_parallel` and `generate_chunked_parallel`, which use multiple
+//! threads to generate a patch. The `async` feature adds `apply_async`, `apply_chunked_async` and
+//! `generate_chunked_async`, which work with the asynchronous I/O traits of the futures crate.
+//!
 //! [ddelta]: https://github.com/julian-klode/ddelta
 //! [bsdiff]: http://www.daemonology.net/bsdiff/
 //! [XzEncoder]: https://docs.rs/xz2/*/xz2/write/struct.XzEncoder.html
 //! [XzDecoder]: https://docs.rs/xz2/*/xz2/read/struct.XzDecoder.html
 
+#![cfg_attr(not(feature = "std"), no_std)]
+
 use byteorder::BigEndian;
-use zerocopy::{AsBytes, FromBytes, Unaligned, I64, U64};
+use zerocopy::{AsBytes, FromBytes, Unaligned, I64, U32, U64};
 
-use anyhow::Result;
+#[cfg(all(feature = "async", feature = "diff"))]
+pub use async_io::generate_chunked_async;
+#[cfg(feature = "async")]
+pub use async_io::{apply_async, apply_chunked_async};
+#[cfg(all(feature = "diff", feature = "bzip2"))]
+pub use bsdiff::generate_bsdiff;
+#[cfg(feature = "std")]
+pub use cancel::CancelToken;
+#[cfg(feature = "std")]
+pub use compression::CompressedWriter;
+pub use compression::Compression;
+#[cfg(feature = "diff")]
+pub use diff::{
+    generate, generate_chunked, generate_chunked_split, generate_chunked_windowed, generate_split,
+    GenerateOptions, Layout,
+};
+pub use error::{Error, Result, Stream};
+#[cfg(feature = "std")]
+pub use in_place::apply_in_place;
+#[cfg(feature = "diff")]
+pub use in_place::generate_in_place;
+#[cfg(feature = "diff")]
+pub use index::OldIndex;
 #[cfg(feature = "diff")]
-pub use diff::{generate, generate_chunked};
-pub use patch::{apply, apply_chunked};
+pub use matcher::{HashChain, HashChainIndex, Matcher};
+#[cfg(feature = "mmap")]
+pub use mmap::generate_mmap;
+#[cfg(feature = "parallel")]
+pub use parallel::{generate_chunked_parallel, generate_parallel};
+pub use parser::{Checksums, ChunkInfo, EntryInfo, Format};
+pub use patch::ApplyOptions;
+#[cfg(feature = "std")]
+pub use patch::{apply, apply_chunked, apply_streaming};
+#[cfg(feature = "std")]
+pub use patched::PatchedReader;
+#[cfg(feature = "std")]
+pub use range::{read_new_range, PatchIndex};
+#[cfg(feature = "std")]
+pub use reader::{PatchReader, Record};
+#[cfg(feature = "std")]
+pub use resume::{apply_resumable, Checkpoint};
+#[cfg(feature = "diff")]
+pub use suffix::{DivSufSort, SaIs, SuffixIndex, SuffixSort};
+#[cfg(feature = "diff")]
+pub use writer::DiffWriter;
 
 const DDELTA_MAGIC: &[u8; 8] = b"DDELTA40";
+/// Same as [`DDELTA_MAGIC`], but the [`PatchHeader`] is followed by a [`ChecksumHeader`].
+const DDELTA_CHECKSUM_MAGIC: &[u8; 8] = b"DDELTA41";
+/// Same as [`DDELTA_CHECKSUM_MAGIC`], but the [`ChecksumHeader`] is followed by a [`SplitHeader`],
+/// and the control entries, extra and diff bytes are stored in separate sections.
+const DDELTA_SPLIT_MAGIC: &[u8; 8] = b"DDELTA42";
+/// Starts a [`WindowHeader`], which precedes a chunk of a chunked patch.
+const DDELTA_WINDOW_MAGIC: &[u8; 8] = b"DDELTAW1";
+/// Ends a chunked patch whose chunks contain checksums. It is written as a [`PatchHeader`] whose
+/// `new_file_size` is the size of the whole new file.
+const DDELTA_END_MAGIC: &[u8; 8] = b"DDELTAE1";
+/// The magic number of patches created by the original bsdiff tool.
+const BSDIFF_MAGIC: &[u8; 8] = b"BSDIFF40";
+/// Starts an [`IndexHeader`], which is followed by the suffix array of an [`OldIndex`] with 32-bit
+/// positions.
+#[cfg(feature = "diff")]
+const DDELTA_INDEX_MAGIC: &[u8; 8] = b"DDELTAI1";
+/// Same as [`DDELTA_INDEX_MAGIC`], but the positions are 64-bit.
+#[cfg(feature = "diff")]
+const DDELTA_INDEX64_MAGIC: &[u8; 8] = b"DDELTAI2";
+/// Starts a [`CheckpointHeader`].
+#[cfg(feature = "std")]
+const DDELTA_CHECKPOINT_MAGIC: &[u8; 8] = b"DDELTAJ1";
+/// Starts a [`CompressionHeader`], which is followed by a compressed patch.
+const DDELTA_COMPRESSED_MAGIC: &[u8; 8] = b"DDELTAC1";
 
+/// Reads a header of type `$type` from the patch `$reader`.
+#[cfg(feature = "std")]
+macro_rules! read {
+    ($reader: expr, $type: ty) => {{
+        let mut buf = [0; core::mem::size_of::<$type>()];
+        let data: crate::Result<$type> =
+            crate::error::Context::context($reader.read_exact(&mut buf), crate::Stream::Patch).map(
+                |_| {
+                    *zerocopy::LayoutVerified::<_, $type>::new_unaligned(&buf[..])
+                        .expect("buffer has the size of the type")
+                },
+            );
+        data
+    }};
+}
+
+#[cfg(feature = "async")]
+mod async_io;
+#[cfg(feature = "bzip2")]
+mod bsdiff;
+#[cfg(feature = "std")]
+mod cancel;
+mod compression;
 #[cfg(feature = "diff")]
 mod diff;
+mod error;
+#[cfg(feature = "std")]
+mod in_place;
+#[cfg(feature = "diff")]
+mod index;
+pub mod io;
+#[cfg(feature = "diff")]
+mod matcher;
+#[cfg(feature = "mmap")]
+mod mmap;
+#[cfg(feature = "parallel")]
+mod parallel;
+mod parser;
 mod patch;
+#[cfg(feature = "std")]
+mod patched;
+#[cfg(feature = "std")]
+mod range;
+#[cfg(feature = "std")]
+mod reader;
+#[cfg(feature = "std")]
+mod resume;
+#[cfg(feature = "diff")]
+mod suffix;
+#[cfg(all(test, feature = "diff"))]
+mod test_data;
+#[cfg(feature = "diff")]
+mod window;
+#[cfg(feature = "diff")]
+mod writer;
 
 /// The current state of the generator.
 ///
@@ -51,12 +230,18 @@ pub enum State {
     /// The new or old file is currently being read. This is currently only used in
     /// [`generate_chunked`].
     Reading,
-    /// The internal algorithm, divsufsort, is currently being run.
+    /// The old file is currently being indexed by the [`Matcher`], which sorts its suffixes with
+    /// divsufsort unless another one was chosen.
     Sorting,
     /// The generator is currently working its way through the data. The number represents how much
     /// of the new file has been worked through. In other words, if calculating a percentage, divide
     /// this number by the size of the new file.
     Working(u64),
+    /// The patch has been written. The number is the peak amount of memory in bytes the generator
+    /// used, as counted by [`GenerateOptions::max_memory`]. This is only reported by [`generate`],
+    /// [`generate_split`], [`generate_chunked`], [`generate_chunked_split`],
+    /// [`generate_chunked_windowed`] and the methods of [`GenerateOptions`].
+    Finished(u64),
 }
 
 #[derive(Debug, Copy, Clone, FromBytes, AsBytes, Unaligned)]
@@ -66,6 +251,75 @@ struct PatchHeader {
     new_file_size: U64<BigEndian>,
 }
 
+#[derive(Debug, Copy, Clone, FromBytes, AsBytes, Unaligned)]
+#[repr(C)]
+struct CompressionHeader {
+    magic: [u8; 8],
+    compression: u8,
+}
+
+#[derive(Debug, Copy, Clone, FromBytes, AsBytes, Unaligned)]
+#[repr(C)]
+struct ChecksumHeader {
+    /// The number of bytes of the old file covered by `old_checksum`, starting at the position of
+    /// the old file when this patch (or chunk) starts being applied.
+    old_file_size: U64<BigEndian>,
+    /// CRC32 of the old data.
+    old_checksum: U32<BigEndian>,
+    /// CRC32 of the new data.
+    new_checksum: U32<BigEndian>,
+}
+
+/// Precedes a chunk of a patch created by [`generate_chunked_windowed`], which is applied to the old
+/// file starting at `old_offset` instead of the offset the chunk has in the new file.
+#[derive(Debug, Copy, Clone, FromBytes, AsBytes, Unaligned)]
+#[repr(C)]
+struct WindowHeader {
+    magic: [u8; 8],
+    old_offset: U64<BigEndian>,
+}
+
+/// Describes the sections following it. Each section is compressed on its own, and its length is
+/// the number of (compressed) bytes it takes up in the patch.
+#[derive(Debug, Copy, Clone, FromBytes, AsBytes, Unaligned)]
+#[repr(C)]
+#[cfg(feature = "std")]
+struct SplitHeader {
+    compression: u8,
+    /// The [`EntryHeader`]s of the patch, without a terminating entry.
+    control_len: U64<BigEndian>,
+    /// The extra bytes of all entries.
+    extra_len: U64<BigEndian>,
+    /// The diff bytes of all entries. This comes last, so that it can be streamed while applying.
+    diff_len: U64<BigEndian>,
+}
+
+/// Precedes the suffix array of a serialized [`OldIndex`], which consists of one big-endian number
+/// per byte of the old file, as wide as the positions of its [`SuffixSort`].
+#[derive(Debug, Copy, Clone, FromBytes, AsBytes, Unaligned)]
+#[repr(C)]
+#[cfg(feature = "diff")]
+struct IndexHeader {
+    magic: [u8; 8],
+    old_file_size: U64<BigEndian>,
+    /// CRC32 of the old file, to detect using the index with a different file.
+    old_checksum: U32<BigEndian>,
+}
+
+/// A serialized [`Checkpoint`].
+#[derive(Debug, Copy, Clone, FromBytes, AsBytes, Unaligned)]
+#[repr(C)]
+#[cfg(feature = "std")]
+struct CheckpointHeader {
+    magic: [u8; 8],
+    patch_offset: U64<BigEndian>,
+    old_offset: U64<BigEndian>,
+    new_offset: U64<BigEndian>,
+    chunk: U64<BigEndian>,
+    /// CRC32 of all previous fields, to detect a checkpoint that was only partially written.
+    checksum: U32<BigEndian>,
+}
+
 #[derive(Debug, Copy, Clone, FromBytes, AsBytes, Unaligned)]
 #[repr(C)]
 struct EntryHeader {
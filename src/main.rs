@@ -1,17 +1,20 @@
 use std::fs::File;
-use std::io::BufWriter;
+use std::io::{BufReader, BufWriter};
 
 use std::path::PathBuf;
 
 use argh::FromArgs;
-use ddelta::{apply_chunked, generate_chunked, State};
+use ddelta::{
+    apply_chunked, CompressedWriter, Compression, Error, GenerateOptions, Layout, PatchReader,
+    Record, State,
+};
 
 use indicatif::{ProgressBar, ProgressStyle};
 
 fn parse_size(mut text: &str) -> Result<usize, String> {
     text = text.trim();
     let (num, suffix) = text.split_at(
-        text.find(|c: char| !c.is_digit(10) && c != '.')
+        text.find(|c: char| !c.is_ascii_digit() && c != '.')
             .unwrap_or(text.len()),
     );
     if num.trim().is_empty() && suffix.trim().is_empty() {
@@ -35,6 +38,7 @@ fn parse_size(mut text: &str) -> Result<usize, String> {
 enum SubCommand {
     Diff(DiffCmd),
     Patch(PatchCmd),
+    Inspect(InspectCmd),
 }
 
 #[derive(FromArgs, PartialEq, Debug)]
@@ -60,6 +64,22 @@ struct DiffCmd {
     /// an optional RAM limit. Defaults to no limit
     #[argh(option, short = 'r', from_str_fn(parse_size), default = "0")]
     ram_limit: usize,
+    /// the compression algorithm to use for the patch: none, xz, zstd or bzip2. Defaults to zstd
+    /// if supported
+    #[argh(option, short = 'c', default = "default_compression()")]
+    compression: Compression,
+    /// store control data, diff and extra bytes in separately compressed sections, which often
+    /// creates smaller patches
+    #[argh(switch, short = 's')]
+    split: bool,
+}
+
+fn default_compression() -> Compression {
+    if Compression::Zstd.is_supported() {
+        Compression::Zstd
+    } else {
+        Compression::None
+    }
 }
 
 #[derive(FromArgs, PartialEq, Debug)]
@@ -77,49 +97,160 @@ struct PatchCmd {
     patch: PathBuf,
 }
 
+#[derive(FromArgs, PartialEq, Debug)]
+/// Print statistics about a patchfile, without needing the old file.
+#[argh(subcommand, name = "inspect")]
+struct InspectCmd {
+    /// the patch file
+    #[argh(positional)]
+    patch: PathBuf,
+}
+
+/// The totals of the control entries of a chunk, or of the whole patch.
+#[derive(Default)]
+struct Totals {
+    entries: u64,
+    diff: u64,
+    extra: u64,
+    max_seek: u64,
+}
+
+impl Totals {
+    fn add(&mut self, other: &Totals) {
+        self.entries += other.entries;
+        self.diff += other.diff;
+        self.extra += other.extra;
+        self.max_seek = self.max_seek.max(other.max_seek);
+    }
+
+    /// The share of the new file that was found in the old file.
+    fn reuse_ratio(&self) -> f64 {
+        match self.diff + self.extra {
+            0 => 0.,
+            total => self.diff as f64 / total as f64 * 100.,
+        }
+    }
+}
+
+fn inspect(path: PathBuf) {
+    let patch_len = std::fs::metadata(&path).unwrap().len();
+    let mut patch = BufReader::new(File::open(path).unwrap());
+    let mut chunks = 0;
+    let mut new_len = 0;
+    let mut total = Totals::default();
+    let mut chunk = Totals::default();
+    let print_chunk = |chunk: &Totals| {
+        println!(
+            "  {} entries, {} diff bytes, {} extra bytes, largest seek {}, {:.1}% reused",
+            chunk.entries,
+            chunk.diff,
+            chunk.extra,
+            chunk.max_seek,
+            chunk.reuse_ratio()
+        )
+    };
+    for record in PatchReader::new(&mut patch).unwrap() {
+        match record.unwrap() {
+            Record::Chunk(info) => {
+                if chunks > 0 {
+                    print_chunk(&chunk);
+                }
+                total.add(&chunk);
+                chunk = Totals::default();
+                chunks += 1;
+                new_len += info.new_size;
+                print!(
+                    "Chunk {} at {}: {:?}, {} bytes",
+                    chunks, info.offset, info.format, info.new_size
+                );
+                match info.old_offset {
+                    Some(offset) => println!(", old file window at {}", offset),
+                    None => println!(),
+                }
+            }
+            Record::Entry(entry) => {
+                chunk.entries += 1;
+                chunk.diff += entry.diff;
+                chunk.extra += entry.extra;
+                chunk.max_seek = chunk.max_seek.max(entry.seek.unsigned_abs());
+            }
+        }
+    }
+    if chunks > 0 {
+        print_chunk(&chunk);
+    }
+    total.add(&chunk);
+    println!();
+    println!("Patch size:      {} bytes", patch_len);
+    println!("New file size:   {} bytes", new_len);
+    println!("Chunks:          {}", chunks);
+    println!("Control entries: {}", total.entries);
+    println!("Diff bytes:      {}", total.diff);
+    println!("Extra bytes:     {}", total.extra);
+    println!("Largest seek:    {}", total.max_seek);
+    println!("Reuse ratio:     {:.1}%", total.reuse_ratio());
+}
+
 fn main() {
     let cmd: Arguments = argh::from_env();
     match cmd.nested {
         SubCommand::Diff(diff) => {
             let mut old = File::open(diff.old).unwrap();
             let mut new = File::open(diff.new).unwrap();
-            let patch = File::create(diff.patch).unwrap();
-            let chunk_sizes = match diff.ram_limit / 6 {
-                0..=2 => None,
-                0..=1024 => {
-                    eprintln!(
-                        "Warning: changing default RAM limit to {} as {} is too small",
-                        1024 * 6,
-                        diff.ram_limit
-                    );
-                    Some(1024)
-                }
-                other => Some(other),
-            };
+            let patch = BufWriter::new(File::create(diff.patch).unwrap());
+            let options = GenerateOptions::new().max_memory(match diff.ram_limit {
+                0 => None,
+                limit => Some(limit as u64),
+            });
             let len = new.metadata().unwrap().len();
             let pb = ProgressBar::new(len);
             pb.set_style(ProgressStyle::default_bar().template("{spinner:.green} {msg}[{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({bytes_per_sec}, {eta})"));
 
             pb.set_message("Reading… ");
-            generate_chunked(
-                &mut old,
-                &mut new,
-                &mut BufWriter::new(patch),
-                chunk_sizes,
-                |v| match v {
-                    State::Reading => {
-                        pb.set_message("Reading… ");
-                    }
-                    State::Sorting => {
-                        pb.set_message("Sorting… ");
-                    }
-                    State::Working(b) => {
-                        pb.set_message("");
-                        pb.set_position(b);
-                    }
-                },
-            )
-            .unwrap();
+            let progress = |v| match v {
+                State::Reading => {
+                    pb.set_message("Reading… ");
+                }
+                State::Sorting => {
+                    pb.set_message("Sorting… ");
+                }
+                State::Working(b) => {
+                    pb.set_message("");
+                    pb.set_position(b);
+                }
+                State::Finished(_) => {}
+            };
+            let result = if diff.split {
+                options.generate_chunked_windowed(
+                    &mut old,
+                    &mut new,
+                    &mut { patch },
+                    None,
+                    Layout::Split(diff.compression),
+                    progress,
+                )
+            } else {
+                let mut patch = CompressedWriter::new(patch, diff.compression).unwrap();
+                options
+                    .generate_chunked_windowed(
+                        &mut old,
+                        &mut new,
+                        &mut patch,
+                        None,
+                        Layout::Interleaved,
+                        progress,
+                    )
+                    .and_then(|()| patch.finish().map(drop))
+            };
+            if let Err(Error::MemoryLimit) = result {
+                pb.abandon();
+                eprintln!(
+                    "Error: the RAM limit of {} bytes is too low to diff these files",
+                    diff.ram_limit
+                );
+                std::process::exit(1);
+            }
+            result.unwrap();
             pb.set_message("");
             pb.finish();
         }
@@ -129,5 +260,6 @@ fn main() {
             let mut patch = File::open(patch.patch).unwrap();
             apply_chunked(&mut old, &mut new, &mut patch).unwrap();
         }
+        SubCommand::Inspect(inspect_cmd) => inspect(inspect_cmd.patch),
     }
 }
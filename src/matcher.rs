@@ -0,0 +1,235 @@
+//! Strategies for finding the data of the new file in the old file.
+
+use std::mem::size_of;
+
+use crate::diff::{match_len, search, sort};
+use crate::suffix::{SuffixIndex, SuffixSort};
+
+/// A strategy for finding the data of the new file in the old file, to be used by
+/// [`GenerateOptions::matcher`][crate::GenerateOptions::matcher].
+///
+/// Before diffing, an index of the old file is built. The generator then asks for a match at every
+/// position of the new file it hasn't covered by a previous match yet, and extends and combines
+/// the matches into the entries of the patch, so a matcher that finds longer matches results in a
+/// smaller patch. Every [`SuffixSort`] is a matcher that finds the longest match with a suffix
+/// array.
+pub trait Matcher {
+    /// What is built from the old file to find matches in it.
+    type Index;
+
+    /// The size of the largest old file this matcher can index. The new file is not indexed, so its
+    /// size is not limited.
+    fn max_len(&self) -> u64;
+
+    /// The peak amount of memory in bytes used to build the index of an old file of `old_len`
+    /// bytes and to find matches with it, which [`GenerateOptions::max_memory`] relies on.
+    ///
+    /// [`GenerateOptions::max_memory`]: crate::GenerateOptions::max_memory
+    fn memory(&self, old_len: usize) -> u64;
+
+    /// Builds the index of `old`.
+    fn index(&self, old: &[u8]) -> Self::Index;
+
+    /// Returns the position in `old` and the length of a match of the start of `new`, preferably
+    /// the longest one. A length of 0 means nothing was found.
+    fn find(&self, index: &Self::Index, old: &[u8], new: &[u8]) -> (usize, usize);
+}
+
+impl<S: SuffixSort> Matcher for S {
+    type Index = Vec<S::Index>;
+
+    fn max_len(&self) -> u64 {
+        S::Index::MAX_LEN
+    }
+
+    fn memory(&self, old_len: usize) -> u64 {
+        SuffixSort::memory(self, old_len)
+    }
+
+    fn index(&self, old: &[u8]) -> Vec<S::Index> {
+        sort(self, old)
+    }
+
+    fn find(&self, index: &Vec<S::Index>, old: &[u8], new: &[u8]) -> (usize, usize) {
+        let mut pos = 0;
+        let len = search(
+            index,
+            &old[..old.len().wrapping_sub(1).min(old.len())],
+            new,
+            0,
+            old.len(),
+            &mut pos,
+        );
+        (pos as usize, len as usize)
+    }
+}
+
+/// Finds matches by hashing blocks of the old file, like rsync. Only data starting at a multiple of
+/// the block size in the old file is found, and only if at least one whole block matches, so
+/// patches are larger than the ones created with a suffix array. In return, the index is built
+/// quickly and takes up less than 1 byte per 2 bytes of the old file with the default block size
+/// of 32 bytes.
+///
+/// ```no_run
+/// # fn main() -> ddelta::Result<()> {
+/// use ddelta::{GenerateOptions, HashChain};
+///
+/// let old = std::fs::read("old").unwrap();
+/// let new = std::fs::read("new").unwrap();
+/// let mut patch = std::fs::File::create("patch").unwrap();
+/// GenerateOptions::new()
+///     .matcher(HashChain::new(64))
+///     .generate(&old, &new, &mut patch, |_| {})?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Eq, PartialEq, Copy, Clone, Hash, Debug)]
+pub struct HashChain {
+    block_size: usize,
+    max_candidates: usize,
+}
+
+/// The index built by a [`HashChain`]: a hash table of the blocks of the old file, where the
+/// blocks with the same hash are chained together.
+#[derive(Debug)]
+pub struct HashChainIndex {
+    /// The first block in each bucket of the hash table.
+    heads: Vec<u32>,
+    /// The next block in the same bucket as each block.
+    next: Vec<u32>,
+    /// The hash is shifted right by this much to get the bucket.
+    shift: u32,
+}
+
+/// Marks the end of a chain.
+const END: u32 = u32::MAX;
+
+impl HashChain {
+    /// Creates a matcher hashing blocks of `block_size` bytes. Smaller blocks find more matches,
+    /// but take up more memory. This panics if `block_size` is 0.
+    pub fn new(block_size: usize) -> Self {
+        assert!(block_size > 0, "the block size must not be 0");
+        HashChain {
+            block_size,
+            max_candidates: 64,
+        }
+    }
+
+    /// How many blocks with the same hash are compared to the new data at most, to bound the time
+    /// spent on data that repeats often. Defaults to 64.
+    pub fn max_candidates(mut self, max_candidates: usize) -> Self {
+        self.max_candidates = max_candidates;
+        self
+    }
+
+    fn hash(&self, block: &[u8], shift: u32) -> usize {
+        // FNV-1a
+        let hash = block.iter().fold(0xcbf2_9ce4_8422_2325u64, |hash, &b| {
+            (hash ^ b as u64).wrapping_mul(0x0100_0000_01b3)
+        });
+        (hash >> shift) as usize
+    }
+}
+
+impl Default for HashChain {
+    fn default() -> Self {
+        HashChain::new(32)
+    }
+}
+
+impl Matcher for HashChain {
+    type Index = HashChainIndex;
+
+    fn max_len(&self) -> u64 {
+        // Block indices must stay below the END sentinel
+        (END as u64 * self.block_size as u64 - 1).min(isize::MAX as u64)
+    }
+
+    fn memory(&self, old_len: usize) -> u64 {
+        let blocks = old_len / self.block_size;
+        (buckets(blocks) + blocks) as u64 * size_of::<u32>() as u64
+    }
+
+    fn index(&self, old: &[u8]) -> HashChainIndex {
+        let blocks = old.len() / self.block_size;
+        let bits = buckets(blocks).trailing_zeros();
+        let shift = 64 - bits;
+        let mut heads = vec![END; 1 << bits];
+        let mut next = vec![END; blocks];
+        // Insert the blocks from the end, so that chains start with the earliest block
+        for (i, block) in old.chunks_exact(self.block_size).enumerate().rev() {
+            let bucket = self.hash(block, shift);
+            next[i] = heads[bucket];
+            heads[bucket] = i as u32;
+        }
+        HashChainIndex { heads, next, shift }
+    }
+
+    fn find(&self, index: &HashChainIndex, old: &[u8], new: &[u8]) -> (usize, usize) {
+        if new.len() < self.block_size || index.next.is_empty() {
+            return (0, 0);
+        }
+        let mut best = (0, 0);
+        let mut block = index.heads[self.hash(&new[..self.block_size], index.shift)];
+        for _ in 0..self.max_candidates {
+            if block == END {
+                break;
+            }
+            let pos = block as usize * self.block_size;
+            let len = match_len(&old[pos..], new);
+            if len > best.1 {
+                best = (pos, len);
+            }
+            block = index.next[block as usize];
+        }
+        best
+    }
+}
+
+/// The size of the hash table for the given amount of blocks.
+fn buckets(blocks: usize) -> usize {
+    blocks.next_power_of_two().max(2)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{HashChain, Matcher};
+    use crate::test_data::random_bytes;
+    use crate::{apply, GenerateOptions, PatchReader, Record};
+
+    #[test]
+    fn hash_chain() {
+        let old = random_bytes(20_000);
+        let mut new = old[5000..15_000].to_vec();
+        new.extend_from_slice(b"some data that isn't in the old file");
+        new.extend_from_slice(&old[..5000]);
+
+        let matcher = HashChain::new(16);
+        let index = matcher.index(&old);
+        assert_eq!(matcher.find(&index, &old, &old[4000..]).0, 4000);
+        assert_eq!(matcher.find(&index, &old, b"too short"), (0, 0));
+
+        let mut patch = Vec::new();
+        GenerateOptions::new()
+            .matcher(matcher)
+            .generate(&old, &new, &mut patch, |_| {})
+            .unwrap();
+        let mut patched = Vec::new();
+        apply(
+            &mut std::io::Cursor::new(&old),
+            &mut patched,
+            &mut &patch[..],
+        )
+        .unwrap();
+        assert_eq!(patched, new);
+
+        // Most of the new file is found in the old one, rather than stored as extra bytes
+        let mut extra = 0;
+        for record in PatchReader::new(&mut &patch[..]).unwrap() {
+            if let Record::Entry(entry) = record.unwrap() {
+                extra += entry.extra;
+            }
+        }
+        assert!(extra < 200, "{} extra bytes", extra);
+    }
+}
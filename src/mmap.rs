@@ -0,0 +1,162 @@
+use std::fs::File;
+use std::io::Write;
+
+use memmap2::Mmap;
+
+use crate::diff::{generate_chunk, write_end};
+use crate::error::Context;
+use crate::matcher::Matcher;
+use crate::{GenerateOptions, Layout, Result, State, Stream};
+
+/// Maps `file` into memory. Empty files are represented by an empty slice, as they can't be mapped
+/// on every platform.
+fn map(file: &File, stream: Stream) -> Result<Option<Mmap>> {
+    if file.metadata().context(stream)?.len() == 0 {
+        return Ok(None);
+    }
+    // Safety: the caller of generate_mmap guarantees that the files aren't modified
+    unsafe { Mmap::map(file) }.map(Some).context(stream)
+}
+
+/// Generate a patch like [`generate_chunked`][crate::generate_chunked] or
+/// [`generate_chunked_split`][crate::generate_chunked_split], but read the old and new file by
+/// mapping them into memory.
+///
+/// This avoids allocating and filling the two buffers of `chunk_sizes` bytes used by the other
+/// functions, so only the suffix array of the current chunk is allocated, while the operating system
+/// pages in the data as needed. The patch is the same as the one created by
+/// [`generate_chunked`][crate::generate_chunked] with the same `chunk_sizes`, and must be applied
+/// with [`apply_chunked`][crate::apply_chunked].
+///
+/// The files must not be modified, e.g. by another process, while the patch is generated. Otherwise,
+/// the patch may be invalid, or the program may crash.
+///
+/// This requires the `mmap` feature.
+pub fn generate_mmap(
+    old_f: &File,
+    new_f: &File,
+    patch_f: &mut impl Write,
+    chunk_sizes: impl Into<Option<usize>>,
+    layout: Layout,
+    progress: impl FnMut(State),
+) -> Result<()> {
+    GenerateOptions::new().generate_mmap(old_f, new_f, patch_f, chunk_sizes, layout, progress)
+}
+
+impl<M: Matcher> GenerateOptions<M> {
+    /// Generate a patch like [`generate_mmap`]. The chunks are sized like the ones of
+    /// [`generate_chunked`][GenerateOptions::generate_chunked], so the patch is the same, even
+    /// though the mapped files don't count towards the [`max_memory`][GenerateOptions::max_memory].
+    ///
+    /// This requires the `mmap` feature.
+    pub fn generate_mmap(
+        &self,
+        old_f: &File,
+        new_f: &File,
+        patch_f: &mut impl Write,
+        chunk_sizes: impl Into<Option<usize>>,
+        layout: Layout,
+        mut progress: impl FnMut(State),
+    ) -> Result<()> {
+        progress(State::Reading);
+        let old_map = map(old_f, Stream::Old)?;
+        let new_map = map(new_f, Stream::New)?;
+        let old = old_map.as_deref().unwrap_or(&[]);
+        let new = new_map.as_deref().unwrap_or(&[]);
+        let chunk_sizes = self.chunk_size(chunk_sizes.into(), layout, 0)?;
+
+        let mut peak_memory = 0;
+        if new.is_empty() {
+            peak_memory = generate_chunk(&[], &[], patch_f, layout, self, |_| {})?;
+        }
+        let mut bytes_completed = 0;
+        for new_chunk in new.chunks(chunk_sizes) {
+            let start = bytes_completed.min(old.len());
+            let old_chunk = &old[start..(start + chunk_sizes).min(old.len())];
+            self.check_cancelled()?;
+            progress(State::Sorting);
+            let memory =
+                generate_chunk(old_chunk, new_chunk, patch_f, layout, self, |d| match d {
+                    State::Working(bytes) => {
+                        progress(State::Working(bytes + bytes_completed as u64))
+                    }
+                    other => progress(other),
+                })?;
+            peak_memory = peak_memory.max(memory);
+            bytes_completed += new_chunk.len();
+        }
+        write_end(patch_f, new.len() as u64)?;
+        patch_f.flush().context(Stream::Patch)?;
+        progress(State::Finished(peak_memory));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Seek, SeekFrom, Write};
+
+    use super::generate_mmap;
+    use crate::test_data::random_bytes;
+    use crate::{generate_chunked, GenerateOptions, Layout, State};
+
+    fn temp_file(data: &[u8]) -> std::fs::File {
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(data).unwrap();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file
+    }
+
+    #[test]
+    fn same_as_chunked() {
+        let old = random_bytes(10_000);
+        let mut new = old.clone();
+        new.splice(5000..5000, b"inserted".iter().copied());
+        for &(old, new) in &[
+            (&old[..], &new[..]),
+            (&old[..], &[][..]),
+            (&[][..], &new[..]),
+        ] {
+            let mut expected = Vec::new();
+            generate_chunked(&mut &old[..], &mut &new[..], &mut expected, 3000, |_| {}).unwrap();
+            let mut patch = Vec::new();
+            generate_mmap(
+                &temp_file(old),
+                &temp_file(new),
+                &mut patch,
+                3000,
+                Layout::Interleaved,
+                |_| {},
+            )
+            .unwrap();
+            assert_eq!(patch, expected);
+        }
+
+        let mut expected = Vec::new();
+        let options = GenerateOptions::new().level(1).max_memory(560_000);
+        options
+            .generate_chunked(
+                &mut &old[..],
+                &mut &new[..],
+                &mut expected,
+                None,
+                Layout::Interleaved,
+                |_| {},
+            )
+            .unwrap();
+        let mut patch = Vec::new();
+        let mut finished = false;
+        options
+            .generate_mmap(
+                &temp_file(&old),
+                &temp_file(&new),
+                &mut patch,
+                None,
+                Layout::Interleaved,
+                |state| finished |= matches!(state, State::Finished(_)),
+            )
+            .unwrap();
+        assert_eq!(patch, expected);
+        assert!(finished);
+    }
+}
@@ -0,0 +1,386 @@
+use std::io::{Read, Write};
+use std::mem::size_of;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use rayon::prelude::*;
+use zerocopy::I64;
+
+use crate::diff::{
+    generate_chunk, output_memory, read_up_to, scan_entries, write_end, write_ending, write_header,
+    EntrySink, ForwardOnly, Interleaved,
+};
+use crate::error::Context;
+use crate::matcher::Matcher;
+use crate::{EntryHeader, GenerateOptions, Layout, Result, State, Stream};
+
+/// The smallest part of the new file scanned by a single thread in [`generate_parallel`].
+const MIN_SEGMENT_LEN: usize = 1024 * 1024;
+
+/// Collects the progress of multiple threads, and passes the sum to a single callback.
+struct Progress<F> {
+    progress: Mutex<F>,
+    bytes_completed: AtomicU64,
+}
+
+impl<F: FnMut(State) + Send> Progress<F> {
+    fn new(progress: F) -> Self {
+        Progress {
+            progress: Mutex::new(progress),
+            bytes_completed: AtomicU64::new(0),
+        }
+    }
+
+    fn report(&self, state: State) {
+        (self.progress.lock().unwrap())(state);
+    }
+
+    /// Returns a callback for one thread, which adds the progress it reports to the total.
+    fn worker(&self) -> impl FnMut(State) + '_ {
+        let mut last = 0;
+        move |state| {
+            if let State::Working(bytes) = state {
+                let added = bytes - last;
+                last = bytes;
+                let total = self.bytes_completed.fetch_add(added, Ordering::Relaxed) + added;
+                self.report(State::Working(total));
+            }
+        }
+    }
+}
+
+/// Records the entries of a segment of the new file, which are written once all segments before it
+/// have been written.
+struct Entries(Vec<EntryHeader>);
+
+impl EntrySink for Entries {
+    fn write_entry(
+        &mut self,
+        entry: &EntryHeader,
+        _old: &[u8],
+        _new: &[u8],
+        _extra: &[u8],
+    ) -> Result<()> {
+        self.0.push(*entry);
+        Ok(())
+    }
+}
+
+/// Generate a patch like [`generate`][crate::generate], but scan the new file with multiple
+/// threads. This has a limit of 2^31-1 bytes.
+///
+/// The old file is sorted once, and the new file is split into one segment per thread of the
+/// [rayon] thread pool, each of which is diffed against the whole old file. The entries are then
+/// written in order, so the patch can be applied like the output of `generate`. As matches can't
+/// span multiple segments, the patch may be slightly larger. New files smaller than a few megabytes
+/// are scanned by a single thread.
+///
+/// This requires the `parallel` feature.
+pub fn generate_parallel(
+    old: &[u8],
+    new: &[u8],
+    patch: &mut impl Write,
+    progress: impl FnMut(State) + Send,
+) -> Result<()> {
+    GenerateOptions::new().generate_parallel(old, new, patch, progress)
+}
+
+/// Generate a patch like [`generate_chunked`][crate::generate_chunked] or
+/// [`generate_chunked_split`][crate::generate_chunked_split], but diff multiple chunks at once.
+///
+/// One chunk is read for every thread of the [rayon] thread pool, and all of them are sorted and
+/// diffed concurrently before the next chunks are read. The output is the same as the one of
+/// `generate_chunked` with the same `chunk_sizes`. As multiple chunks are held in memory at once,
+/// the memory usage described in `generate_chunked` is multiplied by the number of threads.
+///
+/// This requires the `parallel` feature.
+pub fn generate_chunked_parallel(
+    old_f: &mut impl Read,
+    new_f: &mut impl Read,
+    patch_f: &mut impl Write,
+    chunk_sizes: impl Into<Option<usize>>,
+    layout: Layout,
+    progress: impl FnMut(State) + Send,
+) -> Result<()> {
+    GenerateOptions::new().generate_chunked_parallel(
+        old_f,
+        new_f,
+        patch_f,
+        chunk_sizes,
+        layout,
+        progress,
+    )
+}
+
+/// The buffers of one thread of [`GenerateOptions::generate_chunked_parallel`], which are reused
+/// for every chunk it diffs.
+#[derive(Default)]
+struct Slot {
+    old: Vec<u8>,
+    new: Vec<u8>,
+    old_len: usize,
+    new_len: usize,
+    patch: Vec<u8>,
+}
+
+impl Slot {
+    /// The memory allocated for the buffers.
+    fn memory(&self) -> u64 {
+        (self.old.capacity() + self.new.capacity() + self.patch.capacity()) as u64
+    }
+}
+
+impl<M: Matcher + Sync> GenerateOptions<M>
+where
+    M::Index: Sync,
+{
+    /// Generate a patch like [`generate_parallel`]. The limit of the file size depends on the
+    /// matcher, and the entries buffered for each segment count towards the
+    /// [`max_memory`][GenerateOptions::max_memory] reported as [`State::Finished`].
+    ///
+    /// This requires the `parallel` feature.
+    pub fn generate_parallel(
+        &self,
+        old: &[u8],
+        new: &[u8],
+        patch: &mut impl Write,
+        progress: impl FnMut(State) + Send,
+    ) -> Result<()> {
+        let index_memory = self.check_old(old.len())?;
+        let progress = Progress::new(progress);
+        progress.report(State::Sorting);
+        let index = self.index(old);
+        let threads = rayon::current_num_threads()
+            .min(new.len() / MIN_SEGMENT_LEN)
+            .max(1);
+        let segment_len = new.len().div_ceil(threads);
+        let segments = new
+            .par_chunks(segment_len.max(1))
+            .map(|segment| {
+                let mut entries = Entries(Vec::new());
+                // Seeking backwards to the start of each segment is only avoided once they are
+                // joined
+                scan_entries(old, &index, segment, &mut entries, self, progress.worker())?;
+                Ok(entries.0)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let entries_memory = segments
+            .iter()
+            .map(|entries| (entries.capacity() * size_of::<EntryHeader>()) as u64)
+            .sum::<u64>();
+
+        write_header(patch, new.len() as u64)?;
+        let mut interleaved = Interleaved(&mut *patch);
+        let mut sink = ForwardOnly::new(&mut interleaved, self);
+        let (mut old_pos, mut new_pos) = (0i64, 0);
+        for entries in segments {
+            let last = entries.len().saturating_sub(1);
+            for (i, mut entry) in entries.into_iter().enumerate() {
+                let diff = entry.diff.get() as usize;
+                let extra = entry.extra.get() as usize;
+                let diff_old = &old[old_pos as usize..old_pos as usize + diff];
+                let diff_new = &new[new_pos..new_pos + diff];
+                let extra_new = &new[new_pos + diff..new_pos + diff + extra];
+                old_pos += (diff as i64) + entry.seek.get();
+                new_pos += diff + extra;
+                if i == last {
+                    // Every segment was diffed starting at the beginning of the old file
+                    entry.seek = I64::new(entry.seek.get() - old_pos);
+                    old_pos = 0;
+                    if diff == 0 && extra == 0 && entry.seek.get() == 0 {
+                        // This would be mistaken for the end of the patch
+                        continue;
+                    }
+                }
+                sink.write_entry(&entry, diff_old, diff_new, extra_new)?;
+            }
+        }
+        write_ending(patch)?;
+        patch.flush().context(Stream::Patch)?;
+        progress.report(State::Finished(index_memory + entries_memory));
+        Ok(())
+    }
+
+    /// Generate a patch like [`generate_chunked_parallel`]. The chunks are sized so that diffing
+    /// one on every thread fits into the [`max_memory`][GenerateOptions::max_memory], which makes
+    /// them smaller than the ones of [`generate_chunked`][GenerateOptions::generate_chunked], so
+    /// the patch is only the same if no memory limit is set.
+    ///
+    /// This requires the `parallel` feature.
+    pub fn generate_chunked_parallel(
+        &self,
+        old_f: &mut impl Read,
+        new_f: &mut impl Read,
+        patch_f: &mut impl Write,
+        chunk_sizes: impl Into<Option<usize>>,
+        layout: Layout,
+        progress: impl FnMut(State) + Send,
+    ) -> Result<()> {
+        let threads = rayon::current_num_threads();
+        // Every thread also buffers the patch of its chunk until the ones before it are written
+        let chunk_sizes = self.chunk_size_with(chunk_sizes.into(), |len| {
+            threads as u64 * (self.chunk_memory(len, layout) + output_memory(len))
+        })?;
+        let progress = Progress::new(progress);
+        let mut slots: Vec<Slot> = (0..threads).map(|_| Slot::default()).collect();
+        let mut peak_memory = 0;
+        let mut bytes_completed = 0;
+        loop {
+            progress.report(State::Reading);
+            let mut used = 0;
+            for slot in &mut slots {
+                // The buffers are only allocated once a thread gets a chunk
+                if slot.new.is_empty() {
+                    slot.new = vec![0; chunk_sizes];
+                }
+                slot.new_len = read_up_to(new_f, &mut slot.new).context(Stream::New)?;
+                if slot.new_len == 0 {
+                    break;
+                }
+                if slot.old.is_empty() {
+                    slot.old = vec![0; chunk_sizes];
+                }
+                slot.old_len = read_up_to(old_f, &mut slot.old).context(Stream::Old)?;
+                used += 1;
+            }
+            // Nothing left in new file, so no need to read any more
+            if used == 0 {
+                if bytes_completed == 0 {
+                    peak_memory = generate_chunk(&[], &[], patch_f, layout, self, |_| {})?;
+                }
+                break;
+            }
+
+            self.check_cancelled()?;
+            progress.report(State::Sorting);
+            let memory = slots[..used]
+                .par_iter_mut()
+                .map(|slot| {
+                    slot.patch.clear();
+                    generate_chunk(
+                        &slot.old[..slot.old_len],
+                        &slot.new[..slot.new_len],
+                        &mut slot.patch,
+                        layout,
+                        self,
+                        progress.worker(),
+                    )
+                })
+                .collect::<Result<Vec<_>>>()?;
+            for slot in &slots[..used] {
+                patch_f.write_all(&slot.patch).context(Stream::Patch)?;
+                bytes_completed += slot.new_len as u64;
+            }
+            let buffers = slots.iter().map(Slot::memory).sum::<u64>();
+            peak_memory = peak_memory.max(buffers + memory.iter().sum::<u64>());
+        }
+        write_end(patch_f, bytes_completed)?;
+        patch_f.flush().context(Stream::Patch)?;
+        progress.report(State::Finished(peak_memory));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use super::{generate_chunked_parallel, generate_parallel, MIN_SEGMENT_LEN};
+    use crate::test_data::random_bytes;
+    use crate::{
+        apply, apply_streaming, generate_chunked, CancelToken, Error, GenerateOptions, Layout,
+        State,
+    };
+
+    #[test]
+    fn parallel_roundtrip() {
+        let old = random_bytes(3 * MIN_SEGMENT_LEN);
+        let mut new = old.clone();
+        new.splice(10..10, b"inserted".iter().copied());
+        new[2 * MIN_SEGMENT_LEN] ^= 0xFF;
+        new.truncate(new.len() - 1000);
+        let mut patch = Vec::new();
+        // Use multiple threads even on machines with a single core
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(3)
+            .build()
+            .unwrap();
+        pool.install(|| generate_parallel(&old, &new, &mut patch, |_| {}))
+            .unwrap();
+        let mut patched = Vec::new();
+        apply(&mut Cursor::new(&old), &mut patched, &mut &patch[..]).unwrap();
+        assert_eq!(patched, new);
+
+        // Every segment after the first seeks back to the start of the old file, unless the
+        // segments are joined without seeking backwards
+        let err = apply_streaming(&mut &old[..], &mut Vec::new(), &mut &patch[..]).unwrap_err();
+        assert!(matches!(err, Error::BackwardSeek));
+        let options = GenerateOptions::new().forward_only(true);
+        let mut patch = Vec::new();
+        pool.install(|| options.generate_parallel(&old, &new, &mut patch, |_| {}))
+            .unwrap();
+        let mut patched = Vec::new();
+        apply_streaming(&mut &old[..], &mut patched, &mut &patch[..]).unwrap();
+        assert_eq!(patched, new);
+    }
+
+    #[test]
+    fn same_as_chunked() {
+        let old = random_bytes(10_000);
+        let mut new = old.clone();
+        new.splice(5000..5000, b"inserted".iter().copied());
+        let mut expected = Vec::new();
+        generate_chunked(&mut &old[..], &mut &new[..], &mut expected, 3000, |_| {}).unwrap();
+        let mut patch = Vec::new();
+        generate_chunked_parallel(
+            &mut &old[..],
+            &mut &new[..],
+            &mut patch,
+            3000,
+            Layout::Interleaved,
+            |_| {},
+        )
+        .unwrap();
+        assert_eq!(patch, expected);
+
+        let options = GenerateOptions::new().level(1).forward_only(true);
+        let mut expected = Vec::new();
+        options
+            .generate_chunked(
+                &mut &old[..],
+                &mut &new[..],
+                &mut expected,
+                3000,
+                Layout::Interleaved,
+                |_| {},
+            )
+            .unwrap();
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(2)
+            .build()
+            .unwrap();
+        let mut patch = Vec::new();
+        let mut finished = false;
+        pool.install(|| {
+            options.generate_chunked_parallel(
+                &mut &old[..],
+                &mut &new[..],
+                &mut patch,
+                3000,
+                Layout::Interleaved,
+                |state| finished |= matches!(state, State::Finished(_)),
+            )
+        })
+        .unwrap();
+        assert_eq!(patch, expected);
+        assert!(finished);
+
+        let token = CancelToken::new();
+        token.cancel();
+        let result =
+            options
+                .cancel_token(token)
+                .generate_parallel(&old, &new, &mut Vec::new(), |_| {});
+        assert!(matches!(result, Err(Error::Cancelled)));
+    }
+}
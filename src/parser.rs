@@ -0,0 +1,549 @@
+//! Parsing the headers and control entries of a patch, independently of how it is read.
+//!
+//! A [`Parser`] is handed the headers of a patch as they are read, and tells its caller what to do
+//! next, such as applying an entry. It knows the formats, while [`apply_chunked`][crate::apply_chunked],
+//! the asynchronous appliers, [`PatchReader`][crate::PatchReader] and
+//! [`PatchedReader`][crate::PatchedReader] only do the I/O around it.
+
+use core::mem::size_of;
+
+use zerocopy::{FromBytes, LayoutVerified, Unaligned};
+
+use crate::error::Context;
+#[cfg(feature = "std")]
+use crate::io::ErrorKind;
+use crate::io::Read;
+#[cfg(feature = "std")]
+use crate::SplitHeader;
+use crate::{
+    ChecksumHeader, Compression, EntryHeader, Error, PatchHeader, Result, Stream, WindowHeader,
+    BSDIFF_MAGIC, DDELTA_CHECKSUM_MAGIC, DDELTA_END_MAGIC, DDELTA_MAGIC, DDELTA_SPLIT_MAGIC,
+    DDELTA_WINDOW_MAGIC,
+};
+
+/// The format of a patch, or of a chunk of a chunked patch.
+#[derive(Eq, PartialEq, Copy, Clone, Hash, Debug)]
+pub enum Format {
+    /// The format of the original ddelta tool, DDELTA40, as created by
+    /// [`generate`][crate::generate].
+    Ddelta,
+    /// DDELTA41, which contains checksums, as created by
+    /// [`generate_chunked`][crate::generate_chunked].
+    Checksummed,
+    /// DDELTA42, whose control entries, extra and diff bytes are stored in separate sections, each
+    /// compressed with the given algorithm.
+    Split(Compression),
+    /// The format of the original bsdiff tool, BSDIFF40.
+    Bsdiff,
+}
+
+/// The checksums stored in a chunk.
+#[derive(Eq, PartialEq, Copy, Clone, Hash, Debug)]
+pub struct Checksums {
+    /// The amount of bytes of the old file covered by `old`.
+    pub old_size: u64,
+    /// CRC32 of the old data.
+    pub old: u32,
+    /// CRC32 of the new data.
+    pub new: u32,
+}
+
+/// The headers of a patch, or of a chunk of a chunked patch.
+#[derive(Eq, PartialEq, Copy, Clone, Hash, Debug)]
+pub struct ChunkInfo {
+    /// The position of the chunk in the (decompressed) patch.
+    pub offset: u64,
+    /// The format of the chunk.
+    pub format: Format,
+    /// The position in the new file at which the output of this chunk starts.
+    pub new_offset: u64,
+    /// The amount of bytes this chunk produces.
+    pub new_size: u64,
+    /// The position in the old file this chunk is applied to, if the patch was created by
+    /// [`generate_chunked_windowed`][crate::generate_chunked_windowed]. Otherwise, the chunk is
+    /// applied to the old file at `new_offset`.
+    pub old_offset: Option<u64>,
+    /// The checksums of the chunk, if the format contains them.
+    pub checksums: Option<Checksums>,
+}
+
+/// A control entry, which adds `diff` bytes to the old data, appends `extra` new bytes, and then
+/// moves the position in the old file by `seek` bytes.
+#[derive(Eq, PartialEq, Copy, Clone, Hash, Debug)]
+pub struct EntryInfo {
+    /// The position of the entry in the (decompressed) patch. For [`Format::Split`] and
+    /// [`Format::Bsdiff`], this is the position within the decompressed control section instead.
+    pub offset: u64,
+    /// The position in the new file at which the output of this entry starts.
+    pub new_offset: u64,
+    /// The amount of bytes read from the old file and the patch and added together.
+    pub diff: u64,
+    /// The amount of bytes copied from the patch.
+    pub extra: u64,
+    /// The amount of bytes to move the position in the old file by afterwards.
+    pub seek: i64,
+}
+
+/// The lengths of the sections of a chunk whose control entries are stored apart from the data.
+#[cfg(feature = "std")]
+#[derive(Copy, Clone, Debug)]
+pub(crate) enum Sections {
+    /// The control, extra and diff sections of a split chunk, in that order.
+    Split {
+        compression: Compression,
+        control: u64,
+        extra: u64,
+        diff: u64,
+    },
+    /// The bzip2-compressed control and diff blocks of a bsdiff patch, followed by the extra
+    /// block, which extends to the end of the patch.
+    #[cfg(feature = "bzip2")]
+    Bsdiff { control: u64, diff: u64 },
+}
+
+/// What the caller of [`Parser::next`] has to do.
+#[derive(Debug)]
+pub(crate) enum Step {
+    /// Read the next `len` bytes of the patch, or as many as are left, and pass them to
+    /// [`Parser::feed`]. `len` is at most [`HEADER_LEN`].
+    Read(usize),
+    /// A chunk starts here, and has to be applied to the old file at
+    /// `old_offset.unwrap_or(new_offset)`, unless only a single patch is parsed.
+    Chunk(ChunkInfo),
+    /// The sections of the current chunk follow. Read them, and pass the decompressed control
+    /// section to [`Parser::control`] before applying the entries.
+    #[cfg(feature = "std")]
+    Sections(Sections),
+    /// Apply an entry. For interleaved chunks, its diff and extra bytes follow in the patch.
+    Entry(EntryInfo),
+    /// The current chunk has produced as many bytes as declared. Compare the checksum of its new
+    /// data with the one given.
+    ChunkEnd(Option<Checksums>),
+    /// The patch has ended.
+    End,
+}
+
+/// The length of a buffer that fits every header [`Step::Read`] asks for.
+pub(crate) const HEADER_LEN: usize = 32;
+
+enum State {
+    /// At the start of a chunk, where a chunked patch may also end.
+    Header,
+    /// After a window header, expecting the header of the chunk it precedes.
+    Window,
+    /// Expecting the checksums of the current chunk.
+    Checksums {
+        split: bool,
+    },
+    /// Expecting the header describing the sections of a split chunk.
+    #[cfg(feature = "std")]
+    Split,
+    /// Expecting the rest of a bsdiff header, whose start has already been read as the header.
+    #[cfg(feature = "bzip2")]
+    Bsdiff(PatchHeader),
+    /// All headers of the current chunk have been read, and it is reported next.
+    Started,
+    /// The sections of the current chunk are reported next.
+    #[cfg(feature = "std")]
+    Sections(Sections),
+    /// Waiting for the control section passed to [`Parser::control`].
+    #[cfg(feature = "std")]
+    AwaitingControl {
+        bsdiff: bool,
+    },
+    /// Expecting the next interleaved entry.
+    Interleaved,
+    /// An interleaved entry was read and is reported next.
+    Entry(EntryInfo),
+    /// Returning the entries of a control section.
+    #[cfg(feature = "std")]
+    Control {
+        control: Vec<u8>,
+        position: usize,
+        bsdiff: bool,
+    },
+    /// The entries of the current chunk have ended, so it is checked and reported next.
+    Ended {
+        last: bool,
+    },
+    Done,
+}
+
+/// Parses a patch, one header at a time. See the [module documentation][self].
+pub(crate) struct Parser {
+    state: State,
+    /// Whether only a single patch is parsed, which ends after its first chunk.
+    single: bool,
+    /// Whether a chunk with checksums was read, after which the patch has to end with an end
+    /// marker, so that a patch cut off between two chunks isn't mistaken for a shorter one.
+    needs_end: bool,
+    /// The current chunk, once its header has been read.
+    chunk: ChunkInfo,
+    /// The position in the decompressed patch, assuming the caller consumes the data following
+    /// entries and sections.
+    position: u64,
+    /// The position in the new file of the next entry.
+    new_offset: u64,
+    /// Sections following the headers of the current chunk.
+    #[cfg(feature = "std")]
+    sections: Option<Sections>,
+}
+
+impl Parser {
+    /// Parses a chunked patch, as applied by [`apply_chunked`][crate::apply_chunked].
+    pub(crate) fn chunked() -> Self {
+        Parser::resume(0, 0)
+    }
+
+    /// Parses a single patch, as applied by [`apply`][crate::apply]. Nothing after its first
+    /// chunk is read.
+    #[cfg(feature = "std")]
+    pub(crate) fn single() -> Self {
+        Parser {
+            single: true,
+            ..Parser::chunked()
+        }
+    }
+
+    /// Continues parsing a chunked patch at the start of a chunk, `position` bytes into the
+    /// decompressed patch, where `new_offset` bytes of the new file have been produced. The end
+    /// marker is only required once a chunk with checksums has been read after `position`.
+    pub(crate) fn resume(position: u64, new_offset: u64) -> Self {
+        Parser {
+            state: State::Header,
+            single: false,
+            needs_end: false,
+            chunk: ChunkInfo {
+                offset: position,
+                format: Format::Ddelta,
+                new_offset,
+                new_size: 0,
+                old_offset: None,
+                checksums: None,
+            },
+            position,
+            new_offset,
+            #[cfg(feature = "std")]
+            sections: None,
+        }
+    }
+
+    /// Whether the patch consists of chunks, which each move the old file to where they apply.
+    pub(crate) fn is_chunked(&self) -> bool {
+        !self.single
+    }
+
+    /// The position in the decompressed patch, which is the start of the next chunk once
+    /// [`Step::ChunkEnd`] was returned.
+    #[cfg(feature = "std")]
+    pub(crate) fn position(&self) -> u64 {
+        self.position
+    }
+
+    /// The amount of bytes of the new file produced so far.
+    #[cfg(feature = "std")]
+    pub(crate) fn new_offset(&self) -> u64 {
+        self.new_offset
+    }
+
+    /// Returns what to do next.
+    pub(crate) fn next(&mut self) -> Result<Step> {
+        let len = match &self.state {
+            State::Header | State::Window => size_of::<PatchHeader>(),
+            State::Checksums { .. } => size_of::<ChecksumHeader>(),
+            #[cfg(feature = "std")]
+            State::Split => size_of::<SplitHeader>(),
+            #[cfg(feature = "bzip2")]
+            State::Bsdiff(_) => 2 * size_of::<u64>(),
+            State::Interleaved => size_of::<EntryHeader>(),
+            State::Started => {
+                self.state = State::Interleaved;
+                #[cfg(feature = "std")]
+                if let Some(sections) = self.sections.take() {
+                    self.state = State::Sections(sections);
+                }
+                return Ok(Step::Chunk(self.chunk));
+            }
+            #[cfg(feature = "std")]
+            State::Sections(sections) => {
+                let sections = *sections;
+                self.state = match sections {
+                    Sections::Split {
+                        control,
+                        extra,
+                        diff,
+                        ..
+                    } => {
+                        self.position = [control, extra, diff]
+                            .iter()
+                            .try_fold(self.position, |position, &len| position.checked_add(len))
+                            .ok_or(Error::CorruptPatch)?;
+                        State::AwaitingControl { bsdiff: false }
+                    }
+                    #[cfg(feature = "bzip2")]
+                    Sections::Bsdiff { .. } => State::AwaitingControl { bsdiff: true },
+                };
+                return Ok(Step::Sections(sections));
+            }
+            #[cfg(feature = "std")]
+            State::AwaitingControl { .. } => panic!("the control section has not been passed"),
+            State::Entry(entry) => {
+                let entry = *entry;
+                self.state = State::Interleaved;
+                return Ok(Step::Entry(entry));
+            }
+            #[cfg(feature = "std")]
+            State::Control { .. } => {
+                return Ok(match self.next_entry()? {
+                    Some(entry) => Step::Entry(entry),
+                    None => self.next()?,
+                })
+            }
+            State::Ended { last } => {
+                let last = *last;
+                let written = self.new_offset - self.chunk.new_offset;
+                if written != self.chunk.new_size {
+                    return Err(Error::SizeMismatch {
+                        expected: self.chunk.new_size,
+                        actual: written,
+                    });
+                }
+                self.state = if last || self.single {
+                    State::Done
+                } else {
+                    State::Header
+                };
+                return Ok(Step::ChunkEnd(self.chunk.checksums));
+            }
+            State::Done => return Ok(Step::End),
+        };
+        debug_assert!(len <= HEADER_LEN);
+        Ok(Step::Read(len))
+    }
+
+    /// Passes the bytes read for [`Step::Read`], which are fewer than requested only if the patch
+    /// has ended.
+    pub(crate) fn feed(&mut self, bytes: &[u8]) -> Result<()> {
+        let offset = self.position;
+        self.position += bytes.len() as u64;
+        match &self.state {
+            State::Header if bytes.is_empty() && !self.single => {
+                if self.needs_end {
+                    return Err(Error::TruncatedPatch);
+                }
+                self.state = State::Done;
+            }
+            State::Header => {
+                let header = parse::<PatchHeader>(bytes)?;
+                if &header.magic == DDELTA_END_MAGIC && !self.single {
+                    let expected = header.new_file_size.get();
+                    if expected != self.new_offset {
+                        return Err(Error::SizeMismatch {
+                            expected,
+                            actual: self.new_offset,
+                        });
+                    }
+                    self.state = State::Done;
+                    return Ok(());
+                }
+                self.chunk = ChunkInfo {
+                    offset,
+                    format: Format::Ddelta,
+                    new_offset: self.new_offset,
+                    new_size: header.new_file_size.get(),
+                    old_offset: None,
+                    checksums: None,
+                };
+                if &header.magic == DDELTA_WINDOW_MAGIC && !self.single {
+                    let window = parse::<WindowHeader>(bytes)?;
+                    self.chunk.old_offset = Some(window.old_offset.get());
+                    self.state = State::Window;
+                } else {
+                    self.start(header)?;
+                }
+            }
+            State::Window => {
+                let header = parse::<PatchHeader>(bytes)?;
+                self.chunk.new_size = header.new_file_size.get();
+                self.start(header)?;
+            }
+            State::Checksums { split } => {
+                let split = *split;
+                let checksums = parse::<ChecksumHeader>(bytes)?;
+                self.chunk.checksums = Some(Checksums {
+                    old_size: checksums.old_file_size.get(),
+                    old: checksums.old_checksum.get(),
+                    new: checksums.new_checksum.get(),
+                });
+                self.state = match split {
+                    #[cfg(feature = "std")]
+                    true => State::Split,
+                    // Split patches hold their control and extra sections in memory, which
+                    // requires the `std` feature
+                    #[cfg(not(feature = "std"))]
+                    true => return Err(Error::BadMagic),
+                    false => State::Started,
+                };
+            }
+            #[cfg(feature = "std")]
+            State::Split => {
+                let split = parse::<SplitHeader>(bytes)?;
+                let compression = Compression::from_id(split.compression).ok_or(Error::BadMagic)?;
+                self.chunk.format = Format::Split(compression);
+                self.sections = Some(Sections::Split {
+                    compression,
+                    control: split.control_len.get(),
+                    extra: split.extra_len.get(),
+                    diff: split.diff_len.get(),
+                });
+                self.state = State::Started;
+            }
+            #[cfg(feature = "bzip2")]
+            State::Bsdiff(header) => {
+                let header = crate::bsdiff::BsdiffHeader::read(*header, &mut &bytes[..])?;
+                self.chunk.format = Format::Bsdiff;
+                self.chunk.new_size = header.new_file_size;
+                self.sections = Some(Sections::Bsdiff {
+                    control: header.control_len,
+                    diff: header.diff_len,
+                });
+                self.state = State::Started;
+            }
+            State::Interleaved => {
+                let entry = parse::<EntryHeader>(bytes)?;
+                let (diff, extra, seek) = (entry.diff.get(), entry.extra.get(), entry.seek.get());
+                if diff == 0 && extra == 0 && seek == 0 {
+                    self.state = State::Ended { last: false };
+                    return Ok(());
+                }
+                let entry = self.entry(offset, diff, extra, seek)?;
+                self.position = diff
+                    .checked_add(extra)
+                    .and_then(|len| self.position.checked_add(len))
+                    .ok_or(Error::CorruptPatch)?;
+                self.state = State::Entry(entry);
+            }
+            _ => panic!("no bytes were requested"),
+        }
+        Ok(())
+    }
+
+    /// Starts the chunk described by `header`, which is the header following the window header,
+    /// if any.
+    fn start(&mut self, header: PatchHeader) -> Result<()> {
+        self.state = match &header.magic {
+            DDELTA_MAGIC => State::Started,
+            DDELTA_CHECKSUM_MAGIC => {
+                self.chunk.format = Format::Checksummed;
+                self.needs_end = !self.single;
+                State::Checksums { split: false }
+            }
+            DDELTA_SPLIT_MAGIC => {
+                self.needs_end = !self.single;
+                State::Checksums { split: true }
+            }
+            #[cfg(feature = "bzip2")]
+            BSDIFF_MAGIC => State::Bsdiff(header),
+            #[cfg(not(feature = "bzip2"))]
+            BSDIFF_MAGIC => return Err(Error::UnsupportedCompression(Compression::Bzip2)),
+            _ => return Err(Error::BadMagic),
+        };
+        Ok(())
+    }
+
+    /// Passes the decompressed control section after [`Step::Sections`].
+    #[cfg(feature = "std")]
+    pub(crate) fn control(&mut self, control: Vec<u8>) {
+        self.state = match self.state {
+            State::AwaitingControl { bsdiff } => State::Control {
+                control,
+                position: 0,
+                bsdiff,
+            },
+            _ => panic!("no control section was requested"),
+        };
+    }
+
+    /// Returns the next entry of the control section, or `None` once the chunk has ended, after
+    /// which [`next`][Parser::next] returns [`Step::ChunkEnd`].
+    #[cfg(feature = "std")]
+    pub(crate) fn next_entry(&mut self) -> Result<Option<EntryInfo>> {
+        let (control, position, bsdiff) = match &mut self.state {
+            State::Control {
+                control,
+                position,
+                bsdiff,
+            } => (control, position, *bsdiff),
+            _ => panic!("not reading a control section"),
+        };
+        let written = self.new_offset - self.chunk.new_offset;
+        // The control block of a bsdiff patch may be followed by padding
+        let ended = if bsdiff {
+            written == self.chunk.new_size
+        } else {
+            *position == control.len()
+        };
+        if ended {
+            self.state = State::Ended { last: bsdiff };
+            return Ok(None);
+        }
+        let offset = *position as u64;
+        let mut rest = &control[*position..];
+        let (diff, extra, seek) = match bsdiff {
+            #[cfg(feature = "bzip2")]
+            true => crate::bsdiff::read_entry(&mut rest)?,
+            _ => {
+                let entry = read!(rest, EntryHeader)?;
+                (entry.diff.get(), entry.extra.get(), entry.seek.get())
+            }
+        };
+        *position = control.len() - rest.len();
+        if bsdiff && diff.saturating_add(extra) > self.chunk.new_size - written {
+            return Err(Error::CorruptPatch);
+        }
+        self.entry(offset, diff, extra, seek).map(Some)
+    }
+
+    /// Describes an entry at `offset` that starts where the last one ended.
+    fn entry(&mut self, offset: u64, diff: u64, extra: u64, seek: i64) -> Result<EntryInfo> {
+        let entry = EntryInfo {
+            offset,
+            new_offset: self.new_offset,
+            diff,
+            extra,
+            seek,
+        };
+        self.new_offset = diff
+            .checked_add(extra)
+            .and_then(|len| self.new_offset.checked_add(len))
+            .ok_or(Error::CorruptPatch)?;
+        Ok(entry)
+    }
+}
+
+/// Reads a header of type `T` from `bytes`, which are all the bytes read for it.
+fn parse<T: FromBytes + Unaligned + Copy>(bytes: &[u8]) -> Result<T> {
+    LayoutVerified::<_, T>::new_unaligned(bytes)
+        .map(|header| *header)
+        .ok_or(Error::TruncatedPatch)
+}
+
+/// Reads the `len` bytes asked for by [`Step::Read`] into `buf`, or as many as there are left in
+/// the patch.
+pub(crate) fn read_step<'b>(
+    patch: &mut impl Read,
+    buf: &'b mut [u8; HEADER_LEN],
+    len: usize,
+) -> Result<&'b [u8]> {
+    let mut read = 0;
+    while read < len {
+        match patch.read(&mut buf[read..len]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            #[cfg(feature = "std")]
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e).context(Stream::Patch),
+        }
+    }
+    Ok(&buf[..read])
+}
@@ -1,135 +1,858 @@
-use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
-use std::mem::size_of;
+#[cfg(feature = "std")]
+use crate::cancel::{CancelToken, Cancellable};
+#[cfg(feature = "std")]
+use crate::compression::{decoder, decompress};
+use crate::error::Context;
+#[cfg(feature = "std")]
+use crate::io::Std;
+use crate::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+#[cfg(feature = "std")]
+use crate::parser::Sections;
+use crate::parser::{read_step, Parser, Step, HEADER_LEN};
+use crate::{Checksums, Compression, Error, Stream};
+#[cfg(not(feature = "std"))]
+use crate::{CompressionHeader, DDELTA_COMPRESSED_MAGIC};
 
-use zerocopy::LayoutVerified;
-
-use anyhow::{anyhow, bail, ensure};
+use super::Result;
 
-use crate::{EntryHeader, PatchHeader, DDELTA_MAGIC};
+#[cfg(feature = "std")]
+pub(crate) const BLOCK_SIZE: u64 = 32 * 1024;
 
-use super::Result;
-use std::io;
-
-const BLOCK_SIZE: u64 = 32 * 1024;
-macro_rules! read {
-    ($reader: expr, $type: ty) => {{
-        let mut buf = [0; size_of::<$type>()];
-        let data: Result<$type> = $reader
-            .read_exact(&mut buf)
-            .map_err(|err| err.into())
-            .and_then(|_| {
-                LayoutVerified::<_, $type>::new(&buf[..])
-                    .map(|data| *data)
-                    .ok_or_else(|| anyhow!("Bytes not aligned"))
-            });
-        data
-    }};
-}
-fn apply_diff(
+/// Applies `size` bytes of diff data, using each half of `buf` as a block of the patch and of the
+/// old file.
+pub(crate) fn apply_diff(
     patch_f: &mut impl Read,
     old_f: &mut impl Read,
     new_f: &mut impl Write,
     mut size: u64,
+    buf: &mut [u8],
 ) -> Result<()> {
-    let mut old = [0; BLOCK_SIZE as usize];
-    let mut patch = [0; BLOCK_SIZE as usize];
+    let (old, patch) = buf.split_at_mut(buf.len() / 2);
+    let block_size = old.len() as u64;
     while size > 0 {
-        let to_read = BLOCK_SIZE.min(size) as usize;
+        let to_read = block_size.min(size) as usize;
         let old = &mut old[..to_read];
         let patch = &mut patch[..to_read];
 
-        patch_f.read_exact(patch)?;
-        old_f.read_exact(old)?;
+        patch_f.read_exact(patch).context(Stream::Patch)?;
+        old_f.read_exact(old).context(Stream::Old)?;
 
         old.iter_mut()
             .zip(patch.iter())
             .for_each(|(old, patch)| *old = old.wrapping_add(*patch));
 
-        new_f.write_all(&old)?;
+        new_f.write_all(old).context(Stream::New)?;
 
         size -= to_read as u64;
     }
     Ok(())
 }
 
-fn copy_bytes(src: &mut impl Read, dst: &mut impl Write, mut bytes: u64) -> Result<()> {
-    let mut buf = [0; BLOCK_SIZE as usize];
+pub(crate) fn copy_bytes(
+    patch: &mut impl Read,
+    new: &mut impl Write,
+    mut bytes: u64,
+    buf: &mut [u8],
+) -> Result<()> {
     while bytes > 0 {
-        let to_read = BLOCK_SIZE.min(bytes) as usize;
+        let to_read = (buf.len() as u64).min(bytes) as usize;
         let buf = &mut buf[..to_read];
-        src.read_exact(buf)?;
-        dst.write_all(buf)?;
+        patch.read_exact(buf).context(Stream::Patch)?;
+        new.write_all(buf).context(Stream::New)?;
         bytes -= to_read as u64;
     }
     Ok(())
 }
 
-fn apply_with_header(
+/// A writer that calculates the checksum of everything written to it.
+struct ChecksumWriter<'a, W> {
+    inner: &'a mut W,
+    hasher: crc32fast::Hasher,
+}
+
+impl<W: Write> Write for ChecksumWriter<'_, W> {
+    fn write_all(&mut self, buf: &[u8]) -> core::result::Result<(), crate::io::Error> {
+        self.inner.write_all(buf)?;
+        self.hasher.update(buf);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> core::result::Result<(), crate::io::Error> {
+        self.inner.flush()
+    }
+}
+
+/// Verifies the checksum of the old data, leaving `old` at the same position as it was before.
+pub(crate) fn verify_old(
     old: &mut (impl Read + Seek),
-    new: &mut impl Write,
+    checksums: &Checksums,
+    buf: &mut [u8],
+) -> Result<()> {
+    let start = old.stream_position().context(Stream::Old)?;
+    let mut hasher = crc32fast::Hasher::new();
+    let mut bytes = checksums.old_size;
+    while bytes > 0 {
+        let to_read = (buf.len() as u64).min(bytes) as usize;
+        let buf = &mut buf[..to_read];
+        match old.read_exact(buf) {
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+                return Err(Error::ChecksumMismatch(Stream::Old))
+            }
+            other => other.context(Stream::Old)?,
+        }
+        hasher.update(buf);
+        bytes -= to_read as u64;
+    }
+    if hasher.finalize() != checksums.old {
+        return Err(Error::ChecksumMismatch(Stream::Old));
+    }
+    old.seek(SeekFrom::Start(start)).context(Stream::Old)?;
+    Ok(())
+}
+
+/// Applies the chunks `parser` finds in the decompressed `patch`, calling `chunk_done` once each
+/// chunk has been written and verified. The checksum of the old data is only verified if
+/// `check_old` is set, as that requires seeking back in the old file.
+pub(crate) fn apply_parsed<O: Read + Seek, W: Write>(
+    old: &mut O,
+    new: &mut W,
     patch: &mut impl Read,
-    header: PatchHeader,
+    parser: &mut Parser,
+    buf: &mut [u8],
+    check_old: bool,
+    mut chunk_done: impl FnMut(&mut O, &mut W, &Parser) -> Result<()>,
 ) -> Result<()> {
-    ensure!(&header.magic == DDELTA_MAGIC, "Invalid magic number");
-    let mut bytes_written = 0;
+    let mut new = ChecksumWriter {
+        inner: new,
+        hasher: crc32fast::Hasher::new(),
+    };
+    let mut header = [0; HEADER_LEN];
     loop {
-        let entry = read!(patch, EntryHeader)?;
-        if entry.diff.get() == 0 && entry.extra.get() == 0 && entry.seek.get() == 0 {
-            return if bytes_written == header.new_file_size.get() {
-                Ok(())
-            } else {
-                bail!("Patch too short");
-            };
+        match parser.next()? {
+            Step::Read(len) => parser.feed(read_step(patch, &mut header, len)?)?,
+            Step::Chunk(chunk) => {
+                if parser.is_chunked() {
+                    // Without a window, each chunk of the old file has the same position as the
+                    // chunk of the new file, and if they're not, no data is read from the old file
+                    let offset = chunk.old_offset.unwrap_or(chunk.new_offset);
+                    old.seek(SeekFrom::Start(offset)).context(Stream::Old)?;
+                }
+                if let Some(checksums) = chunk.checksums.as_ref().filter(|_| check_old) {
+                    verify_old(old, checksums, buf)?;
+                }
+            }
+            #[cfg(feature = "std")]
+            Step::Sections(sections) => {
+                apply_sections(old, &mut new, patch, parser, sections, buf)?;
+            }
+            Step::Entry(entry) => {
+                apply_diff(patch, old, &mut new, entry.diff, buf)?;
+                copy_bytes(patch, &mut new, entry.extra, buf)?;
+                old.seek(SeekFrom::Current(entry.seek))
+                    .context(Stream::Old)?;
+            }
+            Step::ChunkEnd(checksums) => {
+                let hasher = core::mem::take(&mut new.hasher);
+                if checksums.is_some_and(|checksums| hasher.finalize() != checksums.new) {
+                    return Err(Error::ChecksumMismatch(Stream::New));
+                }
+                chunk_done(old, new.inner, parser)?;
+            }
+            Step::End => return Ok(()),
         }
-        apply_diff(patch, old, new, entry.diff.get())?;
-        copy_bytes(patch, new, entry.extra.get())?;
-        old.seek(SeekFrom::Current(entry.seek.get()))?;
-        bytes_written += entry.diff.get() + entry.extra.get();
     }
 }
 
-/// Apply a patch file. This is compatible with the formats created by [`generate`][crate::generate]
-/// and the original ddelta program.
+/// Reads a whole section of `len` bytes and decompresses it into memory.
+#[cfg(feature = "std")]
+pub(crate) fn read_section(
+    patch: &mut impl std::io::Read,
+    len: u64,
+    compression: Compression,
+) -> Result<Vec<u8>> {
+    decode_section(&read_raw_section(patch, len)?, compression)
+}
+
+/// Reads a whole section of `len` bytes into memory, without decompressing it.
+#[cfg(feature = "std")]
+pub(crate) fn read_raw_section(patch: &mut impl std::io::Read, len: u64) -> Result<Vec<u8>> {
+    let mut raw = Vec::new();
+    std::io::Read::read_to_end(&mut std::io::Read::take(patch, len), &mut raw)
+        .context(Stream::Patch)?;
+    if raw.len() as u64 != len {
+        return Err(Error::TruncatedPatch);
+    }
+    Ok(raw)
+}
+
+/// Decompresses a whole section that has already been read into memory.
+#[cfg(feature = "std")]
+pub(crate) fn decode_section(raw: &[u8], compression: Compression) -> Result<Vec<u8>> {
+    let mut section = Vec::new();
+    std::io::Read::read_to_end(&mut decoder(raw, compression)?, &mut section)
+        .context(Stream::Patch)?;
+    Ok(section)
+}
+
+/// Applies the entries of a chunk whose control entries, extra and diff bytes are stored in
+/// separate sections. The control and extra sections of split chunks are held in memory, while the
+/// diff section is streamed. The extra block of a bsdiff patch extends to the end of the patch, so
+/// it is streamed, while the diff block is held in memory.
+#[cfg(feature = "std")]
+fn apply_sections(
+    old: &mut (impl Read + Seek),
+    new: &mut impl Write,
+    patch: &mut impl Read,
+    parser: &mut Parser,
+    sections: Sections,
+    buf: &mut [u8],
+) -> Result<()> {
+    let mut patch = Std(patch);
+    match sections {
+        Sections::Split {
+            compression,
+            control,
+            extra,
+            diff,
+        } => {
+            parser.control(read_section(&mut patch, control, compression)?);
+            let extra = read_section(&mut patch, extra, compression)?;
+            let mut diff_section = std::io::Read::take(patch, diff);
+            let mut diff = decoder(&mut diff_section, compression)?;
+            apply_section_entries(old, new, parser, &mut diff, &mut &extra[..], buf)?;
+            drop(diff);
+            // Skip anything the decoder didn't need, so the next chunk starts at the right position
+            std::io::copy(&mut diff_section, &mut std::io::sink()).context(Stream::Patch)?;
+        }
+        #[cfg(feature = "bzip2")]
+        Sections::Bsdiff { control, diff } => {
+            parser.control(read_section(&mut patch, control, Compression::Bzip2)?);
+            let diff = read_raw_section(&mut patch, diff)?;
+            let mut diff = decoder(&diff[..], Compression::Bzip2)?;
+            let mut extra = decoder(patch, Compression::Bzip2)?;
+            apply_section_entries(old, new, parser, &mut diff, &mut extra, buf)?;
+        }
+    }
+    Ok(())
+}
+
+/// Applies the entries of the control section passed to `parser`, reading the diff and extra
+/// bytes from their sections.
+#[cfg(feature = "std")]
+fn apply_section_entries(
+    old: &mut (impl Read + Seek),
+    new: &mut impl Write,
+    parser: &mut Parser,
+    diff: &mut impl Read,
+    extra: &mut impl Read,
+    buf: &mut [u8],
+) -> Result<()> {
+    while let Some(entry) = parser.next_entry()? {
+        apply_diff(diff, old, new, entry.diff, buf)?;
+        copy_bytes(extra, new, entry.extra, buf)?;
+        old.seek(SeekFrom::Current(entry.seek))
+            .context(Stream::Old)?;
+    }
+    Ok(())
+}
+
+/// Apply a patch file. This is compatible with the formats created by [`generate`][crate::generate],
+/// [`generate_split`][crate::generate_split] and the original ddelta program. BSDIFF40 patches, as
+/// created by bsdiff or [`generate_bsdiff`][crate::generate_bsdiff], are supported if the `bzip2`
+/// feature is enabled.
+///
+/// If the patch contains checksums, the old file is verified before writing anything to `new`, and
+/// the new file is verified once written. A mismatch results in
+/// [`Error::ChecksumMismatch`][crate::Error::ChecksumMismatch]. Patches created with a
+/// [`CompressedWriter`][crate::CompressedWriter] are decompressed automatically.
 ///
 /// However, it is not compatible with the format created by
 /// [`generate_chunked`][crate::generate_chunked]. In that case, use [`apply_chunked`].
+#[cfg(feature = "std")]
 pub fn apply(
     old: &mut (impl Read + Seek),
     new: &mut impl Write,
     patch: &mut impl Read,
 ) -> Result<()> {
-    let header = read!(patch, PatchHeader)?;
-    apply_with_header(old, new, patch, header)
+    ApplyOptions::new().apply(old, new, patch)
 }
 
 /// Apply a patch file. This is compatible with the formats created by
-/// [`generate`][crate::generate], [`generate_chunked`][crate::generate_chunked], as well as the
-/// original ddelta program.
+/// [`generate`][crate::generate], [`generate_chunked`][crate::generate_chunked],
+/// [`generate_split`][crate::generate_split],
+/// [`generate_chunked_split`][crate::generate_chunked_split],
+/// [`generate_chunked_windowed`][crate::generate_chunked_windowed], as well as the original ddelta
+/// program.
+///
+/// Checksums are verified for every chunk, and compressed patches are decompressed, as described in
+/// [`apply`].
+#[cfg(feature = "std")]
 pub fn apply_chunked(
     old: &mut (impl Read + Seek),
     new: &mut impl Write,
     patch: &mut impl Read,
 ) -> Result<()> {
-    let mut bytes_written = 0;
-    loop {
-        let header = match read!(patch, PatchHeader) {
-            Ok(header) => header,
-            Err(e) => {
-                return if e
-                    .downcast_ref::<io::Error>()
-                    .map_or(false, |e| e.kind() == ErrorKind::UnexpectedEof)
-                {
-                    Ok(())
-                } else {
-                    Err(e)
-                }
+    ApplyOptions::new().apply_chunked(old, new, patch)
+}
+
+/// The buffer data is read into and written from while applying a patch.
+enum Buffer<'a> {
+    #[cfg(feature = "std")]
+    Owned(Vec<u8>),
+    Borrowed(&'a mut [u8]),
+}
+
+/// Options for applying a patch, which control the buffer used for reading and writing.
+///
+/// The buffer is split in half: one half holds a block of the patch, the other a block of the old
+/// file, and they are added together into the new file. Larger blocks mean fewer, larger reads and
+/// writes. [`apply`] and [`apply_chunked`] use blocks of 32 KiB. The same buffer is used for the
+/// whole patch, and for every other patch applied with the same options.
+///
+/// ```no_run
+/// # #[cfg(feature = "std")]
+/// # fn main() -> ddelta::Result<()> {
+/// use ddelta::ApplyOptions;
+/// use std::fs::File;
+///
+/// let mut options = ApplyOptions::new().block_size(1024 * 1024);
+/// for name in &["a", "b"] {
+///     let mut old = File::open(format!("{}.old", name)).unwrap();
+///     let mut new = File::create(name).unwrap();
+///     let mut patch = File::open(format!("{}.patch", name)).unwrap();
+///     options.apply_chunked(&mut old, &mut new, &mut patch)?;
+/// }
+/// # Ok(())
+/// # }
+/// # #[cfg(not(feature = "std"))]
+/// # fn main() {}
+/// ```
+///
+/// Without the `std` feature, only [`with_buffer`][ApplyOptions::with_buffer] and
+/// [`apply_chunked`][ApplyOptions::apply_chunked] are available, e.g. to apply patches on a
+/// microcontroller. Compressed patches then fail with [`Error::UnsupportedCompression`], and split
+/// patches, which are held in memory while being applied, with [`Error::BadMagic`].
+pub struct ApplyOptions<'a> {
+    buf: Buffer<'a>,
+    #[cfg(feature = "std")]
+    cancel: Option<CancelToken>,
+}
+
+#[cfg(feature = "std")]
+impl ApplyOptions<'static> {
+    /// Creates options with the default block size of 32 KiB.
+    pub fn new() -> Self {
+        ApplyOptions::default()
+    }
+
+    /// Reads and writes blocks of up to `size` bytes, allocating a buffer of twice that size. This
+    /// panics if `size` is 0.
+    pub fn block_size(self, size: usize) -> Self {
+        assert!(size > 0, "the block size must not be 0");
+        ApplyOptions {
+            buf: Buffer::Owned(vec![0; 2 * size]),
+            ..self
+        }
+    }
+
+    /// Stops applying the patch with [`Error::Cancelled`] before the next block is written to
+    /// the new file once `token` is cancelled, leaving the new file incomplete.
+    pub fn cancel_token(self, token: CancelToken) -> Self {
+        ApplyOptions {
+            cancel: Some(token),
+            ..self
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl Default for ApplyOptions<'static> {
+    fn default() -> Self {
+        ApplyOptions {
+            buf: Buffer::Owned(vec![0; 2 * BLOCK_SIZE as usize]),
+            cancel: None,
+        }
+    }
+}
+
+impl<'a> ApplyOptions<'a> {
+    /// Uses `buf` instead of allocating a buffer, so blocks of up to half its size are read and
+    /// written. This panics if `buf` is shorter than 2 bytes.
+    pub fn with_buffer(buf: &'a mut [u8]) -> Self {
+        assert!(buf.len() >= 2, "the buffer must be at least 2 bytes long");
+        ApplyOptions {
+            buf: Buffer::Borrowed(buf),
+            #[cfg(feature = "std")]
+            cancel: None,
+        }
+    }
+
+    fn buf(&mut self) -> &mut [u8] {
+        match &mut self.buf {
+            #[cfg(feature = "std")]
+            Buffer::Owned(buf) => buf,
+            Buffer::Borrowed(buf) => buf,
+        }
+    }
+
+    /// Borrows the buffer along with the cancel token.
+    #[cfg(feature = "std")]
+    pub(crate) fn buf_and_token(&mut self) -> (&mut [u8], Option<&CancelToken>) {
+        let token = self.cancel.as_ref();
+        let buf = match &mut self.buf {
+            Buffer::Owned(buf) => &mut buf[..],
+            Buffer::Borrowed(buf) => buf,
+        };
+        (buf, token)
+    }
+
+    /// Apply a patch file like [`apply`].
+    #[cfg(feature = "std")]
+    pub fn apply(
+        &mut self,
+        old: &mut (impl Read + Seek),
+        new: &mut impl Write,
+        patch: &mut impl Read,
+    ) -> Result<()> {
+        let cancel = self.cancel.clone();
+        let mut new = Cancellable::new(new, cancel.as_ref());
+        let mut patch = Std(patch);
+        let buf = self.buf();
+        let result = decompress(&mut patch).and_then(|mut patch| {
+            let mut parser = Parser::single();
+            apply_parsed(
+                old,
+                &mut new,
+                &mut patch,
+                &mut parser,
+                buf,
+                true,
+                |_, _, _| Ok(()),
+            )
+        });
+        new.result(result)
+    }
+
+    /// Apply a patch file like [`apply_chunked`].
+    pub fn apply_chunked(
+        &mut self,
+        old: &mut (impl Read + Seek),
+        new: &mut impl Write,
+        patch: &mut impl Read,
+    ) -> Result<()> {
+        self.apply_chunks(old, new, patch, true)
+    }
+
+    /// Apply a patch file like [`apply_streaming`].
+    pub fn apply_streaming(
+        &mut self,
+        old: &mut impl Read,
+        new: &mut impl Write,
+        patch: &mut impl Read,
+    ) -> Result<()> {
+        let mut old = Forward::new(old);
+        self.apply_chunks(&mut old, new, patch, false).map_err(|e| {
+            if old.backwards {
+                Error::BackwardSeek
+            } else {
+                e
+            }
+        })
+    }
+
+    /// Applies all chunks of a patch, checking the cancel token before every block written.
+    fn apply_chunks(
+        &mut self,
+        old: &mut (impl Read + Seek),
+        new: &mut impl Write,
+        patch: &mut impl Read,
+        check_old: bool,
+    ) -> Result<()> {
+        #[cfg(feature = "std")]
+        {
+            let cancel = self.cancel.clone();
+            let mut new = Cancellable::new(new, cancel.as_ref());
+            let result = apply_chunks(old, &mut new, patch, self.buf(), check_old);
+            new.result(result)
+        }
+        #[cfg(not(feature = "std"))]
+        apply_chunks(old, new, patch, self.buf(), check_old)
+    }
+}
+
+/// Apply a patch file like [`apply_chunked`], but read the old file without seeking in it. This
+/// only works with patches that never seek backwards in the old file, such as the ones created
+/// with [`GenerateOptions::forward_only`][crate::GenerateOptions::forward_only], so the old file
+/// can come from a pipe or a decompressor. Seeking forwards is done by reading and discarding the
+/// old data in between.
+///
+/// If the patch seeks backwards, [`Error::BackwardSeek`] is returned, but `new` may already have
+/// been partially written. As verifying the checksum of the old data of a chunk requires reading
+/// it twice, only the checksum of the new data is verified, so a wrong old file results in
+/// [`Error::ChecksumMismatch`] with [`Stream::New`] after the chunk has been written.
+#[cfg(feature = "std")]
+pub fn apply_streaming(
+    old: &mut impl Read,
+    new: &mut impl Write,
+    patch: &mut impl Read,
+) -> Result<()> {
+    ApplyOptions::new().apply_streaming(old, new, patch)
+}
+
+/// Applies all chunks of a patch, detecting and removing its compression.
+fn apply_chunks(
+    old: &mut (impl Read + Seek),
+    new: &mut impl Write,
+    patch: &mut impl Read,
+    buf: &mut [u8],
+    check_old: bool,
+) -> Result<()> {
+    #[cfg(feature = "std")]
+    let mut patch = Std(patch);
+    #[cfg(feature = "std")]
+    let mut patch = decompress(&mut patch)?;
+    #[cfg(not(feature = "std"))]
+    let mut patch = Uncompressed::new(patch)?;
+    let mut parser = Parser::chunked();
+    apply_parsed(
+        old,
+        new,
+        &mut patch,
+        &mut parser,
+        buf,
+        check_old,
+        |_, _, _| Ok(()),
+    )
+}
+
+/// A file that can only be read, on which seeking forwards is emulated by discarding data.
+pub(crate) struct Forward<'a, R> {
+    inner: &'a mut R,
+    pos: u64,
+    /// Whether seeking backwards was attempted, which fails.
+    backwards: bool,
+}
+
+impl<'a, R> Forward<'a, R> {
+    pub(crate) fn new(inner: &'a mut R) -> Self {
+        Forward {
+            inner,
+            pos: 0,
+            backwards: false,
+        }
+    }
+}
+
+impl<R: Read> Read for Forward<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> core::result::Result<usize, crate::io::Error> {
+        let read = self.inner.read(buf)?;
+        self.pos += read as u64;
+        Ok(read)
+    }
+}
+
+impl<R: Read> Seek for Forward<'_, R> {
+    fn seek(&mut self, pos: SeekFrom) -> core::result::Result<u64, crate::io::Error> {
+        let target = match pos {
+            SeekFrom::Start(target) => Some(target),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+            SeekFrom::End(_) => None,
+        };
+        let target = match target {
+            Some(target) if target >= self.pos => target,
+            _ => {
+                self.backwards = true;
+                return Err(ErrorKind::InvalidInput.into());
             }
         };
-        // Each iteration expects to start from the beginning of the old file, so we can take
-        // advantage of the fact that the chunks of old & new are always the same, and if they're
-        // not, no data is read from the old file
-        old.seek(SeekFrom::Start(bytes_written))?;
-        bytes_written += header.new_file_size.get();
-        apply_with_header(old, new, patch, header)?;
+        let mut discarded = [0; 512];
+        while self.pos < target {
+            let len = (target - self.pos).min(discarded.len() as u64) as usize;
+            match self.inner.read(&mut discarded[..len])? {
+                // Like seeking past the end of a file, this isn't an error until data is read
+                0 => self.pos = target,
+                n => self.pos += n as u64,
+            }
+        }
+        Ok(self.pos)
+    }
+}
+
+/// A patch that is known not to be compressed, which is all that can be applied without the `std`
+/// feature. Yields the magic number that was read to check this before the rest of the patch.
+#[cfg(not(feature = "std"))]
+struct Uncompressed<'a, R> {
+    magic: [u8; 8],
+    magic_len: usize,
+    pos: usize,
+    inner: &'a mut R,
+}
+
+#[cfg(not(feature = "std"))]
+impl<'a, R: Read> Uncompressed<'a, R> {
+    fn new(patch: &'a mut R) -> Result<Self> {
+        let mut magic = [0; 8];
+        let mut magic_len = 0;
+        while magic_len < magic.len() {
+            match patch.read(&mut magic[magic_len..]).context(Stream::Patch)? {
+                0 => break,
+                n => magic_len += n,
+            }
+        }
+        if &magic == DDELTA_COMPRESSED_MAGIC {
+            let mut id = [0; core::mem::size_of::<CompressionHeader>() - 8];
+            patch.read_exact(&mut id).context(Stream::Patch)?;
+            match Compression::from_id(id[0]).ok_or(Error::BadMagic)? {
+                Compression::None => magic_len = 0,
+                other => return Err(Error::UnsupportedCompression(other)),
+            }
+        }
+        Ok(Uncompressed {
+            magic,
+            magic_len,
+            pos: 0,
+            inner: patch,
+        })
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl<R: Read> Read for Uncompressed<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> core::result::Result<usize, crate::io::Error> {
+        if self.pos == self.magic_len {
+            return self.inner.read(buf);
+        }
+        let len = buf.len().min(self.magic_len - self.pos);
+        buf[..len].copy_from_slice(&self.magic[self.pos..self.pos + len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
+
+#[cfg(all(test, feature = "diff"))]
+mod test {
+    use std::io::Cursor;
+
+    use crate::test_data::random_bytes;
+    use crate::{
+        apply, apply_chunked, apply_streaming, generate, generate_chunked, generate_chunked_split,
+        generate_split, ApplyOptions, Compression, Error, GenerateOptions, Layout, PatchReader,
+        Record, Stream,
+    };
+
+    const OLD: &[u8] = b"The quick brown fox jumps over the lazy dog. The end.";
+    const NEW: &[u8] = b"The quick red fox jumped over the lazy dogs! The end!";
+
+    fn chunked_patch() -> Vec<u8> {
+        let mut patch = Vec::new();
+        generate_chunked(&mut &OLD[..], &mut &NEW[..], &mut patch, 16, |_| {}).unwrap();
+        patch
+    }
+
+    #[test]
+    fn checksummed_roundtrip() {
+        let mut new = Vec::new();
+        apply_chunked(&mut Cursor::new(OLD), &mut new, &mut &chunked_patch()[..]).unwrap();
+        assert_eq!(new, NEW);
+    }
+
+    #[test]
+    fn wrong_old_file() {
+        let mut old = OLD.to_vec();
+        old[20] ^= 1;
+        let mut new = Vec::new();
+        let err =
+            apply_chunked(&mut Cursor::new(old), &mut new, &mut &chunked_patch()[..]).unwrap_err();
+        assert!(matches!(err, Error::ChecksumMismatch(Stream::Old)));
+    }
+
+    #[test]
+    fn bad_magic() {
+        let mut patch = chunked_patch();
+        patch[0] = b'X';
+        let err =
+            apply_chunked(&mut Cursor::new(OLD), &mut Vec::new(), &mut &patch[..]).unwrap_err();
+        assert!(matches!(err, Error::BadMagic));
+    }
+
+    #[test]
+    fn truncated_between_chunks() {
+        let patch = chunked_patch();
+        let chunks = PatchReader::new(&mut &patch[..])
+            .unwrap()
+            .filter_map(|record| match record.unwrap() {
+                Record::Chunk(chunk) => Some(chunk.offset as usize),
+                Record::Entry(_) => None,
+            })
+            .collect::<Vec<_>>();
+        // Cut off the last chunk, and then only the end marker
+        for len in [chunks[chunks.len() - 1], patch.len() - 16] {
+            let err = apply_chunked(&mut Cursor::new(OLD), &mut Vec::new(), &mut &patch[..len])
+                .unwrap_err();
+            assert!(matches!(err, Error::TruncatedPatch));
+        }
+
+        // The end marker holds the size of the new file
+        let mut patch = patch;
+        let len = patch.len();
+        patch[len - 1] ^= 1;
+        let err =
+            apply_chunked(&mut Cursor::new(OLD), &mut Vec::new(), &mut &patch[..]).unwrap_err();
+        assert!(matches!(err, Error::SizeMismatch { .. }));
+    }
+
+    #[test]
+    fn split_roundtrip() {
+        let mut patch = Vec::new();
+        generate_split(OLD, NEW, &mut patch, Compression::None, |_| {}).unwrap();
+        let mut new = Vec::new();
+        apply(&mut Cursor::new(OLD), &mut new, &mut &patch[..]).unwrap();
+        assert_eq!(new, NEW);
+
+        let mut patch = Vec::new();
+        generate_chunked_split(
+            &mut &OLD[..],
+            &mut &NEW[..],
+            &mut patch,
+            16,
+            Compression::None,
+            |_| {},
+        )
+        .unwrap();
+        let mut new = Vec::new();
+        apply_chunked(&mut Cursor::new(OLD), &mut new, &mut &patch[..]).unwrap();
+        assert_eq!(new, NEW);
+    }
+
+    #[test]
+    fn options() {
+        let mut split = Vec::new();
+        generate_chunked_split(
+            &mut &OLD[..],
+            &mut &NEW[..],
+            &mut split,
+            16,
+            Compression::None,
+            |_| {},
+        )
+        .unwrap();
+        let mut buf = [0; 3];
+        for options in &mut [
+            ApplyOptions::new().block_size(1),
+            ApplyOptions::new().block_size(1 << 20),
+            ApplyOptions::with_buffer(&mut buf),
+        ] {
+            for patch in &[chunked_patch(), split.clone()] {
+                let mut new = Vec::new();
+                options
+                    .apply_chunked(&mut Cursor::new(OLD), &mut new, &mut &patch[..])
+                    .unwrap();
+                assert_eq!(new, NEW);
+            }
+        }
+    }
+
+    #[test]
+    fn streaming() {
+        // Moving the first block to the end makes a regular patch seek backwards
+        let old = random_bytes(20_000);
+        let mut new = old[5000..].to_vec();
+        new.extend_from_slice(&old[..5000]);
+        new[100] ^= 0xFF;
+        let mut patch = Vec::new();
+        generate(&old, &new, &mut patch, |_| {}).unwrap();
+        let err = apply_streaming(&mut &old[..], &mut Vec::new(), &mut &patch[..]).unwrap_err();
+        assert!(matches!(err, Error::BackwardSeek));
+
+        let options = GenerateOptions::new().forward_only(true);
+        let mut patch = Vec::new();
+        options.generate(&old, &new, &mut patch, |_| {}).unwrap();
+        let mut patched = Vec::new();
+        apply_streaming(&mut &old[..], &mut patched, &mut &patch[..]).unwrap();
+        assert_eq!(patched, new);
+
+        for &layout in &[Layout::Interleaved, Layout::Split(Compression::None)] {
+            let mut patch = Vec::new();
+            options
+                .generate_chunked(
+                    &mut &old[..],
+                    &mut &new[..],
+                    &mut patch,
+                    8000,
+                    layout,
+                    |_| {},
+                )
+                .unwrap();
+            let mut patched = Vec::new();
+            apply_streaming(&mut &old[..], &mut patched, &mut &patch[..]).unwrap();
+            assert_eq!(patched, new);
+        }
+    }
+
+    #[test]
+    fn empty_new_file() {
+        let mut patch = Vec::new();
+        generate_chunked(&mut &OLD[..], &mut &[][..], &mut patch, None, |_| {}).unwrap();
+        let mut new = Vec::new();
+        apply_chunked(&mut Cursor::new(OLD), &mut new, &mut &patch[..]).unwrap();
+        assert!(new.is_empty());
+    }
+}
+
+#[cfg(all(test, not(feature = "std")))]
+mod test_no_std {
+    use super::ApplyOptions;
+    use crate::io::{Error, ErrorKind, Read, Seek, SeekFrom};
+
+    /// The old file, which needs to be seekable.
+    struct Old {
+        data: &'static [u8],
+        pos: usize,
+    }
+
+    impl Read for Old {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+            let read = (&self.data[self.pos.min(self.data.len())..]).read(buf)?;
+            self.pos += read;
+            Ok(read)
+        }
+    }
+
+    impl Seek for Old {
+        fn seek(&mut self, pos: SeekFrom) -> Result<u64, Error> {
+            let pos = match pos {
+                SeekFrom::Start(pos) => pos as i64,
+                SeekFrom::End(offset) => self.data.len() as i64 + offset,
+                SeekFrom::Current(offset) => self.pos as i64 + offset,
+            };
+            if pos < 0 {
+                return Err(ErrorKind::InvalidInput.into());
+            }
+            self.pos = pos as usize;
+            Ok(pos as u64)
+        }
+    }
+
+    #[test]
+    fn small_buffer() {
+        // DDELTA40 patch turning "hello world" into "hello there!"
+        let mut patch = [0; 8 + 8 + 24 + 6 + 6 + 24];
+        patch[..8].copy_from_slice(b"DDELTA40");
+        patch[15] = 12;
+        patch[23] = 6;
+        patch[31] = 6;
+        patch[46..52].copy_from_slice(b"there!");
+        for &len in &[2, 5, 64] {
+            let mut old = Old {
+                data: b"hello world",
+                pos: 0,
+            };
+            let mut new = [0; 12];
+            let mut buf = [0; 64];
+            ApplyOptions::with_buffer(&mut buf[..len])
+                .apply_chunked(&mut old, &mut &mut new[..], &mut &patch[..])
+                .unwrap();
+            assert_eq!(&new, b"hello there!");
+        }
     }
 }
@@ -0,0 +1,480 @@
+//! Applying a patch lazily, as the new file is read.
+
+use std::io::{self, Cursor, Read, Seek, SeekFrom};
+
+use crate::compression::{decoder, Decoder};
+use crate::error::Context;
+use crate::parser::{read_step, Parser, Sections, Step, HEADER_LEN};
+use crate::patch::{read_raw_section, read_section, verify_old, BLOCK_SIZE};
+use crate::range::PatchIndex;
+#[cfg(feature = "bzip2")]
+use crate::Compression;
+use crate::{Error, Result, Stream};
+
+/// Where the diff and extra bytes of the entries of a chunk come from.
+enum Data {
+    /// Each entry is followed by its diff and extra bytes in the patch.
+    Interleaved,
+    /// The diff and extra bytes are decompressed from sections held in memory, as stored by split
+    /// and bsdiff patches.
+    Sections {
+        diff: Box<dyn Read>,
+        extra: Box<dyn Read>,
+    },
+}
+
+/// The progress of a [`PatchedReader`] without an index.
+struct Sequential {
+    parser: Parser,
+    data: Data,
+    /// The checksum of the new data of the current chunk.
+    hasher: crc32fast::Hasher,
+    /// The diff and extra bytes of the current entry that haven't been read yet.
+    diff: u64,
+    extra: u64,
+    /// The seek following the current entry.
+    seek: i64,
+}
+
+/// How a [`PatchedReader`] finds the data of the new file.
+enum Mode<Patch> {
+    /// Applies the patch from start to end, one chunk after the other.
+    Sequential(Sequential),
+    /// Looks up the entry producing the current position in an index.
+    Indexed {
+        index: PatchIndex,
+        /// The position of the patch when it was passed in, to start decompressing it again.
+        start: u64,
+        /// The position in the patch of its first byte after decompression, if it's uncompressed.
+        base: u64,
+        /// The position in the decompressed patch.
+        patch_pos: u64,
+        /// Seeks in the patch, which is only possible if `Patch` implements [`Seek`], while
+        /// [`Read`] is implemented for any patch.
+        seek: fn(&mut Patch, SeekFrom) -> io::Result<u64>,
+    },
+}
+
+/// Exposes the new file as a [`Read`]er, applying the patch lazily as it is read. This allows
+/// passing the new file to code that pulls data, such as a tar reader or a hasher, without writing
+/// it anywhere first.
+///
+/// Created with [`new`][PatchedReader::new], this supports every format
+/// [`apply_chunked`][crate::apply_chunked] does, and verifies checksums the same way, failing with
+/// an [`io::Error`] of kind [`InvalidData`][io::ErrorKind::InvalidData] wrapping the [`Error`].
+/// The sections of split and bsdiff patches are held in memory while their chunk is read.
+///
+/// Created with [`with_index`][PatchedReader::with_index], it also implements [`Seek`], like
+/// [`PatchIndex::read_new_range`].
+///
+/// ```no_run
+/// # fn main() -> ddelta::Result<()> {
+/// use ddelta::PatchedReader;
+/// use std::fs::File;
+/// use std::io;
+///
+/// let old = File::open("old").unwrap();
+/// let patch = File::open("patch").unwrap();
+/// let mut new = PatchedReader::new(old, patch)?;
+/// io::copy(&mut new, &mut io::stdout()).unwrap();
+/// # Ok(())
+/// # }
+/// ```
+pub struct PatchedReader<Old, Patch: Read> {
+    old: Old,
+    /// The decompressed patch. This is only gone if decompressing it again failed.
+    patch: Option<Decoder<Patch>>,
+    buf: Vec<u8>,
+    /// The position in the new file.
+    pos: u64,
+    mode: Mode<Patch>,
+}
+
+impl<Old: Read + Seek, Patch: Read> PatchedReader<Old, Patch> {
+    /// Prepares to apply `patch` to `old`, detecting its compression. The rest of the patch is
+    /// only read as the new file is read.
+    pub fn new(old: Old, patch: Patch) -> Result<Self> {
+        Ok(PatchedReader {
+            old,
+            patch: Some(Decoder::new(patch)?),
+            buf: vec![0; BLOCK_SIZE as usize],
+            pos: 0,
+            mode: Mode::Sequential(Sequential {
+                parser: Parser::chunked(),
+                data: Data::Interleaved,
+                hasher: crc32fast::Hasher::new(),
+                diff: 0,
+                extra: 0,
+                seek: 0,
+            }),
+        })
+    }
+
+    /// Reads from the patch in order, starting a new chunk whenever the last one ended.
+    fn read_sequential(&mut self, out: &mut [u8]) -> Result<usize> {
+        let state = match &mut self.mode {
+            Mode::Sequential(state) => state,
+            Mode::Indexed { .. } => unreachable!("called in indexed mode"),
+        };
+        let patch = self.patch.as_mut().ok_or_else(lost_patch)?;
+        let mut header = [0; HEADER_LEN];
+        loop {
+            if out.is_empty() {
+                return Ok(0);
+            }
+            let len = if state.diff > 0 {
+                let len = state.diff.min(out.len().min(self.buf.len()) as u64) as usize;
+                let out = &mut out[..len];
+                match &mut state.data {
+                    Data::Interleaved => patch.read_exact(out),
+                    Data::Sections { diff, .. } => diff.read_exact(out),
+                }
+                .context(Stream::Patch)?;
+                let old = &mut self.buf[..len];
+                self.old.read_exact(old).context(Stream::Old)?;
+                out.iter_mut()
+                    .zip(old.iter())
+                    .for_each(|(new, old)| *new = new.wrapping_add(*old));
+                state.diff -= len as u64;
+                len
+            } else if state.extra > 0 {
+                let len = state.extra.min(out.len() as u64) as usize;
+                match &mut state.data {
+                    Data::Interleaved => patch.read_exact(&mut out[..len]),
+                    Data::Sections { extra, .. } => extra.read_exact(&mut out[..len]),
+                }
+                .context(Stream::Patch)?;
+                state.extra -= len as u64;
+                len
+            } else {
+                if state.seek != 0 {
+                    self.old
+                        .seek(SeekFrom::Current(state.seek))
+                        .context(Stream::Old)?;
+                    state.seek = 0;
+                }
+                match state.parser.next()? {
+                    Step::Read(len) => state.parser.feed(read_step(patch, &mut header, len)?)?,
+                    Step::Chunk(chunk) => {
+                        let offset = chunk.old_offset.unwrap_or(chunk.new_offset);
+                        self.old
+                            .seek(SeekFrom::Start(offset))
+                            .context(Stream::Old)?;
+                        if let Some(checksums) = &chunk.checksums {
+                            verify_old(&mut self.old, checksums, &mut self.buf)?;
+                        }
+                        state.data = Data::Interleaved;
+                    }
+                    Step::Sections(sections) => {
+                        state.data = read_sections(patch, &mut state.parser, sections)?;
+                    }
+                    Step::Entry(entry) => {
+                        state.diff = entry.diff;
+                        state.extra = entry.extra;
+                        state.seek = entry.seek;
+                    }
+                    Step::ChunkEnd(checksums) => {
+                        let hasher = std::mem::take(&mut state.hasher);
+                        if checksums.is_some_and(|checksums| hasher.finalize() != checksums.new) {
+                            return Err(Error::ChecksumMismatch(Stream::New));
+                        }
+                    }
+                    Step::End => return Ok(0),
+                }
+                continue;
+            };
+            state.hasher.update(&out[..len]);
+            self.pos += len as u64;
+            return Ok(len);
+        }
+    }
+}
+
+impl<Old: Read + Seek, Patch: Read + Seek> PatchedReader<Old, Patch> {
+    /// Prepares to apply `patch` to `old` through `index`, which must have been created from the
+    /// same patch, positioned where `patch` is now. The reader then also implements [`Seek`], and
+    /// only reads the parts of the old file and the patch needed for the data being read.
+    ///
+    /// Like with [`PatchIndex::read_new_range`], checksums aren't verified, and compressed patches
+    /// have to be decompressed again from their start whenever seeking backwards.
+    pub fn with_index(old: Old, mut patch: Patch, index: PatchIndex) -> Result<Self> {
+        let start = patch.stream_position().context(Stream::Patch)?;
+        let mut patch = Decoder::new(patch)?;
+        let base = match patch.uncompressed() {
+            Some(reader) => {
+                let (magic, patch) = reader.get_mut();
+                patch.stream_position().context(Stream::Patch)? - magic.limit()
+            }
+            None => start,
+        };
+        Ok(PatchedReader {
+            old,
+            patch: Some(patch),
+            buf: vec![0; BLOCK_SIZE as usize],
+            pos: 0,
+            mode: Mode::Indexed {
+                index,
+                start,
+                base,
+                patch_pos: 0,
+                seek: Patch::seek,
+            },
+        })
+    }
+}
+
+impl<Old: Read + Seek, Patch: Read> PatchedReader<Old, Patch> {
+    /// Reads from the entry producing the current position, found in the index.
+    fn read_indexed(&mut self, out: &mut [u8]) -> Result<usize> {
+        let (index, start, base, patch_pos, seek) = match &mut self.mode {
+            Mode::Indexed {
+                index,
+                start,
+                base,
+                patch_pos,
+                seek,
+            } => (&*index, *start, *base, patch_pos, *seek),
+            Mode::Sequential(_) => unreachable!("called in sequential mode"),
+        };
+        if self.pos >= index.new_size() || out.is_empty() {
+            return Ok(0);
+        }
+        let entry = index.entry_at(self.pos).ok_or(Error::CorruptPatch)?;
+        let offset = self.pos - entry.new_offset;
+        let target = entry.patch_offset + offset;
+        if *patch_pos != target {
+            seek_patch(&mut self.patch, start, base, patch_pos, target, seek)?;
+        }
+        let patch = self.patch.as_mut().ok_or_else(lost_patch)?;
+        let len = if offset < entry.diff {
+            let len = (entry.diff - offset).min(out.len().min(self.buf.len()) as u64) as usize;
+            let out = &mut out[..len];
+            patch.read_exact(out).context(Stream::Patch)?;
+            let old = &mut self.buf[..len];
+            self.old
+                .seek(SeekFrom::Start(entry.old_offset + offset))
+                .context(Stream::Old)?;
+            self.old.read_exact(old).context(Stream::Old)?;
+            out.iter_mut()
+                .zip(old.iter())
+                .for_each(|(new, old)| *new = new.wrapping_add(*old));
+            len
+        } else {
+            let len = (entry.diff + entry.extra - offset).min(out.len() as u64) as usize;
+            patch.read_exact(&mut out[..len]).context(Stream::Patch)?;
+            len
+        };
+        *patch_pos += len as u64;
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl<Old: Read + Seek, Patch: Read> Read for PatchedReader<Old, Patch> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        Ok(match self.mode {
+            Mode::Sequential(_) => self.read_sequential(out)?,
+            Mode::Indexed { .. } => self.read_indexed(out)?,
+        })
+    }
+}
+
+impl<Old: Read + Seek, Patch: Read + Seek> Seek for PatchedReader<Old, Patch> {
+    /// Moves to `pos` in the new file. Without an index, this fails with
+    /// [`ErrorKind::Unsupported`][io::ErrorKind::Unsupported] unless `pos` is the current
+    /// position.
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_size = match &self.mode {
+            Mode::Indexed { index, .. } => index.new_size(),
+            Mode::Sequential(_) if pos == SeekFrom::Current(0) => return Ok(self.pos),
+            Mode::Sequential(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "seeking in the new file requires a PatchIndex",
+                ))
+            }
+        };
+        let target = match pos {
+            SeekFrom::Start(target) => Some(target),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+            SeekFrom::End(offset) => new_size.checked_add_signed(offset),
+        };
+        self.pos = target.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )
+        })?;
+        Ok(self.pos)
+    }
+}
+
+/// Returned once decompressing the patch from its start failed, which leaves no patch to read.
+fn lost_patch() -> Error {
+    Error::Io(
+        Stream::Patch,
+        io::Error::other("the patch was lost when a previous seek failed"),
+    )
+}
+
+/// Reads the sections of a chunk into memory, passing the control section to `parser`. The diff
+/// and extra bytes are decompressed as they are read.
+fn read_sections(patch: &mut impl Read, parser: &mut Parser, sections: Sections) -> Result<Data> {
+    Ok(match sections {
+        Sections::Split {
+            compression,
+            control,
+            extra,
+            diff,
+        } => {
+            parser.control(read_section(patch, control, compression)?);
+            let extra = read_section(patch, extra, compression)?;
+            let diff = read_raw_section(patch, diff)?;
+            Data::Sections {
+                diff: decoder(Cursor::new(diff), compression)?,
+                extra: Box::new(Cursor::new(extra)),
+            }
+        }
+        // The extra block extends to the end of the patch
+        #[cfg(feature = "bzip2")]
+        Sections::Bsdiff { control, diff } => {
+            parser.control(read_section(patch, control, Compression::Bzip2)?);
+            let diff = read_raw_section(patch, diff)?;
+            let mut extra = Vec::new();
+            patch.read_to_end(&mut extra).context(Stream::Patch)?;
+            Data::Sections {
+                diff: decoder(Cursor::new(diff), Compression::Bzip2)?,
+                extra: decoder(Cursor::new(extra), Compression::Bzip2)?,
+            }
+        }
+    })
+}
+
+/// Moves the decompressed patch from `patch_pos` to `target`. Uncompressed patches are seeked
+/// directly, while compressed ones are decompressed up to `target`, starting over from `start` if
+/// it's behind.
+fn seek_patch<Patch: Read>(
+    patch: &mut Option<Decoder<Patch>>,
+    start: u64,
+    base: u64,
+    patch_pos: &mut u64,
+    target: u64,
+    seek: fn(&mut Patch, SeekFrom) -> io::Result<u64>,
+) -> Result<()> {
+    let decoder = patch.as_mut().ok_or_else(lost_patch)?;
+    if let Some(reader) = decoder.uncompressed() {
+        let (magic, inner) = reader.get_mut();
+        // Skip the magic number that was read to detect the compression, if it hasn't been yet
+        magic.get_mut().set_position(8);
+        seek(inner, SeekFrom::Start(base + target)).context(Stream::Patch)?;
+        *patch_pos = target;
+        return Ok(());
+    }
+    if target < *patch_pos {
+        let mut inner = patch.take().expect("checked above").into_inner();
+        seek(&mut inner, SeekFrom::Start(start)).context(Stream::Patch)?;
+        *patch = Some(Decoder::new(inner)?);
+        *patch_pos = 0;
+    }
+    let decoder = patch.as_mut().expect("checked above");
+    let skip = target - *patch_pos;
+    let skipped = io::copy(&mut decoder.take(skip), &mut io::sink()).context(Stream::Patch)?;
+    *patch_pos += skipped;
+    if skipped != skip {
+        return Err(Error::TruncatedPatch);
+    }
+    Ok(())
+}
+
+#[cfg(all(test, feature = "diff", feature = "zstd"))]
+mod test {
+    use std::io::{Cursor, Read, Seek, SeekFrom};
+
+    use super::PatchedReader;
+    use crate::test_data::random_bytes;
+    use crate::{
+        generate, generate_chunked, generate_chunked_split, CompressedWriter, Compression,
+        PatchIndex,
+    };
+
+    fn files() -> (Vec<u8>, Vec<u8>) {
+        let old = random_bytes(10_000);
+        let mut new = old[3000..].to_vec();
+        new.extend_from_slice(b"inserted");
+        new.extend_from_slice(&old[..3000]);
+        new[5000] ^= 0xFF;
+        (old, new)
+    }
+
+    #[test]
+    fn sequential() {
+        let (old, new) = files();
+        let mut single = Vec::new();
+        generate(&old, &new, &mut single, |_| {}).unwrap();
+        let mut split = Vec::new();
+        generate_chunked_split(
+            &mut &old[..],
+            &mut &new[..],
+            &mut split,
+            4000,
+            Compression::Zstd,
+            |_| {},
+        )
+        .unwrap();
+        let mut compressed = CompressedWriter::new(Vec::new(), Compression::Zstd).unwrap();
+        generate_chunked(&mut &old[..], &mut &new[..], &mut compressed, 4000, |_| {}).unwrap();
+        let compressed = compressed.finish().unwrap();
+
+        for patch in &[single, split, compressed] {
+            let mut reader = PatchedReader::new(Cursor::new(&old), &patch[..]).unwrap();
+            // Read in small, odd steps to cross entry and chunk boundaries
+            let mut patched = Vec::new();
+            let mut buf = [0; 777];
+            loop {
+                match reader.read(&mut buf).unwrap() {
+                    0 => break,
+                    n => patched.extend_from_slice(&buf[..n]),
+                }
+            }
+            assert_eq!(patched, new);
+        }
+
+        let mut patch = Vec::new();
+        generate_chunked(&mut &old[..], &mut &new[..], &mut patch, 4000, |_| {}).unwrap();
+        let mut wrong = old.clone();
+        wrong[0] ^= 1;
+        let mut reader = PatchedReader::new(Cursor::new(&wrong), &patch[..]).unwrap();
+        assert!(reader.read_to_end(&mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn seek() {
+        let (old, new) = files();
+        let mut plain = Vec::new();
+        generate_chunked(&mut &old[..], &mut &new[..], &mut plain, 4000, |_| {}).unwrap();
+        let mut compressed = CompressedWriter::new(Vec::new(), Compression::Zstd).unwrap();
+        generate_chunked(&mut &old[..], &mut &new[..], &mut compressed, 4000, |_| {}).unwrap();
+        let compressed = compressed.finish().unwrap();
+
+        for patch in &[plain, compressed] {
+            let index = PatchIndex::new(&mut &patch[..]).unwrap();
+            let mut reader =
+                PatchedReader::with_index(Cursor::new(&old), Cursor::new(patch), index).unwrap();
+            for &(start, len) in &[(6000, 100), (10, 3000), (9990, 50), (2995, 20)] {
+                reader.seek(SeekFrom::Start(start)).unwrap();
+                let mut buf = vec![0; len];
+                let end = (start as usize + len).min(new.len());
+                let read = reader.read(&mut buf).unwrap();
+                assert!(read > 0);
+                reader
+                    .read_exact(&mut buf[read..end - start as usize])
+                    .unwrap();
+                assert_eq!(&buf[..end - start as usize], &new[start as usize..end]);
+            }
+            reader.seek(SeekFrom::End(-5)).unwrap();
+            let mut tail = Vec::new();
+            reader.read_to_end(&mut tail).unwrap();
+            assert_eq!(tail, &new[new.len() - 5..]);
+        }
+    }
+}
@@ -0,0 +1,256 @@
+//! Reading ranges of the new file from a patch, without applying everything before them.
+
+use std::mem::size_of;
+use std::ops::Range;
+
+use crate::compression::decompress;
+use crate::error::Context;
+use crate::io::{ErrorKind, Read, Seek, SeekFrom, Std, Write};
+use crate::patch::{apply_diff, copy_bytes, Forward, BLOCK_SIZE};
+use crate::{
+    EntryHeader, Error, Format, PatchReader, Record, Result, Stream, DDELTA_COMPRESSED_MAGIC,
+};
+
+/// Where the data of a control entry is located.
+pub(crate) struct Location {
+    /// The position in the new file at which the output of the entry starts.
+    pub(crate) new_offset: u64,
+    /// The position of the diff bytes in the decompressed patch, which are followed by the extra
+    /// bytes.
+    pub(crate) patch_offset: u64,
+    /// The position in the old file the diff bytes are added to.
+    pub(crate) old_offset: u64,
+    pub(crate) diff: u64,
+    pub(crate) extra: u64,
+}
+
+/// An index over the control entries of a patch, to reconstruct any range of the new file without
+/// applying the patch up to that range, e.g. to serve range requests for the new file.
+///
+/// For every control entry, the index holds its position in the new file, the patch and the old
+/// file, which takes up 40 bytes per entry. Only patches whose diff and extra bytes directly
+/// follow their control entries are supported, which are the ones created by
+/// [`generate`][crate::generate], [`generate_chunked`][crate::generate_chunked] and
+/// [`generate_chunked_windowed`][crate::generate_chunked_windowed], or with
+/// [`Layout::Interleaved`][crate::Layout::Interleaved]. Patches compressed with a
+/// [`CompressedWriter`][crate::CompressedWriter] are supported, but have to be decompressed from
+/// their start up to the requested range, so ranges are only read efficiently from uncompressed
+/// patches.
+///
+/// ```no_run
+/// # fn main() -> ddelta::Result<()> {
+/// use ddelta::PatchIndex;
+/// use std::fs::File;
+///
+/// let mut old = File::open("old").unwrap();
+/// let mut patch = File::open("patch").unwrap();
+/// let index = PatchIndex::new(&mut patch)?;
+/// let mut patch = File::open("patch").unwrap();
+/// let mut range = Vec::new();
+/// index.read_new_range(&mut old, &mut patch, 1000..2000, &mut range)?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct PatchIndex {
+    entries: Vec<Location>,
+    new_size: u64,
+}
+
+impl PatchIndex {
+    /// Reads the control entries of `patch`. Split and bsdiff patches fail with
+    /// [`Error::NoRandomAccess`].
+    pub fn new(patch: &mut impl Read) -> Result<Self> {
+        let mut entries = Vec::new();
+        let mut new_size = 0;
+        let mut old_pos = 0i64;
+        let mut patch = Std(patch);
+        for record in PatchReader::new(&mut patch)? {
+            match record? {
+                Record::Chunk(chunk) => {
+                    if let Format::Split(_) | Format::Bsdiff = chunk.format {
+                        return Err(Error::NoRandomAccess);
+                    }
+                    old_pos = chunk.old_offset.unwrap_or(chunk.new_offset) as i64;
+                    new_size = chunk.new_offset + chunk.new_size;
+                }
+                Record::Entry(entry) => {
+                    if entry.diff > 0 && old_pos < 0 {
+                        return Err(Error::CorruptPatch);
+                    }
+                    if entry.diff + entry.extra > 0 {
+                        entries.push(Location {
+                            new_offset: entry.new_offset,
+                            patch_offset: entry.offset + size_of::<EntryHeader>() as u64,
+                            old_offset: old_pos.max(0) as u64,
+                            diff: entry.diff,
+                            extra: entry.extra,
+                        });
+                    }
+                    old_pos += entry.diff as i64 + entry.seek;
+                }
+            }
+        }
+        Ok(PatchIndex { entries, new_size })
+    }
+
+    /// The size of the new file.
+    pub fn new_size(&self) -> u64 {
+        self.new_size
+    }
+
+    /// Returns the entry producing the byte at `pos` in the new file.
+    pub(crate) fn entry_at(&self, pos: u64) -> Option<&Location> {
+        let index = self.first_entry(pos);
+        self.entries
+            .get(index)
+            .filter(|entry| entry.new_offset <= pos)
+    }
+
+    /// Returns the index of the first entry whose output ends after `pos`.
+    fn first_entry(&self, pos: u64) -> usize {
+        self.entries
+            .partition_point(|entry| entry.new_offset + entry.diff + entry.extra <= pos)
+    }
+
+    /// Writes the bytes of the new file in `range` to `out`, which is clamped to the size of the new
+    /// file. `patch` must be the patch this index was created from, positioned at its start.
+    ///
+    /// Only the parts of the old file and the patch that make up `range` are read. Checksums
+    /// stored in the patch cover whole chunks, so they aren't verified.
+    pub fn read_new_range(
+        &self,
+        old: &mut (impl Read + Seek),
+        patch: &mut (impl Read + Seek),
+        range: Range<u64>,
+        out: &mut impl Write,
+    ) -> Result<()> {
+        let end = range.end.min(self.new_size);
+        let range = range.start.min(end)..end;
+        let start = patch.stream_position().context(Stream::Patch)?;
+        let mut magic = [0; 8];
+        let compressed = match patch.read_exact(&mut magic) {
+            Ok(()) => &magic == DDELTA_COMPRESSED_MAGIC,
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => false,
+            Err(e) => return Err(Error::Io(Stream::Patch, e)),
+        };
+        patch.seek(SeekFrom::Start(start)).context(Stream::Patch)?;
+        let mut buf = vec![0; 2 * BLOCK_SIZE as usize];
+        if compressed {
+            let mut patch = Std(patch);
+            let mut patch = decompress(&mut patch)?;
+            self.read_entries(old, &mut Forward::new(&mut patch), 0, range, out, &mut buf)
+        } else {
+            self.read_entries(old, patch, start, range, out, &mut buf)
+        }
+    }
+
+    /// Writes `range` of the new file, reading a patch whose decompressed data starts at `base`.
+    fn read_entries(
+        &self,
+        old: &mut (impl Read + Seek),
+        patch: &mut (impl Read + Seek),
+        base: u64,
+        range: Range<u64>,
+        out: &mut impl Write,
+        buf: &mut [u8],
+    ) -> Result<()> {
+        for entry in &self.entries[self.first_entry(range.start)..] {
+            if entry.new_offset >= range.end {
+                break;
+            }
+            // The part of the entry's output that is in the range
+            let start = range.start.saturating_sub(entry.new_offset);
+            let end = (range.end - entry.new_offset).min(entry.diff + entry.extra);
+            if start < entry.diff {
+                patch
+                    .seek(SeekFrom::Start(base + entry.patch_offset + start))
+                    .context(Stream::Patch)?;
+                old.seek(SeekFrom::Start(entry.old_offset + start))
+                    .context(Stream::Old)?;
+                apply_diff(patch, old, out, end.min(entry.diff) - start, buf)?;
+            }
+            if end > entry.diff {
+                let start = start.max(entry.diff);
+                patch
+                    .seek(SeekFrom::Start(base + entry.patch_offset + start))
+                    .context(Stream::Patch)?;
+                copy_bytes(patch, out, end - start, buf)?;
+            }
+        }
+        out.flush().context(Stream::New)
+    }
+}
+
+/// Writes the bytes of the new file in `range` to `out`, like [`PatchIndex::read_new_range`]. This
+/// reads all control entries of `patch` first, so create a [`PatchIndex`] to read multiple ranges
+/// of the same patch.
+pub fn read_new_range(
+    old: &mut (impl Read + Seek),
+    patch: &mut (impl Read + Seek),
+    range: Range<u64>,
+    out: &mut impl Write,
+) -> Result<()> {
+    let start = patch.stream_position().context(Stream::Patch)?;
+    let index = PatchIndex::new(patch)?;
+    patch.seek(SeekFrom::Start(start)).context(Stream::Patch)?;
+    index.read_new_range(old, patch, range, out)
+}
+
+#[cfg(all(test, feature = "diff", feature = "zstd"))]
+mod test {
+    use std::io::Cursor;
+
+    use super::{read_new_range, PatchIndex};
+    use crate::test_data::random_bytes;
+    use crate::{generate, generate_chunked, generate_split, CompressedWriter, Compression, Error};
+
+    #[test]
+    fn ranges() {
+        let old = random_bytes(10_000);
+        let mut new = old[3000..].to_vec();
+        new.extend_from_slice(b"inserted");
+        new.extend_from_slice(&old[..3000]);
+        new[5000] ^= 0xFF;
+
+        let mut single = Vec::new();
+        generate(&old, &new, &mut single, |_| {}).unwrap();
+        let mut chunked = Vec::new();
+        generate_chunked(&mut &old[..], &mut &new[..], &mut chunked, 4000, |_| {}).unwrap();
+        let mut compressed = CompressedWriter::new(Vec::new(), Compression::Zstd).unwrap();
+        generate_chunked(&mut &old[..], &mut &new[..], &mut compressed, 4000, |_| {}).unwrap();
+        let compressed = compressed.finish().unwrap();
+
+        let patches = [single, chunked, compressed];
+        for patch in &patches {
+            let index = PatchIndex::new(&mut &patch[..]).unwrap();
+            assert_eq!(index.new_size(), new.len() as u64);
+            for &(start, end) in &[
+                (0, 10),
+                (2990, 3020),
+                (3900, 8100),
+                (9990, 20_000),
+                (50, 50),
+            ] {
+                let mut out = Vec::new();
+                let mut patch = Cursor::new(patch);
+                index
+                    .read_new_range(&mut Cursor::new(&old), &mut patch, start..end, &mut out)
+                    .unwrap();
+                let end = (end as usize).min(new.len());
+                assert_eq!(out, &new[start as usize..end]);
+            }
+        }
+
+        let mut out = Vec::new();
+        let mut patch = Cursor::new(&patches[1]);
+        read_new_range(&mut Cursor::new(&old), &mut patch, 100..200, &mut out).unwrap();
+        assert_eq!(out, &new[100..200]);
+
+        let mut split = Vec::new();
+        generate_split(&old, &new, &mut split, Compression::None, |_| {}).unwrap();
+        assert!(matches!(
+            PatchIndex::new(&mut &split[..]),
+            Err(Error::NoRandomAccess)
+        ));
+    }
+}
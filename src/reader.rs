@@ -0,0 +1,215 @@
+use std::io::{self, Read};
+
+use crate::compression::decompress;
+use crate::error::Context;
+use crate::parser::{read_step, Parser, Sections, Step, HEADER_LEN};
+use crate::patch::read_section;
+#[cfg(feature = "bzip2")]
+use crate::Compression;
+use crate::{ChunkInfo, EntryInfo, Format};
+use crate::{Error, Result, Stream};
+
+/// An item of a patch, as returned by [`PatchReader`].
+#[derive(Eq, PartialEq, Copy, Clone, Hash, Debug)]
+pub enum Record {
+    /// The start of a patch or chunk. The entries of that chunk follow.
+    Chunk(ChunkInfo),
+    /// A control entry of the last chunk.
+    Entry(EntryInfo),
+}
+
+/// Iterates over the headers and control entries of a patch, without requiring the old file or
+/// producing any output.
+///
+/// This is useful to inspect a patch, for example to display statistics. It supports every format
+/// [`apply_chunked`][crate::apply_chunked] does, and decompresses patches created with a
+/// [`CompressedWriter`][crate::CompressedWriter]. The diff and extra bytes are skipped. Checksums
+/// are returned as stored in the patch, but not verified.
+///
+/// ```no_run
+/// # fn main() -> ddelta::Result<()> {
+/// use ddelta::{PatchReader, Record};
+/// use std::fs::File;
+///
+/// let mut patch = File::open("patch").unwrap();
+/// for record in PatchReader::new(&mut patch)? {
+///     if let Record::Chunk(chunk) = record? {
+///         println!("{:?} chunk of {} bytes", chunk.format, chunk.new_size);
+///     }
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub struct PatchReader<'a> {
+    patch: Box<dyn Read + 'a>,
+    parser: Parser,
+    /// Whether each entry of the current chunk is followed by its diff and extra bytes.
+    interleaved: bool,
+    /// Whether the last record was an error, after which nothing more is read.
+    failed: bool,
+}
+
+impl<'a> PatchReader<'a> {
+    /// Prepares to read `patch`, decompressing it if necessary.
+    pub fn new<R: Read>(patch: &'a mut R) -> Result<Self> {
+        Ok(PatchReader {
+            patch: decompress(patch)?,
+            parser: Parser::chunked(),
+            interleaved: false,
+            failed: false,
+        })
+    }
+
+    /// Reads the control section of a split or bsdiff chunk and skips the rest of its data.
+    fn read_control(&mut self, sections: Sections) -> Result<()> {
+        let control = match sections {
+            Sections::Split {
+                compression,
+                control,
+                extra,
+                diff,
+            } => {
+                let control = read_section(&mut self.patch, control, compression)?;
+                self.skip(extra + diff)?;
+                control
+            }
+            // The extra block extends to the end of the patch, so nothing can follow the control block
+            #[cfg(feature = "bzip2")]
+            Sections::Bsdiff { control, .. } => {
+                read_section(&mut self.patch, control, Compression::Bzip2)?
+            }
+        };
+        self.parser.control(control);
+        Ok(())
+    }
+
+    fn skip(&mut self, len: u64) -> Result<()> {
+        let skipped =
+            io::copy(&mut (&mut self.patch).take(len), &mut io::sink()).context(Stream::Patch)?;
+        if skipped != len {
+            return Err(Error::TruncatedPatch);
+        }
+        Ok(())
+    }
+
+    fn read_record(&mut self) -> Result<Option<Record>> {
+        let mut header = [0; HEADER_LEN];
+        loop {
+            match self.parser.next()? {
+                Step::Read(len) => {
+                    let bytes = read_step(&mut self.patch, &mut header, len)?;
+                    self.parser.feed(bytes)?;
+                }
+                Step::Chunk(chunk) => {
+                    self.interleaved = matches!(chunk.format, Format::Ddelta | Format::Checksummed);
+                    return Ok(Some(Record::Chunk(chunk)));
+                }
+                Step::Sections(sections) => self.read_control(sections)?,
+                Step::Entry(entry) => {
+                    if self.interleaved {
+                        self.skip(entry.diff + entry.extra)?;
+                    }
+                    return Ok(Some(Record::Entry(entry)));
+                }
+                Step::ChunkEnd(_) => {}
+                Step::End => return Ok(None),
+            }
+        }
+    }
+}
+
+impl Iterator for PatchReader<'_> {
+    type Item = Result<Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        let record = self.read_record();
+        self.failed = record.is_err();
+        record.transpose()
+    }
+}
+
+#[cfg(all(test, feature = "diff"))]
+mod test {
+    use super::{Format, PatchReader, Record};
+    use crate::{generate, generate_chunked, generate_chunked_split, Compression, Error};
+
+    const OLD: &[u8] = b"The quick brown fox jumps over the lazy dog. The end.";
+    const NEW: &[u8] = b"The quick red fox jumped over the lazy dogs! The end!";
+
+    fn read_all(patch: &[u8]) -> Vec<Record> {
+        PatchReader::new(&mut &patch[..])
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap()
+    }
+
+    /// Checks that the entries of each chunk add up to the size of the chunk, and their offsets.
+    fn check_sizes(records: &[Record]) {
+        let mut remaining = 0;
+        let mut new_offset = 0;
+        for record in records {
+            match record {
+                Record::Chunk(chunk) => {
+                    assert_eq!(remaining, 0);
+                    assert_eq!(chunk.new_offset, new_offset);
+                    remaining = chunk.new_size;
+                }
+                Record::Entry(entry) => {
+                    assert_eq!(entry.new_offset, new_offset);
+                    remaining -= entry.diff + entry.extra;
+                    new_offset += entry.diff + entry.extra;
+                }
+            }
+        }
+        assert_eq!(remaining, 0);
+    }
+
+    #[test]
+    fn read_formats() {
+        let mut patch = Vec::new();
+        generate(OLD, NEW, &mut patch, |_| {}).unwrap();
+        let records = read_all(&patch);
+        assert!(matches!(records[0], Record::Chunk(chunk) if chunk.format == Format::Ddelta));
+        check_sizes(&records);
+
+        let mut patch = Vec::new();
+        generate_chunked(&mut &OLD[..], &mut &NEW[..], &mut patch, 20, |_| {}).unwrap();
+        let records = read_all(&patch);
+        let chunks = records
+            .iter()
+            .filter(|record| matches!(record, Record::Chunk(_)))
+            .count();
+        assert_eq!(chunks, 3);
+        check_sizes(&records);
+
+        let mut patch = Vec::new();
+        generate_chunked_split(
+            &mut &OLD[..],
+            &mut &NEW[..],
+            &mut patch,
+            20,
+            Compression::None,
+            |_| {},
+        )
+        .unwrap();
+        let records = read_all(&patch);
+        assert!(
+            matches!(records[0], Record::Chunk(chunk) if chunk.format == Format::Split(Compression::None))
+        );
+        check_sizes(&records);
+    }
+
+    #[test]
+    fn truncated() {
+        let mut patch = Vec::new();
+        generate_chunked(&mut &OLD[..], &mut &NEW[..], &mut patch, None, |_| {}).unwrap();
+        patch.truncate(patch.len() - 10);
+        let mut patch = &patch[..];
+        let mut reader = PatchReader::new(&mut patch).unwrap();
+        assert!(reader.any(|record| matches!(record, Err(Error::TruncatedPatch))));
+        assert!(reader.next().is_none());
+    }
+}
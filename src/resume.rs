@@ -0,0 +1,255 @@
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::mem::size_of;
+
+use zerocopy::{AsBytes, LayoutVerified, U32, U64};
+
+use crate::cancel::Cancellable;
+use crate::compression::decompress;
+use crate::error::Context;
+use crate::parser::Parser;
+use crate::patch::apply_parsed;
+use crate::{ApplyOptions, CheckpointHeader, Error, Result, Stream, DDELTA_CHECKPOINT_MAGIC};
+
+/// The progress of [`apply_resumable`], which is saved after every chunk of the patch.
+///
+/// All offsets refer to the position at which the next chunk starts.
+#[derive(Eq, PartialEq, Copy, Clone, Hash, Debug, Default)]
+pub struct Checkpoint {
+    /// The position in the decompressed patch.
+    pub patch_offset: u64,
+    /// The position in the old file.
+    pub old_offset: u64,
+    /// The amount of bytes of the new file that have been written.
+    pub new_offset: u64,
+    /// The amount of chunks that have been applied.
+    pub chunk: u64,
+}
+
+impl Checkpoint {
+    /// The size of a serialized checkpoint.
+    pub const SIZE: usize = size_of::<CheckpointHeader>();
+
+    fn checksum(header: &CheckpointHeader) -> u32 {
+        crc32fast::hash(&header.as_bytes()[..Self::SIZE - size_of::<u32>()])
+    }
+
+    /// Serializes the checkpoint into [`SIZE`][Checkpoint::SIZE] bytes, including a checksum.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut header = CheckpointHeader {
+            magic: *DDELTA_CHECKPOINT_MAGIC,
+            patch_offset: U64::new(self.patch_offset),
+            old_offset: U64::new(self.old_offset),
+            new_offset: U64::new(self.new_offset),
+            chunk: U64::new(self.chunk),
+            checksum: U32::new(0),
+        };
+        header.checksum = U32::new(Self::checksum(&header));
+        header.as_bytes().to_vec()
+    }
+
+    /// Deserializes a checkpoint created by [`to_bytes`][Checkpoint::to_bytes]. Returns [`None`]
+    /// if `bytes` isn't a valid checkpoint, e.g. because it was only partially written.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let header = LayoutVerified::<_, CheckpointHeader>::new_unaligned(bytes)?;
+        if &header.magic != DDELTA_CHECKPOINT_MAGIC
+            || header.checksum.get() != Self::checksum(&header)
+        {
+            return None;
+        }
+        Some(Checkpoint {
+            patch_offset: header.patch_offset.get(),
+            old_offset: header.old_offset.get(),
+            new_offset: header.new_offset.get(),
+            chunk: header.chunk.get(),
+        })
+    }
+}
+
+/// Apply a patch like [`apply_chunked`][crate::apply_chunked], but save a [`Checkpoint`] after
+/// every chunk, so that applying it can be continued after being interrupted.
+///
+/// `save` is called with the checkpoint once a chunk has been written and flushed to `new`. It
+/// should make sure that the new file is written to disk, e.g. with
+/// [`File::sync_data`][std::fs::File::sync_data], and then persist the checkpoint, e.g. with
+/// [`Checkpoint::to_bytes`]. To continue, call this function again with the same old file and
+/// patch, the partially written new file and the last saved checkpoint. The patch is read from its
+/// start again, but the chunks before the checkpoint are skipped. Pass [`None`] to start from the
+/// beginning.
+///
+/// As progress is only saved between chunks, create the patch with
+/// [`generate_chunked`][crate::generate_chunked] and a `chunk_sizes` that is small enough to not
+/// lose too much progress.
+///
+/// ```no_run
+/// # fn main() -> ddelta::Result<()> {
+/// use ddelta::{apply_resumable, Checkpoint};
+/// use std::fs::{self, File, OpenOptions};
+///
+/// let checkpoint = fs::read("journal").ok().and_then(|bytes| Checkpoint::from_bytes(&bytes));
+/// let mut old = File::open("old").unwrap();
+/// let new = OpenOptions::new().write(true).create(true).open("new").unwrap();
+/// let mut patch = File::open("patch").unwrap();
+/// apply_resumable(&mut old, &mut &new, &mut patch, checkpoint, |checkpoint| {
+///     new.sync_data()?;
+///     fs::write("journal.tmp", checkpoint.to_bytes())?;
+///     fs::rename("journal.tmp", "journal")
+/// })?;
+/// fs::remove_file("journal").unwrap();
+/// # Ok(())
+/// # }
+/// ```
+pub fn apply_resumable(
+    old: &mut (impl Read + Seek),
+    new: &mut (impl Write + Seek),
+    patch: &mut impl Read,
+    checkpoint: Option<Checkpoint>,
+    save: impl FnMut(&Checkpoint) -> io::Result<()>,
+) -> Result<()> {
+    ApplyOptions::new().apply_resumable(old, new, patch, checkpoint, save)
+}
+
+impl ApplyOptions<'_> {
+    /// Apply a patch file like [`apply_resumable`]. If the cancel token is cancelled, the last
+    /// saved checkpoint can be used to continue later.
+    pub fn apply_resumable(
+        &mut self,
+        old: &mut (impl Read + Seek),
+        new: &mut (impl Write + Seek),
+        patch: &mut impl Read,
+        checkpoint: Option<Checkpoint>,
+        mut save: impl FnMut(&Checkpoint) -> io::Result<()>,
+    ) -> Result<()> {
+        let mut checkpoint = checkpoint.unwrap_or_default();
+        let mut patch = decompress(patch)?;
+        let skipped = io::copy(
+            &mut (&mut patch).take(checkpoint.patch_offset),
+            &mut io::sink(),
+        )
+        .context(Stream::Patch)?;
+        if skipped != checkpoint.patch_offset {
+            return Err(Error::TruncatedPatch);
+        }
+        old.seek(SeekFrom::Start(checkpoint.old_offset))
+            .context(Stream::Old)?;
+        new.seek(SeekFrom::Start(checkpoint.new_offset))
+            .context(Stream::New)?;
+
+        let (buf, token) = self.buf_and_token();
+        let mut new = Cancellable::new(new, token);
+        let mut parser = Parser::resume(checkpoint.patch_offset, checkpoint.new_offset);
+        let result = apply_parsed(
+            old,
+            &mut new,
+            &mut patch,
+            &mut parser,
+            buf,
+            true,
+            |old, new, parser| {
+                new.flush().context(Stream::New)?;
+                checkpoint = Checkpoint {
+                    patch_offset: parser.position(),
+                    old_offset: old.stream_position().context(Stream::Old)?,
+                    new_offset: parser.new_offset(),
+                    chunk: checkpoint.chunk + 1,
+                };
+                save(&checkpoint).context(Stream::Journal)
+            },
+        );
+        new.result(result)
+    }
+}
+
+#[cfg(all(test, feature = "diff"))]
+mod test {
+    use std::io::{self, Cursor, Seek, SeekFrom, Write};
+
+    use super::{apply_resumable, Checkpoint};
+    use crate::{generate_chunked, CompressedWriter, Compression, Error, Stream};
+
+    const OLD: &[u8] = b"The quick brown fox jumps over the lazy dog. The end.";
+    const NEW: &[u8] = b"The quick red fox jumped over the lazy dogs! The end!";
+
+    /// A new file that fails once a certain amount of bytes have been written to it.
+    struct Failing {
+        inner: Cursor<Vec<u8>>,
+        remaining: usize,
+    }
+
+    impl Write for Failing {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if buf.len() > self.remaining {
+                return Err(io::Error::other("power loss"));
+            }
+            self.remaining -= buf.len();
+            self.inner.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Seek for Failing {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.inner.seek(pos)
+        }
+    }
+
+    #[test]
+    fn serialize() {
+        let checkpoint = Checkpoint {
+            patch_offset: 1,
+            old_offset: 2,
+            new_offset: 3,
+            chunk: 4,
+        };
+        let mut bytes = checkpoint.to_bytes();
+        assert_eq!(bytes.len(), Checkpoint::SIZE);
+        assert_eq!(Checkpoint::from_bytes(&bytes), Some(checkpoint));
+        bytes[10] ^= 1;
+        assert_eq!(Checkpoint::from_bytes(&bytes), None);
+        assert_eq!(Checkpoint::from_bytes(&bytes[..20]), None);
+    }
+
+    #[test]
+    fn resume() {
+        let compression = if Compression::Zstd.is_supported() {
+            Compression::Zstd
+        } else {
+            Compression::None
+        };
+        let mut patch = CompressedWriter::new(Vec::new(), compression).unwrap();
+        generate_chunked(&mut &OLD[..], &mut &NEW[..], &mut patch, 10, |_| {}).unwrap();
+        let patch = patch.finish().unwrap();
+
+        let mut new = Failing {
+            inner: Cursor::new(Vec::new()),
+            remaining: 25,
+        };
+        let mut saved = None;
+        let result = apply_resumable(
+            &mut Cursor::new(OLD),
+            &mut new,
+            &mut &patch[..],
+            None,
+            |checkpoint| {
+                saved = Some(*checkpoint);
+                Ok(())
+            },
+        );
+        assert!(matches!(result, Err(Error::Io(Stream::New, _))));
+        assert_eq!(saved.unwrap().chunk, 2);
+        assert_eq!(saved.unwrap().new_offset, 20);
+
+        let mut new = new.inner;
+        apply_resumable(
+            &mut Cursor::new(OLD),
+            &mut new,
+            &mut &patch[..],
+            saved,
+            |_| Ok(()),
+        )
+        .unwrap();
+        assert_eq!(new.get_ref(), NEW);
+    }
+}
@@ -0,0 +1,360 @@
+//! Algorithms sorting the suffixes of the old file, which the generator searches for the longest
+//! match of each position in the new file.
+
+use std::mem::size_of;
+
+#[cfg(not(feature = "c"))]
+use divsufsort as cdivsufsort;
+
+/// A position in the old file, as stored in a suffix array. Its size determines how much memory
+/// the suffix array takes up, and how large the old file can be.
+pub trait SuffixIndex: Copy + Default + Send + Sync {
+    /// The size of the largest file whose positions can be stored.
+    const MAX_LEN: u64;
+
+    /// Represents `pos`, which is at most [`MAX_LEN`][SuffixIndex::MAX_LEN].
+    fn new(pos: usize) -> Self;
+
+    /// Returns the position this represents.
+    fn get(self) -> usize;
+}
+
+impl SuffixIndex for i32 {
+    const MAX_LEN: u64 = i32::MAX as u64 - 1;
+
+    fn new(pos: usize) -> Self {
+        pos as i32
+    }
+
+    fn get(self) -> usize {
+        self as usize
+    }
+}
+
+impl SuffixIndex for u64 {
+    const MAX_LEN: u64 = isize::MAX as u64;
+
+    fn new(pos: usize) -> Self {
+        pos as u64
+    }
+
+    fn get(self) -> usize {
+        self as usize
+    }
+}
+
+/// An algorithm calculating the suffix array of the old file, to be used by
+/// [`GenerateOptions::suffix_sort`][crate::GenerateOptions::suffix_sort].
+pub trait SuffixSort {
+    /// The type positions in the suffix array are stored as.
+    type Index: SuffixIndex;
+
+    /// Returns the start positions of all non-empty suffixes of `data` in lexicographic order.
+    /// `data` is at most [`MAX_LEN`][SuffixIndex::MAX_LEN] bytes long.
+    fn sort(&self, data: &[u8]) -> Vec<Self::Index>;
+
+    /// The peak amount of memory in bytes used to sort `len` bytes, including the suffix array
+    /// that is returned. Defaults to the size of the suffix array.
+    fn memory(&self, len: usize) -> u64 {
+        (len as u64 + 1) * size_of::<Self::Index>() as u64
+    }
+}
+
+/// Sorts suffixes with divsufsort, using the C library if the `c` feature is enabled, and its Rust
+/// port otherwise. This is the fastest algorithm, but it stores positions as 32-bit numbers, so
+/// single patches are limited to 2^31-1 bytes. It takes up 4 bytes per byte of the old file.
+#[derive(Eq, PartialEq, Copy, Clone, Hash, Debug, Default)]
+pub struct DivSufSort;
+
+impl SuffixSort for DivSufSort {
+    type Index = i32;
+
+    fn sort(&self, data: &[u8]) -> Vec<i32> {
+        cdivsufsort::sort(data).into_parts().1
+    }
+
+    fn memory(&self, len: usize) -> u64 {
+        // The suffix array, and the buckets of all pairs of bytes
+        (len as u64 + 1) * size_of::<i32>() as u64 + 2 * 256 * 256 * size_of::<i32>() as u64
+    }
+}
+
+/// Sorts suffixes with SA-IS, implemented in Rust. This stores positions as 64-bit numbers, so
+/// files larger than 2^31-1 bytes can be diffed in a single patch, matching data anywhere in the
+/// old file. The suffix array takes up 8 bytes per byte of the old file, and sorting temporarily
+/// needs about 1 more.
+#[derive(Eq, PartialEq, Copy, Clone, Hash, Debug, Default)]
+pub struct SaIs;
+
+impl SuffixSort for SaIs {
+    type Index = u64;
+
+    fn sort(&self, data: &[u8]) -> Vec<u64> {
+        let mut sa = vec![0; data.len()];
+        sa_is(data, u8::MAX as usize, &mut sa, &mut []);
+        // On 64-bit targets, this reuses the allocation
+        sa.into_iter().map(|pos| pos as u64).collect()
+    }
+
+    fn memory(&self, len: usize) -> u64 {
+        // The suffix array, and the types of the suffixes. The buckets of the recursion are stored
+        // in the unused part of the suffix array, unless nearly every other position starts an
+        // LMS suffix, which can take up to 4 more bytes per byte.
+        (len as u64 + 1) * 9
+    }
+}
+
+/// Marks a position of the suffix array that hasn't been filled in yet.
+const EMPTY: usize = usize::MAX;
+
+/// Writes the suffix array of `s`, whose symbols are at most `upper`, to `sa` by induced sorting:
+/// the suffixes starting with an S-type symbol that follows an L-type one (LMS suffixes) are
+/// sorted first, recursively if necessary, and the order of all other suffixes is induced from
+/// them. The reduced problem of the recursion is stored in `sa` itself, and the buckets are stored
+/// in `spare` if it is large enough.
+fn sa_is<T: Copy + Into<usize>>(s: &[T], upper: usize, sa: &mut [usize], spare: &mut [usize]) {
+    let n = s.len();
+    match n {
+        0 => return,
+        1 => {
+            sa[0] = 0;
+            return;
+        }
+        2 => {
+            let less = s[0].into() < s[1].into();
+            sa.copy_from_slice(if less { &[0, 1] } else { &[1, 0] });
+            return;
+        }
+        _ => {}
+    }
+
+    // Sort the LMS substrings, i.e. the parts of the string from each LMS suffix up to and
+    // including the start of the next one, by inducing from the unsorted LMS suffixes
+    let ls = types(s);
+    let is_lms = |i: usize| i > 0 && !ls[i - 1] && ls[i];
+    let mut owned = Vec::new();
+    let bucket = buckets(upper, spare, &mut owned);
+    sa.fill(EMPTY);
+    bucket_ends(s, bucket);
+    for i in (1..n).filter(|&i| is_lms(i)) {
+        let c = s[i].into();
+        bucket[c] -= 1;
+        sa[bucket[c]] = i;
+    }
+    induce(s, &ls, sa, bucket);
+
+    // Move the sorted LMS suffixes to the front
+    let mut m = 0;
+    for i in 0..n {
+        if is_lms(sa[i]) {
+            sa[m] = sa[i];
+            m += 1;
+        }
+    }
+    if m == 0 {
+        // Every suffix is L-type, so their order has already been induced from the end
+        return;
+    }
+
+    // Name each LMS substring by its rank, giving equal substrings the same name. LMS suffixes
+    // are at least 2 positions apart, so there are at most n / 2 of them, and the name of the
+    // one at `pos` can be stored at `m + pos / 2`.
+    sa[m..].fill(EMPTY);
+    let end = |pos: usize| (pos + 1..n).find(|&i| is_lms(i)).unwrap_or(n);
+    let mut rec_upper = 0;
+    let mut prev = (0, 0);
+    for i in 0..m {
+        let pos = sa[i];
+        let cur = (pos, end(pos));
+        if i > 0 {
+            let (mut l, mut r) = (prev.0, cur.0);
+            let mut same = prev.1 - l == cur.1 - r;
+            if same {
+                while l < prev.1 && s[l].into() == s[r].into() {
+                    l += 1;
+                    r += 1;
+                }
+                same = l < n && r < n && s[l].into() == s[r].into();
+            }
+            if !same {
+                rec_upper += 1;
+            }
+        }
+        sa[m + pos / 2] = rec_upper;
+        prev = cur;
+    }
+    // Gather the names at the end in the order of the LMS suffixes, forming the reduced string
+    let mut j = n;
+    for i in (m..n).rev() {
+        if sa[i] != EMPTY {
+            j -= 1;
+            sa[j] = sa[i];
+        }
+    }
+    drop(ls);
+    owned = Vec::new();
+
+    // Sort the suffixes of the reduced string, which is only necessary if some names are equal
+    let (rest, rec_s) = sa.split_at_mut(n - m);
+    let (rec_sa, rec_spare) = rest.split_at_mut(m);
+    if rec_upper + 1 < m {
+        sa_is(rec_s, rec_upper, rec_sa, rec_spare);
+    } else {
+        for (i, &name) in rec_s.iter().enumerate() {
+            rec_sa[name] = i;
+        }
+    }
+    // Replace the reduced string with the positions of the LMS suffixes to look up their order
+    let ls = types(s);
+    let is_lms = |i: usize| !ls[i - 1] && ls[i];
+    for (slot, i) in rec_s.iter_mut().zip((1..n).filter(|&i| is_lms(i))) {
+        *slot = i;
+    }
+    for rank in rec_sa.iter_mut() {
+        *rank = rec_s[*rank];
+    }
+
+    // Place the sorted LMS suffixes at the ends of their buckets, starting with the last one, which
+    // is always placed after the unprocessed ones, and induce the order of the others from them
+    let bucket = buckets(upper, spare, &mut owned);
+    sa[m..].fill(EMPTY);
+    bucket_ends(s, bucket);
+    for i in (0..m).rev() {
+        let pos = sa[i];
+        sa[i] = EMPTY;
+        let c = s[pos].into();
+        bucket[c] -= 1;
+        sa[bucket[c]] = pos;
+    }
+    induce(s, &ls, sa, bucket);
+}
+
+/// Returns room for the buckets of the symbols up to `upper`, in `spare` if it is large enough.
+fn buckets<'a>(upper: usize, spare: &'a mut [usize], owned: &'a mut Vec<usize>) -> &'a mut [usize] {
+    if spare.len() > upper {
+        &mut spare[..=upper]
+    } else {
+        *owned = vec![0; upper + 1];
+        owned
+    }
+}
+
+/// Returns whether each suffix of `s` is S-type, i.e. smaller than the suffix following it.
+fn types<T: Copy + Into<usize>>(s: &[T]) -> Vec<bool> {
+    let n = s.len();
+    let mut ls = vec![false; n];
+    for i in (0..n - 1).rev() {
+        let (a, b) = (s[i].into(), s[i + 1].into());
+        ls[i] = if a == b { ls[i + 1] } else { a < b };
+    }
+    ls
+}
+
+/// Sets each symbol's entry of `bucket` to the number of symbols of `s` that are at most as large,
+/// i.e. the end of the symbol's bucket in the suffix array.
+fn bucket_ends<T: Copy + Into<usize>>(s: &[T], bucket: &mut [usize]) {
+    bucket.fill(0);
+    for &c in s {
+        bucket[c.into()] += 1;
+    }
+    let mut sum = 0;
+    for end in bucket {
+        sum += *end;
+        *end = sum;
+    }
+}
+
+/// Sets each symbol's entry of `bucket` to the number of symbols of `s` that are smaller, i.e.
+/// the start of the symbol's bucket in the suffix array.
+fn bucket_starts<T: Copy + Into<usize>>(s: &[T], bucket: &mut [usize]) {
+    bucket.fill(0);
+    for &c in s {
+        bucket[c.into()] += 1;
+    }
+    let mut sum = 0;
+    for start in bucket {
+        let count = *start;
+        *start = sum;
+        sum += count;
+    }
+}
+
+/// Sorts all suffixes into `sa`, which contains the LMS suffixes at the ends of their buckets.
+fn induce<T: Copy + Into<usize>>(s: &[T], ls: &[bool], sa: &mut [usize], bucket: &mut [usize]) {
+    let n = s.len();
+    // L-type suffixes are induced from left to right at the starts of the buckets, beginning with
+    // the last suffix, which precedes the end of the string
+    bucket_starts(s, bucket);
+    let c = s[n - 1].into();
+    sa[bucket[c]] = n - 1;
+    bucket[c] += 1;
+    for i in 0..n {
+        let v = sa[i];
+        if v != EMPTY && v >= 1 && !ls[v - 1] {
+            let c = s[v - 1].into();
+            sa[bucket[c]] = v - 1;
+            bucket[c] += 1;
+        }
+    }
+    // S-type suffixes are induced from right to left at the ends of the buckets, replacing the
+    // LMS suffixes placed there
+    bucket_ends(s, bucket);
+    for i in (0..n).rev() {
+        let v = sa[i];
+        if v != EMPTY && v >= 1 && ls[v - 1] {
+            let c = s[v - 1].into();
+            bucket[c] -= 1;
+            sa[bucket[c]] = v - 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{DivSufSort, SaIs, SuffixSort};
+    use crate::test_data::random_bytes;
+    use crate::{generate, GenerateOptions};
+
+    #[test]
+    fn matches_divsufsort() {
+        let random = random_bytes(5000);
+        let small_alphabet: Vec<u8> = random.iter().map(|b| b % 3).collect();
+        // Almost every other position starts an LMS suffix
+        let alternating: Vec<u8> = random
+            .iter()
+            .enumerate()
+            .map(|(i, b)| if i % 2 == 0 { b & 0x7F } else { b | 0x80 })
+            .collect();
+        let repetitive: Vec<u8> = b"abracadabra".iter().cycle().take(3000).copied().collect();
+        let inputs: [&[u8]; 8] = [
+            b"",
+            b"a",
+            b"ba",
+            b"mississippi",
+            &random,
+            &small_alphabet,
+            &alternating,
+            &repetitive,
+        ];
+        for data in inputs {
+            let expected: Vec<u64> = DivSufSort
+                .sort(data)
+                .into_iter()
+                .map(|pos| pos as u64)
+                .collect();
+            assert_eq!(SaIs.sort(data), expected);
+        }
+        assert_eq!(SaIs.sort(&[0; 100]), (0..100).rev().collect::<Vec<u64>>());
+
+        let mut new = random.clone();
+        new[1000..1100].copy_from_slice(&repetitive[..100]);
+        let mut expected = Vec::new();
+        generate(&random, &new, &mut expected, |_| {}).unwrap();
+        let mut patch = Vec::new();
+        GenerateOptions::new()
+            .suffix_sort(SaIs)
+            .generate(&random, &new, &mut patch, |_| {})
+            .unwrap();
+        assert_eq!(patch, expected);
+    }
+}
@@ -0,0 +1,15 @@
+//! Data shared by the tests of several modules.
+
+/// Returns `len` pseudo-random bytes, which are the same on every call. Unlike data following a
+/// short pattern, no part of it matches anywhere else, so tests notice matches at wrong positions.
+pub(crate) fn random_bytes(len: usize) -> Vec<u8> {
+    let mut state = 1u64;
+    (0..len)
+        .map(|_| {
+            state = state
+                .wrapping_mul(6_364_136_223_846_793_005)
+                .wrapping_add(1_442_695_040_888_963_407);
+            (state >> 56) as u8
+        })
+        .collect()
+}
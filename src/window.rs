@@ -0,0 +1,195 @@
+use std::collections::HashMap;
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::mem::size_of;
+
+use crate::diff::{read_up_to, OldSource};
+
+/// The amount of bytes hashed for each sample of the old file.
+const BLOCK_LEN: usize = 32;
+/// The maximum amount of samples taken from the old file. This limits the index to a few dozen
+/// megabytes, regardless of the size of the old file.
+const MAX_SAMPLES: u64 = 1 << 20;
+const PRIME: u64 = 0x0100_0000_01b3;
+
+/// A Rabin-Karp hash over the last [`BLOCK_LEN`] bytes.
+struct RollingHash {
+    hash: u64,
+    /// `PRIME` to the power of `BLOCK_LEN - 1`, to remove the oldest byte.
+    outgoing_factor: u64,
+}
+
+impl RollingHash {
+    fn new(block: &[u8]) -> Self {
+        let outgoing_factor = (1..BLOCK_LEN).fold(1u64, |acc, _| acc.wrapping_mul(PRIME));
+        let hash = block.iter().fold(0u64, |acc, &b| {
+            acc.wrapping_mul(PRIME).wrapping_add(b as u64)
+        });
+        RollingHash {
+            hash,
+            outgoing_factor,
+        }
+    }
+
+    fn roll(&mut self, outgoing: u8, incoming: u8) {
+        self.hash = self
+            .hash
+            .wrapping_sub((outgoing as u64).wrapping_mul(self.outgoing_factor))
+            .wrapping_mul(PRIME)
+            .wrapping_add(incoming as u64);
+    }
+}
+
+/// Selects the window of the old file that best matches each chunk of the new file.
+///
+/// The old file is sampled at a fixed interval, and every sample is indexed by its hash. For a chunk
+/// of the new file, the hash of every block is looked up, and the window of the old file that
+/// contains the most matching samples is used.
+pub(crate) struct Windowed<'a, R> {
+    old: &'a mut R,
+    len: u64,
+    index: HashMap<u64, u64>,
+    /// The offset of the old data that would be used if the chunks of the old and new file were
+    /// aligned, which is used if no block of the new chunk is found in the index.
+    aligned: u64,
+}
+
+impl<'a, R: Read + Seek> Windowed<'a, R> {
+    pub(crate) fn new(old: &'a mut R) -> io::Result<Self> {
+        let len = old.seek(SeekFrom::End(0))?;
+        old.seek(SeekFrom::Start(0))?;
+        let step = (len / MAX_SAMPLES).max(BLOCK_LEN as u64);
+        let mut index = HashMap::new();
+        let mut reader = BufReader::with_capacity(1024 * 1024, &mut *old);
+        let mut block = [0; BLOCK_LEN];
+        let mut offset = 0;
+        while offset + BLOCK_LEN as u64 <= len {
+            reader.read_exact(&mut block)?;
+            index.entry(RollingHash::new(&block).hash).or_insert(offset);
+            reader.seek_relative(step as i64 - BLOCK_LEN as i64)?;
+            offset += step;
+        }
+        Ok(Windowed {
+            old,
+            len,
+            index,
+            aligned: 0,
+        })
+    }
+
+    /// Returns the start of the window of `window_len` bytes that contains the most blocks of
+    /// `new`.
+    fn select(&self, new: &[u8], window_len: u64) -> u64 {
+        let aligned = self.aligned.min(self.len.saturating_sub(window_len));
+        if self.len <= window_len || new.len() < BLOCK_LEN {
+            return aligned;
+        }
+        let mut offsets = Vec::new();
+        let mut hash = RollingHash::new(&new[..BLOCK_LEN]);
+        for i in 0..=new.len() - BLOCK_LEN {
+            if i > 0 {
+                hash.roll(new[i - 1], new[i + BLOCK_LEN - 1]);
+            }
+            if let Some(&offset) = self.index.get(&hash.hash) {
+                offsets.push(offset);
+            }
+        }
+        if offsets.is_empty() {
+            return aligned;
+        }
+        offsets.sort_unstable();
+        offsets.dedup();
+
+        // Find the range of matched offsets that fits in a window and contains the most of them
+        let span = window_len - BLOCK_LEN as u64;
+        let (mut best_start, mut best_end) = (0, 0);
+        let mut start = 0;
+        for end in 0..offsets.len() {
+            while offsets[end] - offsets[start] > span {
+                start += 1;
+            }
+            if end - start > best_end - best_start {
+                best_start = start;
+                best_end = end;
+            }
+        }
+        // Center the window around that range
+        let used = offsets[best_end] + BLOCK_LEN as u64 - offsets[best_start];
+        offsets[best_start]
+            .saturating_sub((window_len - used) / 2)
+            .min(self.len - window_len)
+    }
+}
+
+impl<R: Read + Seek> OldSource for Windowed<'_, R> {
+    fn read_chunk(&mut self, new: &[u8], buf: &mut [u8]) -> io::Result<(usize, Option<u64>)> {
+        let start = self.select(new, buf.len() as u64);
+        self.aligned += new.len() as u64;
+        self.old.seek(SeekFrom::Start(start))?;
+        Ok((read_up_to(self.old, buf)?, Some(start)))
+    }
+
+    fn memory(&self) -> u64 {
+        // The table has a control byte for every bucket, and is at most 7/8 full
+        let buckets = self.index.capacity() as u64 * 8 / 7;
+        buckets * (size_of::<(u64, u64)>() as u64 + 1)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use super::{RollingHash, Windowed, BLOCK_LEN};
+    use crate::test_data::random_bytes;
+    use crate::{apply_chunked, generate_chunked, generate_chunked_windowed, Layout};
+
+    #[test]
+    fn rolling_hash() {
+        let data: Vec<u8> = (0..100u8).collect();
+        let mut hash = RollingHash::new(&data[..BLOCK_LEN]);
+        for i in 1..=data.len() - BLOCK_LEN {
+            hash.roll(data[i - 1], data[i + BLOCK_LEN - 1]);
+            assert_eq!(hash.hash, RollingHash::new(&data[i..i + BLOCK_LEN]).hash);
+        }
+    }
+
+    #[test]
+    fn selects_shifted_window() {
+        let old = random_bytes(100_000);
+        let mut cursor = Cursor::new(&old);
+        let windowed = Windowed::new(&mut cursor).unwrap();
+        let start = windowed.select(&old[40_000..50_000], 10_000);
+        assert!((39_950..=40_000).contains(&start));
+        let start = windowed.select(&old[40_123..50_123], 20_000);
+        assert!(start <= 40_123 && start + 20_000 >= 50_123);
+    }
+
+    #[test]
+    fn insertion_roundtrip() {
+        let old = random_bytes(100_000);
+        let mut new = old.clone();
+        new.splice(1000..1000, random_bytes(3000).into_iter().rev());
+
+        let mut aligned = Vec::new();
+        generate_chunked(&mut &old[..], &mut &new[..], &mut aligned, 10_000, |_| {}).unwrap();
+        let mut windowed = Vec::new();
+        generate_chunked_windowed(
+            &mut Cursor::new(&old),
+            &mut &new[..],
+            &mut windowed,
+            10_000,
+            Layout::Interleaved,
+            |_| {},
+        )
+        .unwrap();
+        // The inserted data can't be diffed, but everything else should be found in the old file,
+        // resulting in diff bytes of zero
+        let nonzero = |patch: &[u8]| patch.iter().filter(|&&b| b != 0).count();
+        assert!(nonzero(&windowed) < 3000 + 1000);
+        assert!(nonzero(&windowed) * 5 < nonzero(&aligned));
+
+        let mut patched = Vec::new();
+        apply_chunked(&mut Cursor::new(&old), &mut patched, &mut &windowed[..]).unwrap();
+        assert_eq!(patched, new);
+    }
+}
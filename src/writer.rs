@@ -0,0 +1,236 @@
+//! Generating a patch from a new file that is written incrementally.
+
+use std::io::{self, Read, Write};
+
+use crate::diff::{generate_chunk, write_chunk, write_end, Aligned};
+use crate::error::Context;
+use crate::matcher::Matcher;
+use crate::suffix::DivSufSort;
+use crate::{Error, GenerateOptions, Layout, Result, Stream};
+
+/// Accepts the new file through [`Write`], and writes a patch like
+/// [`generate_chunked`][crate::generate_chunked] as each chunk of it is filled. This avoids
+/// buffering the whole new file when it is produced by code that pushes data, such as a linker or
+/// an encoder.
+///
+/// Only one chunk of the new file and of the old file are held in memory at a time. Once the
+/// whole new file has been written, call [`finish`][DiffWriter::finish] to diff the last chunk.
+/// Dropping the writer without doing so leaves the patch incomplete. Errors while generating are
+/// returned by [`write`][Write::write] as an [`io::Error`] wrapping the [`Error`][crate::Error].
+/// After an error, the patch is incomplete, so all further writes and `finish` fail as well.
+///
+/// ```no_run
+/// # fn main() -> ddelta::Result<()> {
+/// use ddelta::DiffWriter;
+/// use std::fs::File;
+/// use std::io::{self, Write};
+///
+/// let old = File::open("old").unwrap();
+/// let patch = File::create("patch").unwrap();
+/// let mut writer = DiffWriter::new(old, patch, 64 * 1024 * 1024);
+/// io::copy(&mut File::open("new").unwrap(), &mut writer).unwrap();
+/// writer.finish()?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct DiffWriter<Old, Patch, M = DivSufSort> {
+    old: Old,
+    patch: Patch,
+    /// The size of the chunks, or 0 if they don't fit into the memory limit.
+    chunk_size: usize,
+    layout: Layout,
+    options: GenerateOptions<M>,
+    old_buf: Vec<u8>,
+    new_buf: Vec<u8>,
+    /// Whether a chunk has been written to the patch.
+    started: bool,
+    /// The amount of bytes of the new file that have been diffed.
+    new_len: u64,
+    /// Whether diffing a chunk failed, leaving the patch incomplete.
+    failed: bool,
+}
+
+impl<Old: Read, Patch: Write> DiffWriter<Old, Patch> {
+    /// Prepares to write a patch of the data written to this writer against `old` to `patch`, in
+    /// chunks of `chunk_sizes` bytes of the new and old file, like
+    /// [`generate_chunked`][crate::generate_chunked].
+    pub fn new(old: Old, patch: Patch, chunk_sizes: impl Into<Option<usize>>) -> Self {
+        GenerateOptions::new().diff_writer(old, patch, chunk_sizes, Layout::Interleaved)
+    }
+}
+
+impl<Old: Read, Patch: Write, M: Matcher> DiffWriter<Old, Patch, M> {
+    pub(crate) fn with_options(
+        old: Old,
+        patch: Patch,
+        chunk_sizes: Option<usize>,
+        layout: Layout,
+        options: GenerateOptions<M>,
+    ) -> Self {
+        DiffWriter {
+            old,
+            patch,
+            chunk_size: options.chunk_size(chunk_sizes, layout, 0).unwrap_or(0),
+            layout,
+            options,
+            old_buf: Vec::new(),
+            new_buf: Vec::new(),
+            started: false,
+            new_len: 0,
+            failed: false,
+        }
+    }
+
+    /// Diffs the buffered chunk of the new file against the next chunk of the old file.
+    fn write_buffered_chunk(&mut self) -> Result<()> {
+        self.check_failed()?;
+        if self.chunk_size == 0 {
+            return Err(Error::MemoryLimit);
+        }
+        if self.old_buf.is_empty() {
+            self.old_buf = vec![0; self.chunk_size];
+        }
+        let result = write_chunk(
+            &mut Aligned(&mut self.old),
+            &mut self.old_buf,
+            &self.new_buf,
+            &mut self.patch,
+            self.layout,
+            &self.options,
+            |_| {},
+        );
+        self.new_len += self.new_buf.len() as u64;
+        self.new_buf.clear();
+        self.failed = result.is_err();
+        self.started = true;
+        result.map(|_| ())
+    }
+
+    /// Fails if an earlier chunk couldn't be diffed, as the patch would be missing it.
+    fn check_failed(&self) -> Result<()> {
+        if self.failed {
+            let err = io::Error::other("The patch is incomplete after an earlier error");
+            return Err(Error::Io(Stream::Patch, err));
+        }
+        Ok(())
+    }
+
+    /// Diffs the rest of the new file, and returns the patch once it has been flushed.
+    pub fn finish(mut self) -> Result<Patch> {
+        self.check_failed()?;
+        if !self.new_buf.is_empty() || self.chunk_size == 0 {
+            self.write_buffered_chunk()?;
+        } else if !self.started {
+            generate_chunk(
+                &[],
+                &[],
+                &mut self.patch,
+                self.layout,
+                &self.options,
+                |_| {},
+            )?;
+        }
+        write_end(&mut self.patch, self.new_len)?;
+        self.patch.flush().context(Stream::Patch)?;
+        Ok(self.patch)
+    }
+}
+
+impl<Old: Read, Patch: Write, M: Matcher> Write for DiffWriter<Old, Patch, M> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.check_failed()?;
+        if self.chunk_size == 0 {
+            return Err(Error::MemoryLimit.into());
+        }
+        let len = buf.len().min(self.chunk_size - self.new_buf.len());
+        self.new_buf.extend_from_slice(&buf[..len]);
+        if self.new_buf.len() == self.chunk_size {
+            self.write_buffered_chunk()?;
+        }
+        Ok(len)
+    }
+
+    /// Flushes the patch. The buffered part of the new file is only diffed once its chunk is full,
+    /// or the writer is finished.
+    fn flush(&mut self) -> io::Result<()> {
+        self.patch.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{self, Cursor, Write};
+
+    use super::DiffWriter;
+    use crate::test_data::random_bytes;
+    use crate::{apply_chunked, generate_chunked, Compression, GenerateOptions, Layout};
+
+    #[test]
+    fn matches_generate_chunked() {
+        let old = random_bytes(10_000);
+        let mut new = old.clone();
+        new[2000..2100].copy_from_slice(&[1; 100]);
+        new.extend_from_slice(b"appended");
+
+        let mut expected = Vec::new();
+        generate_chunked(&mut &old[..], &mut &new[..], &mut expected, 4000, |_| {}).unwrap();
+        let mut writer = DiffWriter::new(&old[..], Vec::new(), 4000);
+        for part in new.chunks(999) {
+            writer.write_all(part).unwrap();
+        }
+        assert_eq!(writer.finish().unwrap(), expected);
+
+        let writer = DiffWriter::new(&old[..], Vec::new(), 4000);
+        let mut patched = Vec::new();
+        apply_chunked(
+            &mut Cursor::new(&old),
+            &mut patched,
+            &mut &writer.finish().unwrap()[..],
+        )
+        .unwrap();
+        assert!(patched.is_empty());
+
+        let mut writer = GenerateOptions::new().diff_writer(
+            &old[..],
+            Vec::new(),
+            3000,
+            Layout::Split(Compression::None),
+        );
+        writer.write_all(&new).unwrap();
+        let patch = writer.finish().unwrap();
+        let mut patched = Vec::new();
+        apply_chunked(&mut Cursor::new(&old), &mut patched, &mut &patch[..]).unwrap();
+        assert_eq!(patched, new);
+    }
+
+    /// A patch that fails to be written to once `remaining` bytes have been accepted.
+    struct Failing {
+        remaining: usize,
+    }
+
+    impl Write for Failing {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.remaining == 0 {
+                return Err(io::Error::other("disk full"));
+            }
+            let len = buf.len().min(self.remaining);
+            self.remaining -= len;
+            Ok(len)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn failed_chunk() {
+        let old = vec![0; 1000];
+        let mut writer = DiffWriter::new(&old[..], Failing { remaining: 10 }, 100);
+        let err = writer.write_all(&[1; 100]).unwrap_err();
+        assert_eq!(err.to_string(), "disk full");
+        // The failed chunk is not retried, and the writer doesn't pretend to accept more data
+        assert!(writer.write(&[1; 100]).is_err());
+        assert!(writer.finish().is_err());
+    }
+}